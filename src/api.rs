@@ -0,0 +1,615 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use reqwest::Url;
+
+use serde_json::{json, Value};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    time,
+};
+
+#[allow(unused_imports)]
+use crate::GetM3u8;
+use crate::{
+    downloadqueue::{Download, DownloadQueue, DownloadStatus, Finished},
+    grandmother::Refreshed,
+    server::{read_request, write_response, Request, Response, REQUEST_TIMEOUT},
+    BackgroundRefresher, FileDeletion, GrandMother, Identity, M3u8, OfflineLibrary, Player, Relay,
+    Upstream, WatchedFind,
+};
+
+type Error = String;
+
+/// What went wrong, as the status to answer with and a message for the client
+type Answer = Result<Value, (&'static str, String)>;
+
+/// A request from a connection, and where to send the reply
+type Call = (Request, oneshot::Sender<Reply>);
+
+/// A refresh that was fetched, and where to send the response once it's swapped in
+type RefreshDone = (Refreshed, oneshot::Sender<Response>);
+
+/**
+ * How the api answers a connection. What waits for another server is done by the
+ * connection or on another task, so that a slow one doesn't hold up the other clients
+ */
+enum Reply {
+    Now(Response),
+    /// The stream to relay for the key of an entry
+    Relay(String, Upstream),
+    /// Sent when a refresh is done
+    Later(oneshot::Receiver<Response>),
+}
+
+/// How often to look whether mpv has been closed
+const PLAYER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to look for playlists that are due for a refresh
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How many entries a search answers with, unless ?limit= says otherwise
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+/// The page that is served at /, which uses the api
+const WEB_UI: &str = include_str!("webui.html");
+
+/**
+ * The state that the api controls. Everything happens on one task, so the connections
+ * send their requests to it instead of touching the GrandMother themselves
+ */
+struct Api {
+    gm: GrandMother,
+    token: Option<String>,
+    /// The port when listening on a loopback address, where only local names may be used
+    loopback_port: Option<u16>,
+    /// Streams entries to the player in the browser, shared with the connections
+    relay: Arc<Relay>,
+    /// The mpv that was started through the api
+    player: Player,
+    /// Entries that were asked to be saved for offline use
+    queue: DownloadQueue,
+    refreshes: mpsc::UnboundedSender<RefreshDone>,
+}
+
+/**
+ * Control the GrandMother, the offline downloads and mpv through a json api over http,
+ * until stopped. The playlists are refreshed in the background as usual
+ */
+pub async fn run_api(gm: GrandMother, address: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|e| format!("Failed to listen on {}, {:?}", address, e))?;
+    let token = gm.config.api.token.clone();
+    let loopback_port = listener
+        .local_addr()
+        .ok()
+        .filter(|x| x.ip().is_loopback())
+        .map(|x| x.port());
+    if token.is_none() && loopback_port.is_none() {
+        println!(
+            "There is no api.token in the configfile, anyone who can reach {} can control ilovetv",
+            address
+        );
+    }
+    println!(
+        "The api is at http://{}/api and the web ui at http://{}/",
+        address, address
+    );
+
+    let (calls_sender, mut calls) = mpsc::channel::<Call>(16);
+    let (queue, mut finished_downloads) = DownloadQueue::new();
+    let (refreshes, mut refreshed) = mpsc::unbounded_channel::<RefreshDone>();
+    let mut api = Api {
+        gm,
+        token,
+        loopback_port,
+        relay: Arc::new(Relay::new()),
+        player: Player::new(),
+        queue,
+        refreshes,
+    };
+
+    let mut refresher = BackgroundRefresher::new();
+    let mut player_check = time::interval(PLAYER_CHECK_INTERVAL);
+    let mut refresh_check = time::interval(REFRESH_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream, calls_sender.clone(), api.relay.clone()));
+                }
+                Err(e) => println!("Failed to accept a connection, {:?}", e),
+            },
+            Some((request, reply)) = calls.recv() => {
+                let _ = reply.send(api.handle(&request));
+            }
+            Some((done, reply)) = refreshed.recv() => {
+                let _ = reply.send(api.finish_refresh(done).await);
+            }
+            Some(finished) = finished_downloads.recv() => api.finish_download(finished),
+            _ = player_check.tick() => api.player.poll(&api.gm),
+            _ = refresh_check.tick() => {
+                let refreshed = refresher.poll(&mut api.gm).await;
+                if !refreshed.is_empty() {
+                    let refreshed: Vec<&str> = refreshed.iter().map(|x| x.as_str()).collect();
+                    println!("Refreshed {} in the background", refreshed.join(", "));
+                }
+            }
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, calls: mpsc::Sender<Call>, relay: Arc<Relay>) {
+    let request = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(status)) => {
+            let response = Response::json(status, &json!({ "error": status }));
+            let _ = write_response(&mut stream, response, true).await;
+            return;
+        }
+        Err(_) => return,
+    };
+
+    let is_head = request.method == "HEAD";
+    let (sender, reply) = oneshot::channel();
+    if calls.send((request.clone(), sender)).await.is_err() {
+        return;
+    }
+    let response = match reply.await {
+        Ok(Reply::Now(response)) => response,
+        Ok(Reply::Relay(key, upstream)) => relay.forward(&request, &key, &upstream).await,
+        Ok(Reply::Later(response)) => match response.await {
+            Ok(response) => response,
+            Err(_) => return,
+        },
+        Err(_) => return,
+    };
+    let _ = write_response(&mut stream, response, !is_head).await;
+}
+
+impl Api {
+    fn handle(&mut self, request: &Request) -> Reply {
+        let path = request.path.split('?').next().unwrap_or_default();
+        // The page asks for the token itself, if one is needed
+        if path == "/" || path == "/index.html" {
+            return Reply::Now(Response::ok("text/html; charset=utf-8", WEB_UI.to_owned()));
+        }
+
+        let answer = if !self.is_same_origin(request) {
+            Err((
+                "403 Forbidden",
+                "Only the web ui of the api may send requests from a browser".to_owned(),
+            ))
+        } else if path == "/relay/url" {
+            // Only this server can sign them, so they are proof enough
+            let headers_of = |key: &str| {
+                find_entry(&self.gm, key)
+                    .ok()
+                    .map(|x| self.gm.headers_of(x))
+            };
+            return match self.relay.signed_upstream(request, headers_of) {
+                Ok((key, upstream)) => Reply::Relay(key, upstream),
+                Err(status) => Reply::Now(Response::error(status)),
+            };
+        } else if !self.is_authorized(request) {
+            Err(("401 Unauthorized", "A valid token is required".to_owned()))
+        } else if let Some(id) = path.strip_prefix("/relay/") {
+            return self.relay_entry(id);
+        } else if (request.method.as_str(), path) == ("POST", "/api/refresh") {
+            return self.refresh();
+        } else {
+            match (request.method.as_str(), path) {
+                ("GET", "/api/status") => Ok(self.status()),
+                ("GET", "/api/groups") => Ok(self.groups()),
+                ("GET", "/api/search") => self.search(request),
+                ("GET", "/api/epg") => self.search_epg(request),
+                ("POST", "/api/play") => self.play(request),
+                ("POST", "/api/stop") => Ok(json!({ "stopped": self.stop_player() })),
+                ("GET", "/api/offline") => Ok(self.offline()),
+                ("POST", "/api/offline") => self.add_offline(request),
+                ("POST", "/api/offline/remove") => self.remove_offline(request),
+                ("GET", "/api/downloads") => Ok(self.downloads()),
+                (
+                    _,
+                    "/api/status"
+                    | "/api/groups"
+                    | "/api/search"
+                    | "/api/epg"
+                    | "/api/play"
+                    | "/api/stop"
+                    | "/api/refresh"
+                    | "/api/offline"
+                    | "/api/offline/remove"
+                    | "/api/downloads",
+                ) => Err((
+                    "405 Method Not Allowed",
+                    format!("{} can't be used with {}", path, request.method),
+                )),
+                _ => Err(("404 Not Found", format!("There is nothing at {}", path))),
+            }
+        };
+        match answer {
+            Ok(value) => Reply::Now(Response::json("200 OK", &value)),
+            Err((status, error)) => Reply::Now(Response::json(status, &json!({ "error": error }))),
+        }
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        let token = match self.token.as_ref() {
+            Some(token) => token,
+            None => return true,
+        };
+        let bearer = request
+            .headers
+            .get("authorization")
+            .and_then(|x| x.strip_prefix("Bearer "));
+        bearer == Some(token.as_str()) || request.query("token").as_ref() == Some(token)
+    }
+
+    /**
+     * Whether a request comes from the web ui of the api, or from something that isn't a
+     * browser. Browsers send the Origin of the page with requests to other sites, so that
+     * they can't make them control ilovetv. On a loopback address the Host has to be a local
+     * name as well, since another site could otherwise point its own name at it
+     */
+    fn is_same_origin(&self, request: &Request) -> bool {
+        let host = request.host();
+        if let Some(origin) = request.headers.get("origin") {
+            let origin = origin.split_once("://").map_or(origin.as_str(), |x| x.1);
+            if !origin.eq_ignore_ascii_case(host) {
+                return false;
+            }
+        }
+        let port = match self.loopback_port {
+            Some(port) => port,
+            None => return true,
+        };
+        let url = match Url::parse(&format!("http://{}", host)) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        let name = url.host_str().unwrap_or_default();
+        let is_local_name = name.eq_ignore_ascii_case("localhost")
+            || name
+                .trim_matches(|c| c == '[' || c == ']')
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| ip.is_loopback());
+        is_local_name && url.port_or_known_default() == Some(port)
+    }
+
+    fn status(&self) -> Value {
+        let identity = self.gm.config.identity;
+        let playing = self.player.playing().map(|m3u8| entry_json(m3u8, identity));
+        let downloading = self
+            .queue
+            .downloads()
+            .iter()
+            .filter(|x| x.is_pending())
+            .count();
+        json!({
+            "profile": self.gm.config.active_profile,
+            "online": !self.gm.playlists.is_empty(),
+            "entries": self.gm.parser.get_m3u8().len(),
+            "playing": playing,
+            "downloading": downloading,
+        })
+    }
+
+    /**
+     * The groups in the order of the playlists, with how many entries they have
+     */
+    fn groups(&self) -> Value {
+        let mut groups: Vec<(&str, usize)> = Vec::new();
+        for m3u8 in self.gm.parser.get_m3u8() {
+            match groups
+                .iter_mut()
+                .find(|(name, _)| *name == m3u8.group_title)
+            {
+                Some((_, count)) => *count += 1,
+                None => groups.push((&m3u8.group_title, 1)),
+            }
+        }
+        let groups: Vec<Value> = groups
+            .iter()
+            .map(|(name, count)| json!({ "name": name, "count": count }))
+            .collect();
+        json!({ "groups": groups })
+    }
+
+    /**
+     * Entries with a name containing ?q=, all of them if it's left out.
+     * With ?group= only the entries in that group
+     */
+    fn search(&self, request: &Request) -> Answer {
+        let query = param(request, "q").unwrap_or_default();
+        let group = param(request, "group");
+        let limit = limit(request)?;
+        let identity = self.gm.config.identity;
+        let mut found = self.gm.parser.find(query.trim());
+        if let Some(group) = group.as_ref() {
+            found.retain(|m3u8| m3u8.group_title == *group);
+        }
+        let entries: Vec<Value> = found
+            .iter()
+            .take(limit)
+            .map(|m3u8| entry_json(m3u8, identity))
+            .collect();
+        Ok(json!({ "total": found.len(), "entries": entries }))
+    }
+
+    /**
+     * Upcoming programmes with ?q= in the title or description, with their channel
+     */
+    fn search_epg(&self, request: &Request) -> Answer {
+        let query = required(request, "q")?;
+        let limit = limit(request)?;
+        let identity = self.gm.config.identity;
+        let found = self.gm.search_epg(query.trim());
+        let programmes: Vec<Value> = found
+            .iter()
+            .take(limit)
+            .map(|(programme, m3u8)| {
+                json!({
+                    "title": programme.title,
+                    "desc": programme.desc,
+                    "start": programme.start,
+                    "stop": programme.stop,
+                    "channel": entry_json(m3u8, identity),
+                })
+            })
+            .collect();
+        Ok(json!({ "total": found.len(), "programmes": programmes }))
+    }
+
+    /**
+     * Play the entry with ?id= in mpv, instead of what's already playing
+     */
+    fn play(&mut self, request: &Request) -> Answer {
+        let id = required(request, "id")?;
+        let fullscreen = param(request, "fullscreen")
+            .map(|x| x == "true" || x == "1")
+            .unwrap_or_default();
+        let m3u8 = find_entry(&self.gm, &id)?;
+        self.player.play(&self.gm, m3u8, fullscreen).map_err(|e| {
+            if e.starts_with("Failed to start mpv") {
+                ("500 Internal Server Error", e)
+            } else {
+                ("409 Conflict", e)
+            }
+        })?;
+        println!("Playing {}", m3u8.name);
+        Ok(json!({ "playing": entry_json(m3u8, self.gm.config.identity) }))
+    }
+
+    /**
+     * Close mpv if it's playing, what was playing is saved to the watch history
+     */
+    fn stop_player(&mut self) -> Option<Value> {
+        let entry = self.player.stop(&self.gm)?;
+        Some(entry_json(&entry, self.gm.config.identity))
+    }
+
+    /**
+     * Fetch the playlists on another task, they are swapped in by `finish_refresh`
+     */
+    fn refresh(&self) -> Reply {
+        let fetch = match self.gm.fetch_refresh() {
+            Ok(fetch) => fetch,
+            Err(e) => return Reply::Now(Response::json("409 Conflict", &json!({ "error": e }))),
+        };
+        let (sender, response) = oneshot::channel();
+        let refreshes = self.refreshes.clone();
+        tokio::spawn(async move {
+            let _ = refreshes.send((fetch.await, sender));
+        });
+        Reply::Later(response)
+    }
+
+    async fn finish_refresh(&mut self, refreshed: Refreshed) -> Response {
+        self.gm.apply_refresh(refreshed).await;
+        let (added, removed) = self
+            .gm
+            .last_diff
+            .as_ref()
+            .map(|diff| (diff.added.len(), diff.removed.len()))
+            .unwrap_or_default();
+        let value = json!({
+            "entries": self.gm.parser.get_m3u8().len(),
+            "added": added,
+            "removed": removed,
+        });
+        Response::json("200 OK", &value)
+    }
+
+    fn offline(&self) -> Value {
+        // The prompt or another command may have changed it since
+        self.gm.config.reload_offlinefile_ugly();
+        let library = OfflineLibrary::new(self.gm.config.clone());
+        let entries: Vec<Value> = library
+            .list()
+            .iter()
+            .enumerate()
+            .map(|(idx, (entry, size))| {
+                json!({
+                    "number": idx + 1,
+                    "name": entry.name,
+                    "path": entry.path,
+                    "size": size,
+                })
+            })
+            .collect();
+        json!({ "entries": entries, "downloads": self.downloads()["downloads"] })
+    }
+
+    /**
+     * Remove the offline entry with ?number=. Its file is deleted if it's in the data
+     * directory, not at all with ?delete_files=false and wherever it is with ?delete_files=true
+     */
+    fn remove_offline(&self, request: &Request) -> Answer {
+        let number = required(request, "number")?;
+        let delete_files = match param(request, "delete_files").as_deref() {
+            None => FileDeletion::InDataDir,
+            Some("false" | "0") => FileDeletion::Keep,
+            Some(_) => FileDeletion::All,
+        };
+        let idx = match number.parse::<usize>() {
+            Ok(number) if number > 0 => number - 1,
+            _ => {
+                return Err((
+                    "400 Bad Request",
+                    format!("number has to be a number from 1, not '{}'", number),
+                ))
+            }
+        };
+
+        self.gm.config.reload_offlinefile_ugly();
+        let library = OfflineLibrary::new(self.gm.config.clone());
+        let removed = library
+            .remove(&[idx], delete_files)
+            .map_err(|e| ("404 Not Found", e))?;
+        let names: Vec<&str> = removed.iter().map(|x| x.name.as_str()).collect();
+        Ok(json!({ "removed": names }))
+    }
+
+    /**
+     * Where the stream of an entry is, for the connection to pipe it to the browser
+     * with the headers of its source
+     */
+    fn relay_entry(&self, id: &str) -> Reply {
+        let m3u8 = match find_entry(&self.gm, id) {
+            Ok(m3u8) => m3u8,
+            Err((status, error)) => {
+                return Reply::Now(Response::json(status, &json!({ "error": error })))
+            }
+        };
+        if !m3u8.link.starts_with("http") {
+            let error = "Only entries that are online can be played in the browser";
+            return Reply::Now(Response::json("409 Conflict", &json!({ "error": error })));
+        }
+        let upstream = Upstream {
+            link: m3u8.link.to_string(),
+            headers: self.gm.headers_of(m3u8),
+        };
+        Reply::Relay(id.to_owned(), upstream)
+    }
+
+    fn downloads(&self) -> Value {
+        let downloads: Vec<Value> = self.queue.downloads().iter().map(download_json).collect();
+        json!({ "downloads": downloads })
+    }
+
+    /**
+     * Queue the entry with ?id= to be downloaded for offline use
+     */
+    fn add_offline(&mut self, request: &Request) -> Answer {
+        let id = required(request, "id")?;
+        let entry = find_entry(&self.gm, &id)?.clone();
+        let id = self.queue.add(&self.gm, entry, true);
+        let download = match self.queue.downloads().iter().find(|x| x.id == id) {
+            Some(download) => download,
+            None => {
+                return Err((
+                    "500 Internal Server Error",
+                    "The download is gone".to_owned(),
+                ))
+            }
+        };
+        match &download.status {
+            DownloadStatus::Measuring | DownloadStatus::Downloading => {
+                println!("Downloading {}", download.entry.name)
+            }
+            DownloadStatus::Failed { error } => {
+                println!("Not downloading {}, {}", download.entry.name, error)
+            }
+            _ => {}
+        }
+        Ok(json!({ "download": download_json(download) }))
+    }
+
+    /**
+     * Add a finished download to the offline catalog, and start the next one
+     */
+    fn finish_download(&mut self, finished: Finished) {
+        let download = match self.queue.finish(&self.gm, finished) {
+            Some(download) => download,
+            None => return,
+        };
+        match &download.status {
+            DownloadStatus::Done { .. } => println!("Downloaded {}", download.entry.name),
+            DownloadStatus::Failed { error } => {
+                println!("Failed to download {}, {}", download.entry.name, error)
+            }
+            _ => {}
+        }
+    }
+}
+
+fn download_json(download: &Download) -> Value {
+    json!({
+        "id": download.id,
+        "name": download.entry.name,
+        "path": download.path,
+        "status": download.status,
+    })
+}
+
+/**
+ * Entries are pointed to by a hash of their id, which stays the same between refreshes
+ */
+fn entry_json(m3u8: &M3u8, identity: Identity) -> Value {
+    let (now, next) = m3u8.now_and_next();
+    json!({
+        "id": Relay::key(&m3u8.id(identity)),
+        "name": m3u8.name,
+        "group": m3u8.group_title,
+        "tvg_id": m3u8.tvg_id,
+        "logo": m3u8.tvg_logo,
+        "source": m3u8.source.as_deref(),
+        "watched": m3u8.watched,
+        "now": now.map(|x| &x.title),
+        "next": next.map(|x| &x.title),
+    })
+}
+
+fn find_entry<'a>(gm: &'a GrandMother, id: &str) -> Result<&'a M3u8, (&'static str, String)> {
+    let identity = gm.config.identity;
+    gm.parser
+        .get_m3u8()
+        .into_iter()
+        .find(|m3u8| Relay::key(&m3u8.id(identity)) == id)
+        .ok_or_else(|| {
+            (
+                "404 Not Found",
+                format!("There is no entry with the id {}", id),
+            )
+        })
+}
+
+/**
+ * A parameter from the query string, or from a json object in the body
+ */
+fn param(request: &Request, key: &str) -> Option<String> {
+    if let Some(value) = request.query(key) {
+        return Some(value);
+    }
+    let body: Value = serde_json::from_str(&request.body).ok()?;
+    match body.get(key)? {
+        Value::String(value) => Some(value.clone()),
+        Value::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+fn required(request: &Request, key: &str) -> Result<String, (&'static str, String)> {
+    param(request, key).ok_or_else(|| ("400 Bad Request", format!("{} is required", key)))
+}
+
+fn limit(request: &Request) -> Result<usize, (&'static str, String)> {
+    match param(request, "limit") {
+        Some(limit) => limit.parse().map_err(|_| {
+            (
+                "400 Bad Request",
+                format!("limit has to be a number, not '{}'", limit),
+            )
+        }),
+        None => Ok(DEFAULT_SEARCH_LIMIT),
+    }
+}
@@ -0,0 +1,411 @@
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::epg;
+
+type Error = String;
+
+/**
+ * How the provider serves the archive of a channel, from the catchup attribute
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Hash, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CatchupKind {
+    /// The catchup-source is the whole url
+    Default,
+    /// The catchup-source is appended to the link of the channel
+    Append,
+    /// utc and lutc are added to the query of the link
+    Shift,
+    /// The link is rewritten to the archive of a Flussonic server
+    Flussonic,
+    /// The link is rewritten to the timeshift of an Xtream Codes server
+    Xc,
+}
+
+impl CatchupKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Append => "append",
+            Self::Shift => "shift",
+            Self::Flussonic => "flussonic",
+            Self::Xc => "xc",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind.trim().to_lowercase().as_str() {
+            "default" => Some(Self::Default),
+            "append" => Some(Self::Append),
+            // Older playlists say timeshift="..." which works like shift
+            "shift" | "timeshift" => Some(Self::Shift),
+            "flussonic" | "flussonic-hls" | "flussonic-ts" | "fs" => Some(Self::Flussonic),
+            "xc" => Some(Self::Xc),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Archive playback of a live channel, parsed from catchup="...", catchup-source="..."
+ * and catchup-days="..." in the playlist
+ */
+#[derive(Serialize, Deserialize, Clone, Hash, Debug)]
+pub struct Catchup {
+    pub kind: CatchupKind,
+    /// The template with placeholders such as {utc} and {duration}, may be empty
+    pub source: String,
+    /// How many days back the archive goes
+    pub days: Option<u32>,
+}
+
+impl Catchup {
+    pub fn new(kind: &str, source: &str, days: Option<&str>) -> Option<Self> {
+        Some(Self {
+            kind: CatchupKind::parse(kind)?,
+            source: source.to_owned(),
+            days: days.and_then(|x| x.trim().parse().ok()),
+        })
+    }
+
+    /**
+     * The attributes for an #EXTINF line, such as ` catchup="shift" catchup-days="7"`
+     */
+    pub fn to_attributes(&self) -> String {
+        let mut attributes = format!(" catchup=\"{}\"", self.kind.as_str());
+        if !self.source.is_empty() {
+            attributes.push_str(&format!(" catchup-source=\"{}\"", self.source));
+        }
+        if let Some(days) = self.days {
+            attributes.push_str(&format!(" catchup-days=\"{}\"", days));
+        }
+        attributes
+    }
+
+    /**
+     * Whether a programme that started at `start` is still in the archive
+     */
+    pub fn covers(&self, start: i64) -> bool {
+        let now = epg::now();
+        let oldest = self
+            .days
+            .map(|days| now - days as i64 * 60 * 60 * 24)
+            .unwrap_or(i64::MIN);
+        oldest <= start && start < now
+    }
+
+    /**
+     * The link to play what was on between `start` and `stop`, seconds since the unix epoch
+     */
+    pub fn url(&self, link: &str, start: i64, stop: i64) -> Result<String, Error> {
+        self.url_at(link, start, stop, epg::now())
+    }
+
+    fn url_at(&self, link: &str, start: i64, stop: i64, now: i64) -> Result<String, Error> {
+        if stop <= start {
+            return Err("The programme has to end after it starts".to_owned());
+        }
+        let times = Times { start, stop, now };
+        let template = match self.kind {
+            CatchupKind::Default if self.source.is_empty() => {
+                return Err(
+                    "The channel has catchup=\"default\" without a catchup-source".to_owned(),
+                )
+            }
+            CatchupKind::Default => self.source.clone(),
+            CatchupKind::Append => format!("{}{}", link, self.source),
+            CatchupKind::Shift => {
+                let separator = if link.contains('?') { '&' } else { '?' };
+                format!("{}{}utc={{utc}}&lutc={{lutc}}", link, separator)
+            }
+            // A catchup-source overrides the rewriting of the link
+            CatchupKind::Flussonic | CatchupKind::Xc if !self.source.is_empty() => {
+                if self.source.contains("://") {
+                    self.source.clone()
+                } else {
+                    format!("{}{}", link, self.source)
+                }
+            }
+            CatchupKind::Flussonic => flussonic_template(link)?,
+            CatchupKind::Xc => xc_template(link)?,
+        };
+        Ok(expand(&template, &times))
+    }
+}
+
+struct Times {
+    start: i64,
+    stop: i64,
+    now: i64,
+}
+
+/**
+ * Rewrite http://host/channel/index.m3u8?token=x into the archive of the channel.
+ * HLS links become video-{start}-{duration}.m3u8 and MPEG-TS links timeshift_abs-{start}.ts
+ */
+fn flussonic_template(link: &str) -> Result<String, Error> {
+    let mut url = Url::parse(link).map_err(|e| format!("'{}' is not an url, {}", link, e))?;
+    let mut segments: Vec<String> = url
+        .path_segments()
+        .map(|x| x.map(str::to_owned).collect())
+        .unwrap_or_default();
+    let last = match segments.pop() {
+        Some(last) if !segments.is_empty() => last,
+        _ => return Err(format!("'{}' is not a Flussonic link", link)),
+    };
+    let archive = if last == "mpegts" {
+        "timeshift_abs-{utc}.ts".to_owned()
+    } else if last.ends_with(".m3u8") {
+        format!(
+            "{}-{{utc}}-{{duration}}.m3u8",
+            last.trim_end_matches(".m3u8")
+        )
+    } else {
+        return Err(format!("'{}' is not a Flussonic link", link));
+    };
+    segments.push(archive);
+    url.set_path(&segments.join("/"));
+    // The braces are escaped by the url, but they have to stay as placeholders
+    Ok(url.to_string().replace("%7B", "{").replace("%7D", "}"))
+}
+
+/**
+ * Rewrite http://host/live/user/pass/1234.ts, or http://host/user/pass/1234,
+ * into http://host/timeshift/user/pass/{minutes}/{Y}-{m}-{d}:{H}-{M}/1234.ts
+ */
+fn xc_template(link: &str) -> Result<String, Error> {
+    let url = Url::parse(link).map_err(|e| format!("'{}' is not an url, {}", link, e))?;
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|x| x.filter(|x| !x.is_empty()).collect())
+        .unwrap_or_default();
+    let (user, password, stream) = match segments.as_slice() {
+        ["live", user, password, stream] | [user, password, stream] => (user, password, stream),
+        _ => return Err(format!("'{}' is not an Xtream Codes link", link)),
+    };
+    let (stream_id, ending) = match stream.split_once('.') {
+        Some((stream_id, ending)) => (stream_id, ending),
+        None => (*stream, "ts"),
+    };
+
+    let mut origin = url.origin().ascii_serialization();
+    origin.push_str(&format!(
+        "/timeshift/{}/{}/{{duration:60}}/{{Y}}-{{m}}-{{d}}:{{H}}-{{M}}/{}.{}",
+        user, password, stream_id, ending
+    ));
+    if let Some(query) = url.query() {
+        origin.push('?');
+        origin.push_str(query);
+    }
+    Ok(origin)
+}
+
+/**
+ * Replace the placeholders of a catchup template. Both {name} and ${name} are accepted:
+ *  - {utc}, {start}, {utcend}, {end}, {lutc}, {now} and {timestamp} in seconds since the epoch
+ *  - {duration} and {offset} in seconds, or divided such as {duration:60} for minutes
+ *  - {Y}, {m}, {d}, {H}, {M} and {S} of the start in UTC
+ *  - {utc:YmdHMS}, {utcend:...} and {lutc:...} with a format of those letters
+ *
+ * Unknown placeholders are left as they are
+ */
+fn expand(template: &str, times: &Times) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let close = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => break,
+        };
+        let dollar = open > 0 && rest.as_bytes()[open - 1] == b'$';
+        let prefix_end = if dollar { open - 1 } else { open };
+        match placeholder(&rest[open + 1..close], times) {
+            Some(value) => {
+                expanded.push_str(&rest[..prefix_end]);
+                expanded.push_str(&value);
+            }
+            None => expanded.push_str(&rest[..=close]),
+        }
+        rest = &rest[close + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn placeholder(placeholder: &str, times: &Times) -> Option<String> {
+    let (name, argument) = match placeholder.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (placeholder, None),
+    };
+    let divided = |seconds: i64| {
+        let divisor = argument
+            .and_then(|x| x.parse::<i64>().ok())
+            .filter(|x| *x > 0)
+            .unwrap_or(1);
+        Some((seconds / divisor).to_string())
+    };
+    let timestamp = |timestamp: i64| match argument {
+        Some(format) => Some(format_utc(timestamp, format)),
+        None => Some(timestamp.to_string()),
+    };
+
+    match name {
+        "utc" | "start" => timestamp(times.start),
+        "utcend" | "end" => timestamp(times.stop),
+        "lutc" | "now" | "timestamp" => timestamp(times.now),
+        "duration" => divided(times.stop - times.start),
+        "offset" => divided(times.now - times.start),
+        "Y" | "m" | "d" | "H" | "M" | "S" if argument.is_none() => {
+            Some(format_utc(times.start, name))
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Format with the letters Y, m, d, H, M and S, anything else is kept as it is
+ */
+fn format_utc(timestamp: i64, format: &str) -> String {
+    let time: DateTime<Utc> = DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
+    format
+        .chars()
+        .map(|c| match c {
+            'Y' => time.format("%Y").to_string(),
+            'm' => time.format("%m").to_string(),
+            'd' => time.format("%d").to_string(),
+            'H' => time.format("%H").to_string(),
+            'M' => time.format("%M").to_string(),
+            'S' => time.format("%S").to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2023-11-14 22:13:20 UTC
+    const START: i64 = 1_700_000_000;
+    const STOP: i64 = START + 3600;
+    const NOW: i64 = START + 7200;
+
+    fn url(kind: &str, source: &str, link: &str) -> Result<String, Error> {
+        Catchup::new(kind, source, None)
+            .unwrap()
+            .url_at(link, START, STOP, NOW)
+    }
+
+    #[test]
+    fn default_uses_the_source_as_the_url() {
+        assert_eq!(
+            url(
+                "default",
+                "http://a.example/archive?start={utc}&end={utcend}",
+                "http://a.example/live"
+            ),
+            Ok("http://a.example/archive?start=1700000000&end=1700003600".to_owned())
+        );
+    }
+
+    #[test]
+    fn default_without_a_source_is_an_error() {
+        assert!(url("default", "", "http://a.example/live").is_err());
+    }
+
+    #[test]
+    fn append_adds_the_source_to_the_link() {
+        assert_eq!(
+            url(
+                "append",
+                "&utc=${start}&duration=${duration}",
+                "http://b.example/live?tok=1"
+            ),
+            Ok("http://b.example/live?tok=1&utc=1700000000&duration=3600".to_owned())
+        );
+    }
+
+    #[test]
+    fn shift_adds_utc_and_lutc() {
+        assert_eq!(
+            url("shift", "", "http://c.example/live"),
+            Ok("http://c.example/live?utc=1700000000&lutc=1700007200".to_owned())
+        );
+        assert_eq!(
+            url("timeshift", "", "http://c.example/live?tok=1"),
+            Ok("http://c.example/live?tok=1&utc=1700000000&lutc=1700007200".to_owned())
+        );
+    }
+
+    #[test]
+    fn flussonic_rewrites_hls_and_mpegts() {
+        assert_eq!(
+            url("flussonic", "", "http://fs.example/ch1/index.m3u8?token=x"),
+            Ok("http://fs.example/ch1/index-1700000000-3600.m3u8?token=x".to_owned())
+        );
+        assert_eq!(
+            url("fs", "", "http://fs.example/ch1/mpegts?token=x"),
+            Ok("http://fs.example/ch1/timeshift_abs-1700000000.ts?token=x".to_owned())
+        );
+        assert!(url("flussonic", "", "http://fs.example/ch1/stream.mp4").is_err());
+    }
+
+    #[test]
+    fn xc_rewrites_to_the_timeshift() {
+        assert_eq!(
+            url("xc", "", "http://xc.example:8080/live/u/p/123.ts"),
+            Ok("http://xc.example:8080/timeshift/u/p/60/2023-11-14:22-13/123.ts".to_owned())
+        );
+        assert_eq!(
+            url("xc", "", "http://xc.example:8080/u/p/123"),
+            Ok("http://xc.example:8080/timeshift/u/p/60/2023-11-14:22-13/123.ts".to_owned())
+        );
+        assert!(url("xc", "", "http://xc.example:8080/movie/123.mkv").is_err());
+    }
+
+    #[test]
+    fn programme_has_to_end_after_it_starts() {
+        let catchup = Catchup::new("shift", "", None).unwrap();
+        assert!(catchup
+            .url_at("http://c.example/live", STOP, START, NOW)
+            .is_err());
+    }
+
+    fn expanded(template: &str) -> String {
+        let times = Times {
+            start: START,
+            stop: STOP,
+            now: NOW,
+        };
+        expand(template, &times)
+    }
+
+    #[test]
+    fn expands_braces_and_dollar_braces() {
+        assert_eq!(
+            expanded("{utc}/{duration}/${start}/${end}/{lutc}/{offset}"),
+            "1700000000/3600/1700000000/1700003600/1700007200/7200"
+        );
+    }
+
+    #[test]
+    fn expands_divided_and_formatted_placeholders() {
+        assert_eq!(expanded("{duration:60}/{offset:60}"), "60/120");
+        assert_eq!(expanded("{Y}-{m}-{d}:{H}-{M}-{S}"), "2023-11-14:22-13-20");
+        assert_eq!(
+            expanded("{utc:Y-m-d H:M:S}|${utcend:YmdHMS}"),
+            "2023-11-14 22:13:20|20231114231320"
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders() {
+        assert_eq!(
+            expanded("a/{channel}/${unknown}/{utc}"),
+            "a/{channel}/${unknown}/1700000000"
+        );
+        assert_eq!(expanded("unclosed {utc"), "unclosed {utc");
+    }
+}
@@ -1,9 +1,12 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fs::{self, File},
     io::{self, BufReader},
+    net::SocketAddr,
     ops::Deref,
     path::{Path, PathBuf},
     rc::Rc,
+    time::{Duration, SystemTime},
 };
 
 use directories::ProjectDirs;
@@ -11,56 +14,529 @@ use serde::{Deserialize, Serialize};
 use serde_json;
 
 use crate::{
-    get_mut_ref, m3u8::OfflineEntry, Readline, APP_IDENTIFIER, JSON_CONFIG_FILENAME,
-    STANDARD_OFFLINE_FILENAME, STANDARD_PLAYLIST_FILENAME, STANDARD_SEEN_LINKS_FILENAME,
+    get_mut_ref,
+    listing::SortKey,
+    m3u8::{Identity, OfflineEntry},
+    offlinelibrary::parse_size,
+    offlinelibrary::EvictionPolicy,
+    relay::RelayMode,
+    xtream::XtreamLogin,
+    Readline, APP_IDENTIFIER, JSON_CONFIG_FILENAME, STANDARD_DIFF_HISTORY_FILENAME,
+    STANDARD_FAVORITES_FILENAME, STANDARD_LAST_SEARCH_FILENAME, STANDARD_OFFLINE_FILENAME,
+    STANDARD_PLAYLIST_FILENAME, STANDARD_RECORDINGS_FILENAME, STANDARD_SEEN_LINKS_FILENAME,
+    STANDARD_WATCH_HISTORY_FILENAME, TOML_CONFIG_FILENAME,
 };
 
-#[derive(Serialize, Deserialize, Debug)]
+type Error = String;
+
+/// The version of the configfile that this build writes
+pub const CONFIG_VERSION: u32 = 3;
+
+/**
+ * Migrations between versions of the configfile, where the migration at index i
+ * takes a configfile from version i to i + 1. Version 0 is the old json config
+ */
+const MIGRATIONS: [fn(&mut toml::Table); CONFIG_VERSION as usize] =
+    [migrate_v0_to_v1, migrate_v1_to_v2, migrate_v2_to_v3];
+
+/// A profile with this name merges all sources, unless there is a profile called so
+pub const ALL_SOURCES_PROFILE: &str = "all";
+
+/// Where `ilovetv serve` listens unless something else is configured
+pub const DEFAULT_SERVE_ADDRESS: &str = "0.0.0.0:8080";
+/// How many streams media servers may tune to at the same time, unless configured
+pub const DEFAULT_TUNER_COUNT: u32 = 2;
+/// Where the json api listens unless something else is configured, only reachable locally
+pub const DEFAULT_API_ADDRESS: &str = "127.0.0.1:8081";
+/// How many search results the prompt lists at a time, unless configured
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Conf {
-    pub playlist_filename: String,
-    pub playlist_url: Rc<String>,
-    pub last_search: Option<String>,
+    pub version: u32,
+    /// Name of a profile or a single source
+    pub active_profile: String,
     pub seen_links_filename: String,
+    /// How entries are recognized between refreshes: url, url-without-query, tvg-id or name-group
+    #[serde(default)]
+    pub identity: Identity,
+    #[serde(default)]
+    pub offline: OfflineConf,
+    #[serde(default)]
+    pub serve: ServeConf,
+    #[serde(default)]
+    pub api: ApiConf,
+    #[serde(default)]
+    pub prompt: PromptConf,
+    pub sources: Vec<Source>,
+    /// Named sets of sources that are shown together
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    pub name: Rc<String>,
+    /// An http(s) url, a local path, a file:// url or - for stdin
+    pub url: Rc<String>,
+    /// How old the cached playlist may be before it's downloaded again, such as "3d" or "12h".
+    /// Local playlists are instead read again when they are modified
+    #[serde(default = "Source::default_refresh_interval")]
+    pub refresh_interval: String,
+    /// Extra headers to send when downloading, such as User-Agent
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Only used for playlists that are downloaded.
+    /// Defaults to the name of the source with .m3u8, or .json for Xtream Codes, appended
+    pub cache_filename: Option<String>,
+    /// Url or path of an XMLTV file, defaults to url-tvg in the header of the playlist
+    pub epg: Option<String>,
+    /// Read the url as an Xtream Codes panel with this account, instead of as a playlist
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xtream: Option<XtreamLogin>,
+}
+
+/**
+ * Where the playlist of a source comes from
+ */
+pub enum Location {
+    Url(Rc<String>),
+    File(PathBuf),
+    Stdin,
+}
+
+impl Location {
+    pub fn of(url: &Rc<String>) -> Self {
+        if **url == "-" {
+            Self::Stdin
+        } else if let Some(path) = url.strip_prefix("file://") {
+            Self::File(PathBuf::from(path))
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Self::Url(url.clone())
+        } else {
+            Self::File(PathBuf::from(&**url))
+        }
+    }
+}
+
+impl Source {
+    pub fn new(name: &str, url: String) -> Self {
+        Self {
+            name: Rc::new(name.to_owned()),
+            url: Rc::new(url),
+            refresh_interval: Self::default_refresh_interval(),
+            headers: BTreeMap::new(),
+            cache_filename: None,
+            epg: None,
+            xtream: None,
+        }
+    }
+
+    fn default_refresh_interval() -> String {
+        "3d".to_owned()
+    }
+
+    /**
+     * "-" is stdin, http(s) urls are downloaded and everything else is a local file,
+     * with or without file://
+     */
+    pub fn location(&self) -> Location {
+        Location::of(&self.url)
+    }
+
+    /**
+     * Xtream Codes sources are saved as json of the panel, instead of as a playlist
+     */
+    pub fn cache_filename(&self) -> String {
+        let ending = if self.xtream.is_some() {
+            "json"
+        } else {
+            "m3u8"
+        };
+        self.cache_filename
+            .clone()
+            .unwrap_or_else(|| format!("{}.{}", self.name, ending))
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        parse_duration(&self.refresh_interval).unwrap_or(Duration::from_secs(60 * 60 * 24 * 3))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OfflineConf {
+    /// Max size of the offline store, such as "50GB"
+    pub quota: Option<String>,
+    pub eviction_policy: Option<EvictionPolicy>,
+}
+
+/**
+ * What `ilovetv serve` shares with other devices, on top of the sources of the profile
+ */
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServeConf {
+    /// Where to listen, such as "0.0.0.0:8080"
+    pub address: Option<String>,
+    /// Only serve entries in these groups, every group if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Leave out entries with a name or group containing any of these, ignoring case
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// New names for entries, by their name in the playlist
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rename: BTreeMap<String, String>,
+    /// Names of entries in the playlist to put first, in this order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub favorites: Vec<String>,
+    /// Which entries to send through the relay: never, with-headers or always
+    #[serde(default)]
+    pub relay: RelayMode,
+    /// How many streams the emulated HDHomeRun tuner says it can play at the same time
+    pub tuners: Option<u32>,
+}
+
+impl ServeConf {
+    pub fn address(&self) -> &str {
+        self.address.as_deref().unwrap_or(DEFAULT_SERVE_ADDRESS)
+    }
+}
+
+/**
+ * The json api that controls ilovetv remotely, such as from a phone
+ */
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConf {
+    /// Where to listen, such as "0.0.0.0:8081" to be reachable from other devices
+    pub address: Option<String>,
+    /// Required as `Authorization: Bearer <token>` or ?token=<token> when set
+    pub token: Option<String>,
+}
+
+impl ApiConf {
+    pub fn address(&self) -> &str {
+        self.address.as_deref().unwrap_or(DEFAULT_API_ADDRESS)
+    }
+}
+
+/**
+ * How the prompt lists search results
+ */
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PromptConf {
+    /// How many results to list at a time
+    pub page_size: Option<usize>,
+    /// What the results are sorted by, until another key is picked
+    pub sort: Option<SortKey>,
+}
+
+impl PromptConf {
+    pub fn page_size(&self) -> usize {
+        self.page_size.unwrap_or(DEFAULT_PAGE_SIZE)
+    }
 }
 
 impl Conf {
     /**
-     * Read configurationfile or ask user for link input if it isn't created.
-     * Will error if it fails to write config file
+     * Read the configfile, migrating it from an older version if needed, or ask the user
+     * for a link if there isn't one. A configfile that can't be read is never overwritten,
+     * instead an error pointing to the problem is returned
      */
-    pub fn new(ilovetv_config_file: &Path) -> Result<Conf, io::Error> {
-        // Read the configuraionfile if it exists
-        if ilovetv_config_file.exists() {
-            let config_file = Self::read_configfile(&ilovetv_config_file);
-            if let Ok(cfg) = config_file {
-                return Ok(cfg);
-            } else {
-                println!("There are some problem with the configurationfile");
-            }
+    pub fn new(config_dir: &Path) -> Result<Conf, Error> {
+        let toml_path = config_dir.join(TOML_CONFIG_FILENAME);
+        let json_path = config_dir.join(JSON_CONFIG_FILENAME);
+
+        if toml_path.exists() {
+            let content = fs::read_to_string(&toml_path)
+                .map_err(|e| format!("Failed to read '{}', {:?}", toml_path.display(), e))?;
+            return Self::parse(&content, &toml_path);
+        }
+        if json_path.exists() {
+            return Self::migrate_from_json(&json_path, &toml_path);
         }
 
         // Get fresh config with url from user
-        let playlist_url = Self::user_setup().into();
-
-        Ok(Self {
-            playlist_filename: STANDARD_PLAYLIST_FILENAME.to_owned(),
-            playlist_url,
-            last_search: None,
+        let conf = Self {
+            version: CONFIG_VERSION,
+            active_profile: "default".to_owned(),
             seen_links_filename: STANDARD_SEEN_LINKS_FILENAME.to_owned(),
-        })
+            identity: Identity::default(),
+            offline: OfflineConf::default(),
+            serve: ServeConf::default(),
+            api: ApiConf::default(),
+            prompt: PromptConf::default(),
+            sources: vec![Source::new("default", Self::user_setup())],
+            profiles: BTreeMap::new(),
+        };
+        conf.write_configfile(&toml_path)
+            .map_err(|e| format!("Failed to write '{}', {:?}", toml_path.display(), e))?;
+        Ok(conf)
     }
 
-    fn read_configfile(config_file: &Path) -> Result<Conf, io::Error> {
-        let reader = BufReader::new(File::open(config_file)?);
-        let conf: Conf = serde_json::from_reader(reader)?;
+    fn parse(content: &str, path: &Path) -> Result<Conf, Error> {
+        let invalid = |e: String| format!("Invalid configfile '{}'\n{}", path.display(), e);
+
+        let table: toml::Table = toml::from_str(content).map_err(|e| invalid(e.to_string()))?;
+        let version = Self::get_version(&table).map_err(invalid)?;
+        if version == CONFIG_VERSION {
+            // Parse the text again rather than the table, to get line numbers in errors
+            let conf: Conf = toml::from_str(content).map_err(|e| invalid(e.to_string()))?;
+            conf.validate().map_err(invalid)?;
+            Ok(conf)
+        } else {
+            let conf = Self::migrate(table, version).map_err(invalid)?;
+            // A config that can't be used mustn't replace the one that was there
+            conf.validate().map_err(invalid)?;
+            let backup = path.with_extension(format!("toml.v{}.bak", version));
+            fs::copy(path, &backup)
+                .map_err(|e| format!("Failed to back up '{}', {:?}", path.display(), e))?;
+            conf.write_configfile(path)
+                .map_err(|e| format!("Failed to write '{}', {:?}", path.display(), e))?;
+            println!(
+                "Migrated the configfile from version {} to {}, the old one is saved as '{}'",
+                version,
+                CONFIG_VERSION,
+                backup.display()
+            );
+            Ok(conf)
+        }
+    }
+
+    /**
+     * The json config is version 0, it's left where it is after being migrated
+     */
+    fn migrate_from_json(json_path: &Path, toml_path: &Path) -> Result<Conf, Error> {
+        let invalid = |e: String| format!("Invalid configfile '{}'\n{}", json_path.display(), e);
+
+        let reader = BufReader::new(
+            File::open(json_path)
+                .map_err(|e| format!("Failed to read '{}', {:?}", json_path.display(), e))?,
+        );
+        let json = serde_json::from_reader(reader).map_err(|e| invalid(e.to_string()))?;
+
+        let conf = Self::from_json(json).map_err(invalid)?;
+        conf.write_configfile(toml_path)
+            .map_err(|e| format!("Failed to write '{}', {:?}", toml_path.display(), e))?;
+        println!(
+            "Migrated '{}' to '{}'",
+            json_path.display(),
+            toml_path.display()
+        );
         Ok(conf)
     }
 
-    fn write_configfile(&self, path: &Path) -> Result<(), io::Error> {
-        fs::write(path, serde_json::to_string(&self)?)?;
+    /**
+     * Read a config that has been converted to json, such as the old json config or an
+     * exported one. It's migrated if it's from an older version
+     */
+    pub fn from_json(mut json: serde_json::Map<String, serde_json::Value>) -> Result<Conf, Error> {
+        remove_nulls(&mut json);
+        let table = toml::Table::try_from(json).map_err(|e| e.to_string())?;
+
+        let version = Self::get_version(&table)?;
+        let conf = Self::migrate(table, version)?;
+        conf.validate()?;
+        Ok(conf)
+    }
+
+    fn get_version(table: &toml::Table) -> Result<u32, Error> {
+        let version = match table.get("version") {
+            Some(toml::Value::Integer(version)) => u32::try_from(*version)
+                .map_err(|_| format!("Invalid value for `version`: {}", version))?,
+            Some(other) => {
+                return Err(format!(
+                    "Invalid value for `version`: expected an integer, found {}",
+                    other.type_str()
+                ))
+            }
+            None => 0,
+        };
+        if version > CONFIG_VERSION {
+            return Err(format!(
+                "`version` is {}, but this version of ilovetv only understands up to {}",
+                version, CONFIG_VERSION
+            ));
+        }
+        Ok(version)
+    }
+
+    fn migrate(mut table: toml::Table, from_version: u32) -> Result<Conf, Error> {
+        for migration in &MIGRATIONS[from_version as usize..] {
+            migration(&mut table);
+        }
+        table.insert(
+            "version".to_owned(),
+            toml::Value::Integer(CONFIG_VERSION.into()),
+        );
+
+        Conf::deserialize(toml::Value::Table(table)).map_err(|e| e.to_string())
+    }
+
+    /**
+     * Check the values that are valid toml but still can't be used
+     */
+    fn validate(&self) -> Result<(), Error> {
+        validate_filename("seen_links_filename", &self.seen_links_filename)?;
+
+        if self.sources.is_empty() {
+            return Err("Invalid value for `sources`: there has to be at least one".to_owned());
+        }
+        for (idx, source) in self.sources.iter().enumerate() {
+            let key = |field: &str| format!("sources[{}].{}", idx, field);
+            validate_filename(&key("name"), &source.name)?;
+            if self.sources[..idx].iter().any(|x| x.name == source.name) {
+                return Err(format!(
+                    "Invalid value for `{}`: there is already a source called '{}'",
+                    key("name"),
+                    source.name
+                ));
+            }
+            if source.url.trim().is_empty() {
+                return Err(format!(
+                    "Invalid value for `{}`: it can't be empty",
+                    key("url")
+                ));
+            }
+            if source.xtream.is_some() && !matches!(source.location(), Location::Url(_)) {
+                return Err(format!(
+                    "Invalid value for `{}`: an Xtream Codes panel has to be an http(s) url",
+                    key("url")
+                ));
+            }
+            let is_stdin = |x: &Source| matches!(x.location(), Location::Stdin);
+            if is_stdin(source) && self.sources[..idx].iter().any(is_stdin) {
+                return Err(format!(
+                    "Invalid value for `{}`: only one source can read from stdin",
+                    key("url")
+                ));
+            }
+            parse_duration(&source.refresh_interval)
+                .map_err(|e| format!("Invalid value for `{}`: {}", key("refresh_interval"), e))?;
+            validate_filename(&key("cache_filename"), &source.cache_filename())?;
+            // They would overwrite each other's playlist
+            if let Some(other) = self.sources[..idx]
+                .iter()
+                .find(|x| x.cache_filename() == source.cache_filename())
+            {
+                return Err(format!(
+                    "Invalid value for `{}`: '{}' is already used by the source '{}'",
+                    key("cache_filename"),
+                    source.cache_filename(),
+                    other.name
+                ));
+            }
+        }
+
+        for (profile, sources) in self.profiles.iter() {
+            if sources.is_empty() {
+                return Err(format!(
+                    "Invalid value for `profiles.{}`: it needs at least one source",
+                    profile
+                ));
+            }
+            if let Some(missing) = sources
+                .iter()
+                .find(|name| !self.sources.iter().any(|x| *x.name == **name))
+            {
+                return Err(format!(
+                    "Invalid value for `profiles.{}`: there is no source called '{}'",
+                    profile, missing
+                ));
+            }
+        }
+        if self.profile_sources(&self.active_profile).is_none() {
+            return Err(format!(
+                "Invalid value for `active_profile`: there is no profile or source called '{}'",
+                self.active_profile
+            ));
+        }
+
+        if let Some(quota) = &self.offline.quota {
+            parse_size(quota).map_err(|e| format!("Invalid value for `offline.quota`: {}", e))?;
+        }
+        if let Some(address) = &self.serve.address {
+            address.parse::<SocketAddr>().map_err(|_| {
+                format!(
+                    "Invalid value for `serve.address`: '{}' has to be an ip and port, such as {}",
+                    address, DEFAULT_SERVE_ADDRESS
+                )
+            })?;
+        }
+        if let Some(address) = &self.api.address {
+            address.parse::<SocketAddr>().map_err(|_| {
+                format!(
+                    "Invalid value for `api.address`: '{}' has to be an ip and port, such as {}",
+                    address, DEFAULT_API_ADDRESS
+                )
+            })?;
+        }
+        if self.api.token.as_deref() == Some("") {
+            return Err("Invalid value for `api.token`: it can't be empty".to_owned());
+        }
+        if self.serve.tuners == Some(0) {
+            return Err(
+                "Invalid value for `serve.tuners`: there has to be at least one".to_owned(),
+            );
+        }
+        if self.prompt.page_size == Some(0) {
+            return Err(
+                "Invalid value for `prompt.page_size`: there has to be at least one".to_owned(),
+            );
+        }
+
         Ok(())
     }
 
+    /**
+     * The sources of a profile. A single source can also be used as a profile,
+     * and "all" merges every source unless there is a profile with that name
+     */
+    pub fn profile_sources(&self, profile: &str) -> Option<Vec<&Source>> {
+        if let Some(names) = self.profiles.get(profile) {
+            return Some(
+                self.sources
+                    .iter()
+                    .filter(|source| names.iter().any(|name| **name == *source.name))
+                    .collect(),
+            );
+        }
+        if let Some(source) = self.sources.iter().find(|x| *x.name == profile) {
+            return Some(vec![source]);
+        }
+        if profile == ALL_SOURCES_PROFILE {
+            return Some(self.sources.iter().collect());
+        }
+        None
+    }
+
+    /**
+     * Everything that can be used as a profile, sorted by name. A profile may be
+     * called the same as a source, which is then only listed once
+     */
+    pub fn profile_names(&self) -> Vec<String> {
+        let mut names: BTreeSet<String> = self.profiles.keys().cloned().collect();
+        names.extend(self.sources.iter().map(|x| x.name.to_string()));
+        names.insert(ALL_SOURCES_PROFILE.to_owned());
+        names.into_iter().collect()
+    }
+
+    /**
+     * Write to a temporary file first, so that the configfile never is left half written
+     */
+    fn write_configfile(&self, path: &Path) -> Result<(), io::Error> {
+        let content = toml::to_string_pretty(&self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp_path = path.with_extension("toml.tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(tmp_path, path)
+    }
+
     fn user_setup() -> String {
         let mut readline = Readline::new();
 
@@ -76,18 +552,106 @@ impl Conf {
     }
 }
 
+/**
+ * There is no null in toml, a missing key is the same thing
+ */
+fn remove_nulls(json: &mut serde_json::Map<String, serde_json::Value>) {
+    json.retain(|_, value| !value.is_null());
+    for value in json.values_mut() {
+        match value {
+            serde_json::Value::Object(map) => remove_nulls(map),
+            serde_json::Value::Array(values) => values
+                .iter_mut()
+                .filter_map(|x| x.as_object_mut())
+                .for_each(remove_nulls),
+            _ => {}
+        }
+    }
+}
+
+fn validate_filename(key: &str, filename: &str) -> Result<(), Error> {
+    if filename.is_empty() || filename.contains(['/', '\\']) {
+        return Err(format!(
+            "Invalid value for `{}`: '{}' has to be a plain filename",
+            key, filename
+        ));
+    }
+    Ok(())
+}
+
+/**
+ * Parse a duration such as "3d", "12h", "30m" or "90s"
+ */
+pub fn parse_duration(duration: &str) -> Result<Duration, Error> {
+    let duration = duration.trim();
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(unit_start);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid duration", duration))?;
+
+    let seconds = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => {
+            return Err(format!(
+                "'{}' has to end with one of s, m, h, d or w",
+                duration
+            ))
+        }
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+/**
+ * The offline settings got their own table
+ */
+fn migrate_v0_to_v1(table: &mut toml::Table) {
+    let mut offline = toml::Table::new();
+    for (old_key, new_key) in [
+        ("offline_quota", "quota"),
+        ("eviction_policy", "eviction_policy"),
+    ] {
+        if let Some(value) = table.remove(old_key) {
+            offline.insert(new_key.to_owned(), value);
+        }
+    }
+    if !offline.is_empty() {
+        table.insert("offline".to_owned(), toml::Value::Table(offline));
+    }
+}
+
 pub struct Configuration {
     pub conf: Conf,
-    pub playlist_path: Rc<PathBuf>,
+    pub cache_dir: PathBuf,
+    /// The profile in use, which may differ from the one in the configfile
+    pub active_profile: String,
     pub seen_links_path: PathBuf,
+    /// Ids of the watched entries, see `Identity`
     pub seen_links: Vec<String>,
+    /// What was searched for last, kept in the cache directory rather than the configfile
+    pub last_search: Option<String>,
+    last_search_path: PathBuf,
     config_file_path: PathBuf,
     pub data_dir: PathBuf,
+    /// What changed in the playlists the latest refreshes
+    pub diff_history_path: PathBuf,
+    /// When entries were played and for how long
+    pub watch_history_path: PathBuf,
+    /// Recordings that are scheduled or done
+    pub recordings_path: PathBuf,
+    /// Entries marked as favorites in the terminal ui
+    pub favorites_path: PathBuf,
     pub offlinefile_content: Rc<Vec<OfflineEntry>>,
 }
 
 impl Configuration {
-    pub fn new() -> Result<Self, io::Error> {
+    pub fn new() -> Result<Self, Error> {
         let project_dirs =
             ProjectDirs::from(APP_IDENTIFIER[0], APP_IDENTIFIER[1], APP_IDENTIFIER[2]).unwrap();
 
@@ -102,17 +666,17 @@ impl Configuration {
         }
 
         // Config setup
-        let config_file_path = config_dir.join(JSON_CONFIG_FILENAME).to_path_buf();
-        let configuration = Conf::new(&config_file_path)?;
-        fs::write(
-            &config_file_path,
-            serde_json::to_string(&configuration).unwrap(),
-        )?;
+        let config_file_path = config_dir.join(TOML_CONFIG_FILENAME);
+        let configuration = Conf::new(config_dir)?;
 
         // Playlist
-        let playlist_path = cache_dir.join(&configuration.playlist_filename).into();
         let seen_links_path = cache_dir.join(&configuration.seen_links_filename);
         let seen_links = Self::get_watched(&seen_links_path).unwrap_or_default();
+        let last_search_path = cache_dir.join(STANDARD_LAST_SEARCH_FILENAME);
+        let last_search = fs::read_to_string(&last_search_path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .flatten();
 
         // Datadir
         let offlinefile = offline_dir.join(STANDARD_OFFLINE_FILENAME);
@@ -120,28 +684,138 @@ impl Configuration {
             Rc::new(Self::get_offline_content(&offlinefile).unwrap_or_default());
 
         Ok(Self {
+            active_profile: configuration.active_profile.clone(),
             conf: configuration,
-            playlist_path,
+            cache_dir,
             seen_links,
             seen_links_path,
+            last_search,
+            last_search_path,
             config_file_path,
+            diff_history_path: offline_dir.join(STANDARD_DIFF_HISTORY_FILENAME),
+            watch_history_path: offline_dir.join(STANDARD_WATCH_HISTORY_FILENAME),
+            recordings_path: offline_dir.join(STANDARD_RECORDINGS_FILENAME),
+            favorites_path: offline_dir.join(STANDARD_FAVORITES_FILENAME),
             data_dir: offline_dir,
             offlinefile_content,
         })
     }
 
     pub fn update_last_search_ugly(&self, last_search: Option<String>) {
-        unsafe { get_mut_ref(&self.conf).last_search = last_search }
+        unsafe { get_mut_ref(self) }.last_search = last_search;
+
+        let written = serde_json::to_string(&self.last_search)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&self.last_search_path, content));
+        if let Err(e) = written {
+            println!("Failed to save the last search, {:?}", e);
+        }
+    }
+
+    /**
+     * The sources of the active profile
+     */
+    pub fn active_sources(&self) -> Vec<Source> {
+        self.profile_sources(&self.active_profile)
+            .unwrap_or_default()
+            .into_iter()
+            .cloned()
+            .collect()
+    }
 
-        if let Err(e) = self.write_configfile(&self.config_file_path) {
-            println!("Failed to write to configfile, {:?}", e);
+    /**
+     * Switch profile, and make it the default for the next time if `persist` is set
+     */
+    pub fn set_active_profile_ugly(&self, profile: &str, persist: bool) -> Result<(), Error> {
+        if self.profile_sources(profile).is_none() {
+            return Err(format!(
+                "There is no profile or source called '{}'",
+                profile
+            ));
+        }
+        unsafe { get_mut_ref(self) }.active_profile = profile.to_owned();
+
+        if persist {
+            unsafe { get_mut_ref(&self.conf) }.active_profile = profile.to_owned();
+            self.write_configfile(&self.config_file_path)
+                .map_err(|e| format!("Failed to write to configfile, {:?}", e))?;
         }
+        Ok(())
+    }
+
+    pub fn set_seen_links_ugly(&self, seen_links: Vec<String>) {
+        unsafe { get_mut_ref(self) }.seen_links = seen_links;
+    }
+
+    /**
+     * Read the watched entries from disk again, for when the api or another process
+     * may have marked more of them since
+     */
+    pub fn reload_seen_links_ugly(&self) {
+        if let Some(seen_links) = Self::get_watched(&self.seen_links_path) {
+            self.set_seen_links_ugly(seen_links);
+        }
+    }
+
+    /**
+     * When the configfile was last written to
+     */
+    pub fn config_modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.config_file_path)
+            .and_then(|x| x.modified())
+            .ok()
+    }
+
+    /**
+     * Replace the whole config, such as with an imported one.
+     * The old configfile is kept as a backup
+     */
+    pub fn replace_conf_ugly(&self, conf: Conf) -> Result<PathBuf, Error> {
+        conf.validate()?;
+        let backup = self.config_file_path.with_extension("toml.bak");
+        fs::copy(&self.config_file_path, &backup).map_err(|e| {
+            format!(
+                "Failed to back up '{}', {:?}",
+                self.config_file_path.display(),
+                e
+            )
+        })?;
+        conf.write_configfile(&self.config_file_path)
+            .map_err(|e| format!("Failed to write to configfile, {:?}", e))?;
+        unsafe { get_mut_ref(self) }.conf = conf;
+        Ok(backup)
+    }
+
+    pub fn write_seen_links(&self) -> Result<(), io::Error> {
+        fs::write(
+            &self.seen_links_path,
+            serde_json::to_string(&self.seen_links)?,
+        )
     }
 
     pub fn push_offlinefile_ugly(&self, data_entry: OfflineEntry) {
         unsafe { get_mut_ref(&*self.offlinefile_content) }.push(data_entry);
     }
 
+    pub fn replace_offlinefile_ugly(&self, index: usize, data_entry: OfflineEntry) {
+        let offlinefile_content = unsafe { get_mut_ref(&*self.offlinefile_content) };
+        offlinefile_content[index] = data_entry;
+    }
+
+    pub fn remove_offlinefile_ugly(&self, index: usize) -> OfflineEntry {
+        unsafe { get_mut_ref(&*self.offlinefile_content) }.remove(index)
+    }
+
+    /**
+     * Read the offline catalog from disk again, for when another process may have changed it
+     */
+    pub fn reload_offlinefile_ugly(&self) {
+        let path = self.data_dir.join(STANDARD_OFFLINE_FILENAME);
+        if let Some(content) = Self::get_offline_content(&path) {
+            *unsafe { get_mut_ref(&*self.offlinefile_content) } = content;
+        }
+    }
+
     pub fn write_datafile(&self) -> Result<(), io::Error> {
         let path = self.data_dir.join(STANDARD_OFFLINE_FILENAME);
         fs::write(path, serde_json::to_string(&self.offlinefile_content)?)
@@ -165,3 +839,34 @@ impl Deref for Configuration {
         &self.conf
     }
 }
+
+/**
+ * The single playlist became the first of many sources
+ */
+fn migrate_v1_to_v2(table: &mut toml::Table) {
+    let mut source = toml::Table::new();
+    source.insert("name".to_owned(), toml::Value::String("default".to_owned()));
+    if let Some(url) = table.remove("playlist_url") {
+        source.insert("url".to_owned(), url);
+    }
+    let cache_filename = table
+        .remove("playlist_filename")
+        .unwrap_or_else(|| toml::Value::String(STANDARD_PLAYLIST_FILENAME.to_owned()));
+    source.insert("cache_filename".to_owned(), cache_filename);
+
+    table.insert(
+        "sources".to_owned(),
+        toml::Value::Array(vec![toml::Value::Table(source)]),
+    );
+    table.insert(
+        "active_profile".to_owned(),
+        toml::Value::String("default".to_owned()),
+    );
+}
+
+/**
+ * The last search moved to the cache directory, so that searching doesn't rewrite the configfile
+ */
+fn migrate_v2_to_v3(table: &mut toml::Table) {
+    table.remove("last_search");
+}
@@ -1,13 +1,17 @@
 use std::{
+    collections::BTreeMap,
     error, fmt,
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     io::{self, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 use bytes::Bytes;
 use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use reqwest::{self, Client};
+use reqwest::{self, header, Client, StatusCode};
+use serde::{Deserialize, Serialize};
 use std::cmp;
 
 use crate::get_mut_ref;
@@ -89,13 +93,27 @@ impl TryInto<String> for DualWriter {
 pub async fn download_with_progress(
     link: &str,
     file_name: Option<&str>,
+) -> Result<DualWriter, Box<dyn error::Error>> {
+    download_with_headers(link, file_name, &BTreeMap::new()).await
+}
+
+/**
+ * Same as download_with_progress, but with extra headers such as User-Agent or Referer
+ */
+pub async fn download_with_headers(
+    link: &str,
+    file_name: Option<&str>,
+    headers: &BTreeMap<String, String>,
 ) -> Result<DualWriter, Box<dyn error::Error>> {
     let mut dual_writer: DualWriter = file_name.try_into()?;
 
     let client = Client::builder().gzip(true).deflate(true).build()?;
-    let builder = client
+    let mut builder = client
         .get(link)
         .header("Range", format!("bytes={}-", dual_writer.len()));
+    for (key, value) in headers.iter() {
+        builder = builder.header(key, value);
+    }
 
     let resp = builder.send().await?;
 
@@ -123,3 +141,187 @@ pub async fn download_with_progress(
 
     Ok(dual_writer)
 }
+
+/**
+ * Ask the server how big the file behind the link is, without downloading it.
+ * Returns None if the server can't be reached or doesn't say
+ */
+pub async fn remote_content_length(link: &str) -> Option<u64> {
+    let client = Client::builder().gzip(true).deflate(true).build().ok()?;
+    let resp = client.head(link).send().await.ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+
+    resp.headers()
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/**
+ * How far a download has come, shared with whoever shows it while it runs
+ */
+#[derive(Default, Debug)]
+pub struct DownloadProgress {
+    pub downloaded: AtomicU64,
+    /// Zero until the server has said how big it is, if it does
+    pub total: AtomicU64,
+}
+
+/**
+ * Stream a file to disk without keeping it in memory, for big files such as an EPG.
+ * It's written to a temporary file first, so a failed download never replaces the old file
+ */
+pub async fn download_to_file(
+    link: &str,
+    path: &Path,
+    headers: &BTreeMap<String, String>,
+) -> Result<u64, String> {
+    download_to_file_with_progress(link, path, headers, &DownloadProgress::default()).await
+}
+
+/**
+ * Same as download_to_file, while keeping `progress` up to date
+ */
+pub async fn download_to_file_with_progress(
+    link: &str,
+    path: &Path,
+    headers: &BTreeMap<String, String>,
+    progress: &DownloadProgress,
+) -> Result<u64, String> {
+    let client = Client::builder()
+        .gzip(true)
+        .deflate(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut builder = client.get(link);
+    for (key, value) in headers.iter() {
+        builder = builder.header(key, value);
+    }
+    let resp = builder.send().await.map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("The server answered {}", resp.status()));
+    }
+    progress
+        .total
+        .store(resp.content_length().unwrap_or_default(), Ordering::Relaxed);
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path).map_err(|e| format!("{:?}", e))?;
+    let mut written = 0;
+    let mut stream = resp.bytes_stream();
+    while let Some(item) = stream.next().await {
+        let bytes = item.map_err(|e| e.to_string())?;
+        file.write_all(&bytes).map_err(|e| format!("{:?}", e))?;
+        written += bytes.len() as u64;
+        progress.downloaded.store(written, Ordering::Relaxed);
+    }
+    fs::rename(&tmp_path, path).map_err(|e| format!("{:?}", e))?;
+    Ok(written)
+}
+
+/**
+ * What the server told us about the file the last time it was downloaded,
+ * so that it can tell us whether it has changed since
+ */
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+pub enum Fetched {
+    NotModified,
+    Modified {
+        content: String,
+        validators: Validators,
+    },
+}
+
+/**
+ * Download a text file with If-None-Match and If-Modified-Since, so that nothing is
+ * downloaded if it's unchanged. Without progress the future doesn't print anything,
+ * which makes it possible to run it in the background
+ */
+pub async fn fetch_if_modified(
+    link: String,
+    headers: BTreeMap<String, String>,
+    validators: Validators,
+    show_progress: bool,
+) -> Result<Fetched, String> {
+    let client = Client::builder()
+        .gzip(true)
+        .deflate(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut builder = client.get(&link);
+    for (key, value) in headers.iter() {
+        builder = builder.header(key, value);
+    }
+    if let Some(etag) = validators.etag.as_ref() {
+        builder = builder.header(header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = validators.last_modified.as_ref() {
+        builder = builder.header(header::IF_MODIFIED_SINCE, last_modified);
+    }
+
+    let resp = builder.send().await.map_err(|e| e.to_string())?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified);
+    }
+    if !resp.status().is_success() {
+        return Err(format!("The server answered {}", resp.status()));
+    }
+
+    let get_header = |name: header::HeaderName| {
+        resp.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let validators = Validators {
+        etag: get_header(header::ETAG),
+        last_modified: get_header(header::LAST_MODIFIED),
+    };
+
+    let progress_bar = match resp.content_length() {
+        Some(content_length) if show_progress => {
+            let progress_bar = ProgressBar::new(content_length);
+            progress_bar.set_style(
+                ProgressStyle::with_template(
+                    "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}",
+                )
+                .unwrap()
+                .progress_chars("#>-"),
+            );
+            progress_bar
+        }
+        None if show_progress => ProgressBar::new_spinner(),
+        _ => ProgressBar::hidden(),
+    };
+
+    let mut buffer = Vec::new();
+    let mut stream = resp.bytes_stream();
+    while let Some(item) = stream.next().await {
+        let bytes = item.map_err(|e| e.to_string())?;
+        buffer.extend_from_slice(&bytes);
+        progress_bar.set_position(buffer.len() as u64);
+    }
+    progress_bar.finish_and_clear();
+
+    let content = String::from_utf8(buffer).or(Err("Failed to decode buffer".to_owned()))?;
+    Ok(Fetched::Modified {
+        content,
+        validators,
+    })
+}
@@ -0,0 +1,205 @@
+use std::{path::Path, rc::Rc, sync::Arc};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{
+    downloader::{download_to_file_with_progress, DownloadProgress},
+    offlinelibrary::file_ending,
+    remote_content_length, GrandMother, M3u8, OfflineEntry, OfflineLibrary, WatchedFind,
+};
+
+type Error = String;
+
+/// The id of a download and how it went, sent when it's done. While measuring it's the size
+pub type Finished = (usize, Result<u64, Error>);
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum DownloadStatus {
+    Queued,
+    /// Asking the server how big it is, to make room for it within the offline quota
+    Measuring,
+    Downloading,
+    Done {
+        size: u64,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+pub struct Download {
+    pub id: usize,
+    pub entry: M3u8,
+    pub path: String,
+    /// Added to the offline catalog when done, instead of just being saved
+    pub offline: bool,
+    pub status: DownloadStatus,
+    pub progress: Arc<DownloadProgress>,
+}
+
+impl Download {
+    pub fn is_pending(&self) -> bool {
+        matches!(
+            self.status,
+            DownloadStatus::Queued | DownloadStatus::Measuring | DownloadStatus::Downloading
+        )
+    }
+}
+
+/**
+ * Downloads that run one at a time in the background, for the api and the terminal ui.
+ * The finished downloads are sent to the receiver from `new`, and have to be given
+ * back to `finish` so that the next one starts
+ */
+pub struct DownloadQueue {
+    downloads: Vec<Download>,
+    finished: mpsc::UnboundedSender<Finished>,
+}
+
+impl DownloadQueue {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Finished>) {
+        let (finished, receiver) = mpsc::unbounded_channel();
+        let queue = Self {
+            downloads: Vec::new(),
+            finished,
+        };
+        (queue, receiver)
+    }
+
+    pub fn downloads(&self) -> &[Download] {
+        &self.downloads
+    }
+
+    /**
+     * Queue an entry, to the offline store or else to the current directory.
+     * Returns the id of the download
+     */
+    pub fn add(&mut self, gm: &GrandMother, entry: M3u8, offline: bool) -> usize {
+        let file_name = format!("{}{}", entry.name, file_ending(&entry.link));
+        let path = if offline {
+            gm.config.data_dir.join(file_name)
+        } else {
+            Path::new(&file_name).to_path_buf()
+        };
+        let id = self.downloads.len() + 1;
+        self.downloads.push(Download {
+            id,
+            entry,
+            path: path.to_string_lossy().to_string(),
+            offline,
+            status: DownloadStatus::Queued,
+            progress: Arc::new(DownloadProgress::default()),
+        });
+        self.start_next(gm);
+        id
+    }
+
+    /**
+     * Start the next queued download, unless one is already running. With an offline
+     * quota the server is asked how big it is first, which is sent back like a finished download
+     */
+    fn start_next(&mut self, gm: &GrandMother) {
+        if self.downloads.iter().any(|x| {
+            matches!(
+                x.status,
+                DownloadStatus::Measuring | DownloadStatus::Downloading
+            )
+        }) {
+            return;
+        }
+        let idx = match self
+            .downloads
+            .iter()
+            .position(|x| matches!(x.status, DownloadStatus::Queued))
+        {
+            Some(idx) => idx,
+            None => return,
+        };
+        let has_quota = matches!(OfflineLibrary::new(gm.config.clone()).quota(), Ok(Some(_)));
+        if !self.downloads[idx].offline || !has_quota {
+            self.start(gm, idx);
+            return;
+        }
+
+        let download = &mut self.downloads[idx];
+        download.status = DownloadStatus::Measuring;
+        let id = download.id;
+        let link = download.entry.link.to_string();
+        let finished = self.finished.clone();
+        tokio::spawn(async move {
+            let size = remote_content_length(&link).await.unwrap_or_default();
+            let _ = finished.send((id, Ok(size)));
+        });
+    }
+
+    fn start(&mut self, gm: &GrandMother, idx: usize) {
+        let download = &mut self.downloads[idx];
+        download.status = DownloadStatus::Downloading;
+        let id = download.id;
+        let link = download.entry.link.to_string();
+        let path = download.path.clone();
+        let headers = gm.headers_of(&download.entry);
+        let progress = download.progress.clone();
+        let finished = self.finished.clone();
+        tokio::spawn(async move {
+            let result =
+                download_to_file_with_progress(&link, Path::new(&path), &headers, &progress).await;
+            let _ = finished.send((id, result));
+        });
+    }
+
+    /**
+     * Add a finished offline download to the offline catalog, and start the next one.
+     * A download that was measured is started once old offline entries are evicted to make
+     * room for it, if the offline quota requires it. Returns the download that finished
+     */
+    pub fn finish(&mut self, gm: &GrandMother, (id, result): Finished) -> Option<&Download> {
+        let idx = self.downloads.iter().position(|x| x.id == id)?;
+        if matches!(self.downloads[idx].status, DownloadStatus::Measuring) {
+            match make_room_for(gm, result.unwrap_or_default()) {
+                Ok(()) => self.start(gm, idx),
+                Err(error) => {
+                    self.downloads[idx].status = DownloadStatus::Failed { error };
+                    self.start_next(gm);
+                }
+            }
+            return self.downloads.get(idx);
+        }
+
+        let download = &mut self.downloads[idx];
+        download.status = match result {
+            Ok(size) if download.offline => {
+                let config = &gm.config;
+                // The prompt or another command may have changed it since
+                config.reload_offlinefile_ugly();
+                let entry =
+                    OfflineEntry::new(download.entry.clone(), Rc::new(download.path.clone()));
+                OfflineLibrary::new(config.clone()).add_download(entry, true);
+                match config.write_datafile() {
+                    Ok(()) => DownloadStatus::Done { size },
+                    Err(e) => DownloadStatus::Failed {
+                        error: format!("Failed to write offline catalog {:?}", e),
+                    },
+                }
+            }
+            Ok(size) => DownloadStatus::Done { size },
+            Err(error) => DownloadStatus::Failed { error },
+        };
+        self.start_next(gm);
+        self.downloads.get(idx)
+    }
+}
+
+fn make_room_for(gm: &GrandMother, incoming: u64) -> Result<(), Error> {
+    let library = OfflineLibrary::new(gm.config.clone());
+    let watched_ids = gm.parser.get_watched_ids(gm.config.identity);
+    let watched_ids: Vec<&str> = watched_ids.iter().map(String::as_str).collect();
+    match library.enforce_quota(incoming, &watched_ids)? {
+        Some(report) if !report.fits(incoming) => {
+            Err("there isn't enough room within the offline quota".to_owned())
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use flate2::read::GzDecoder;
+use quick_xml::{events::Event, Reader, XmlVersion};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Location, downloader::download_to_file, Playlist};
+
+type Error = String;
+
+/// How old a downloaded EPG may be before it's downloaded again
+const EPG_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60 * 12);
+
+/**
+ * One programme in the EPG, with times in seconds since the unix epoch
+ */
+#[derive(Serialize, Deserialize, Clone, Hash, Debug)]
+pub struct Programme {
+    pub channel: String,
+    pub start: i64,
+    pub stop: i64,
+    pub title: String,
+    pub desc: String,
+}
+
+impl Programme {
+    pub fn is_airing(&self, now: i64) -> bool {
+        self.start <= now && now < self.stop
+    }
+
+    pub fn is_airing_now(&self) -> bool {
+        self.is_airing(now())
+    }
+
+    pub fn local_start(&self) -> String {
+        local_time(self.start, "%H:%M")
+    }
+
+    pub fn local_stop(&self) -> String {
+        local_time(self.stop, "%H:%M")
+    }
+}
+
+/**
+ * Where the EPG of a source comes from and where it's cached, so that it can be
+ * downloaded on another thread
+ */
+pub struct EpgDownload {
+    source: String,
+    url: String,
+    cache_path: PathBuf,
+    headers: BTreeMap<String, String>,
+}
+
+/**
+ * Programmes from XMLTV files, by channel id
+ */
+#[derive(Default)]
+pub struct Epg {
+    programmes: HashMap<String, Rc<Vec<Programme>>>,
+}
+
+impl Epg {
+    /**
+     * Get the EPG of every playlist and read them into one. The EPG of a source is
+     * its `epg` setting, or the url-tvg in the header of the playlist.
+     * A source whose EPG can't be loaded is skipped
+     */
+    pub async fn load(playlists: &[Playlist], cache_dir: &Path) -> Self {
+        let files = Self::download(Self::downloads(playlists, cache_dir)).await;
+        Self::read(&files)
+    }
+
+    pub fn downloads(playlists: &[Playlist], cache_dir: &Path) -> Vec<EpgDownload> {
+        let mut downloads = Vec::new();
+        for playlist in playlists.iter() {
+            for (idx, url) in playlist.epg_urls().into_iter().enumerate() {
+                downloads.push(EpgDownload {
+                    source: playlist.source.name.to_string(),
+                    url,
+                    cache_path: cache_dir.join(format!(
+                        "{}.{}.xmltv",
+                        playlist.source.name,
+                        idx + 1
+                    )),
+                    headers: playlist.source.headers.clone(),
+                });
+            }
+        }
+        downloads
+    }
+
+    /**
+     * Download the EPGs that aren't cached or are too old. Returns the files to read
+     */
+    pub async fn download(downloads: Vec<EpgDownload>) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for download in downloads {
+            match fetch_epg(&download.url, &download.cache_path, &download.headers).await {
+                Ok(path) => files.push(path),
+                Err(e) => println!("Failed to get the EPG of '{}', {}", download.source, e),
+            }
+        }
+        files
+    }
+
+    pub fn read(files: &[PathBuf]) -> Self {
+        let mut by_channel: HashMap<String, Vec<Programme>> = HashMap::new();
+        for file in files.iter() {
+            if let Err(e) = read_xmltv(file, &mut by_channel) {
+                println!("Failed to read the EPG '{}', {}", file.display(), e);
+            }
+        }
+        Self::from_programmes(by_channel)
+    }
+
+    fn from_programmes(by_channel: HashMap<String, Vec<Programme>>) -> Self {
+        let programmes = by_channel
+            .into_iter()
+            .map(|(channel, mut programmes)| {
+                programmes.sort_by_key(|x| x.start);
+                programmes.dedup_by(|a, b| a.start == b.start && a.title == b.title);
+                (channel, Rc::new(programmes))
+            })
+            .collect();
+        Self { programmes }
+    }
+
+    /**
+     * The programmes of a channel by its tvg-id, ordered by start
+     */
+    pub fn programmes(&self, tvg_id: &str) -> Option<Rc<Vec<Programme>>> {
+        if tvg_id.is_empty() {
+            return None;
+        }
+        self.programmes.get(&tvg_id.to_lowercase()).cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.programmes.is_empty()
+    }
+
+    /**
+     * Every programme of every channel
+     */
+    pub fn all(&self) -> impl Iterator<Item = &Programme> {
+        self.programmes.values().flat_map(|x| x.iter())
+    }
+}
+
+/**
+ * Download the EPG if it's an url and the cached one is too old, and return where it's stored
+ */
+async fn fetch_epg(
+    url: &str,
+    cache_path: &Path,
+    headers: &BTreeMap<String, String>,
+) -> Result<PathBuf, Error> {
+    match Location::of(&Rc::new(url.to_owned())) {
+        Location::File(path) => return Ok(path),
+        Location::Stdin => return Err("the EPG can't be read from stdin".to_owned()),
+        Location::Url(_) => {}
+    }
+
+    let is_fresh = fs::metadata(cache_path)
+        .and_then(|x| x.modified())
+        .map(|modified| {
+            modified
+                .elapsed()
+                .map(|x| x < EPG_REFRESH_INTERVAL)
+                .unwrap_or(false)
+        })
+        .unwrap_or(false);
+    if !is_fresh {
+        println!("Downloading the EPG from {}", url);
+        if let Err(e) = download_to_file(url, cache_path, headers).await {
+            // An old EPG is better than none
+            if !cache_path.exists() {
+                return Err(e);
+            }
+            println!("{}, using the one downloaded earlier", e);
+        }
+    }
+    Ok(cache_path.to_path_buf())
+}
+
+/**
+ * Read the programmes of an XMLTV file one element at a time, so that the whole file
+ * never has to be in memory. Gzipped files are decompressed on the fly
+ */
+fn read_xmltv(path: &Path, by_channel: &mut HashMap<String, Vec<Programme>>) -> Result<(), Error> {
+    let mut file = File::open(path).map_err(|e| format!("{:?}", e))?;
+    let mut magic = [0; 2];
+    let is_gzip = file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let file = File::open(path).map_err(|e| format!("{:?}", e))?;
+    let reader: Box<dyn BufRead> = if is_gzip {
+        Box::new(BufReader::new(GzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+
+    parse_xmltv(reader, by_channel)
+}
+
+/// The element of a programme that text is being read into
+enum Field {
+    Title,
+    Desc,
+    Other,
+}
+
+fn parse_xmltv(
+    reader: impl BufRead,
+    by_channel: &mut HashMap<String, Vec<Programme>>,
+) -> Result<(), Error> {
+    let mut reader = Reader::from_reader(reader);
+    let mut buf = Vec::new();
+    let mut programme: Option<Programme> = None;
+    let mut field = Field::Other;
+
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .map_err(|e| format!("at byte {}, {}", reader.buffer_position(), e))?;
+        match event {
+            Event::Start(element) => match element.name().as_ref() {
+                "programme" => {
+                    let attribute = |key: &str| {
+                        element
+                            .try_get_attribute(key)
+                            .ok()
+                            .flatten()
+                            .and_then(|x| x.normalized_value(XmlVersion::Implicit1_0).ok())
+                            .map(|x| x.to_string())
+                    };
+                    let start = attribute("start").and_then(|x| parse_xmltv_time(&x));
+                    let stop = attribute("stop").and_then(|x| parse_xmltv_time(&x));
+                    programme = match (attribute("channel"), start, stop) {
+                        (Some(channel), Some(start), Some(stop)) => Some(Programme {
+                            channel,
+                            start,
+                            stop,
+                            title: String::new(),
+                            desc: String::new(),
+                        }),
+                        _ => None,
+                    };
+                }
+                // Only the first title and description are used, there may be one per language
+                "title" if programme.as_ref().is_some_and(|x| x.title.is_empty()) => {
+                    field = Field::Title
+                }
+                "desc" if programme.as_ref().is_some_and(|x| x.desc.is_empty()) => {
+                    field = Field::Desc
+                }
+                _ => {}
+            },
+            Event::Text(text) => push_text(&mut programme, &field, &text.xml10_content()),
+            Event::CData(text) => push_text(&mut programme, &field, &text.xml10_content()),
+            Event::GeneralRef(reference) => {
+                let entity = format!("&{};", reference.xml10_content());
+                let resolved = quick_xml::escape::unescape(&entity)
+                    .map(|x| x.to_string())
+                    .unwrap_or(entity);
+                push_text(&mut programme, &field, &resolved);
+            }
+            Event::End(element) => match element.name().as_ref() {
+                "programme" => {
+                    if let Some(programme) = programme.take() {
+                        by_channel
+                            .entry(programme.channel.to_lowercase())
+                            .or_default()
+                            .push(programme);
+                    }
+                }
+                "title" | "desc" => field = Field::Other,
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+fn push_text(programme: &mut Option<Programme>, field: &Field, text: &str) {
+    if let Some(programme) = programme.as_mut() {
+        match field {
+            Field::Title => programme.title.push_str(text),
+            Field::Desc => programme.desc.push_str(text),
+            Field::Other => {}
+        }
+    }
+}
+
+/**
+ * XMLTV times look like "20230715203000 +0200", where the offset is optional and
+ * defaults to UTC
+ */
+fn parse_xmltv_time(time: &str) -> Option<i64> {
+    let time = time.trim();
+    if let Ok(time) = DateTime::parse_from_str(time, "%Y%m%d%H%M%S %z") {
+        return Some(time.timestamp());
+    }
+    let digits = time.get(..14)?;
+    NaiveDateTime::parse_from_str(digits, "%Y%m%d%H%M%S")
+        .ok()
+        .map(|x| x.and_utc().timestamp())
+}
+
+pub fn local_time(timestamp: i64, format: &str) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|x| x.with_timezone(&Local).format(format).to_string())
+        .unwrap_or_default()
+}
+
+pub fn now() -> i64 {
+    Utc::now().timestamp()
+}
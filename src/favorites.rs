@@ -0,0 +1,73 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+type Error = String;
+
+/**
+ * Entries picked out in the terminal ui, by their id so that they survive refreshes.
+ * `ilovetv serve` puts them first as well, and they are part of exports
+ */
+pub struct Favorites {
+    path: PathBuf,
+    ids: BTreeSet<String>,
+}
+
+impl Favorites {
+    pub fn new(path: &Path) -> Self {
+        let ids = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Self {
+            path: path.to_path_buf(),
+            ids,
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &String> {
+        self.ids.iter()
+    }
+
+    /**
+     * Add favorites from elsewhere, such as an import, and save. Returns how many were new
+     */
+    pub fn merge(&mut self, ids: impl IntoIterator<Item = String>) -> Result<usize, Error> {
+        let before = self.ids.len();
+        self.ids.extend(ids);
+        let added = self.ids.len() - before;
+        if added > 0 {
+            self.save()?;
+        }
+        Ok(added)
+    }
+
+    /**
+     * Add or remove a favorite and save. Returns whether it's a favorite now
+     */
+    pub fn toggle(&mut self, id: &str) -> Result<bool, Error> {
+        let added = if self.ids.remove(id) {
+            false
+        } else {
+            self.ids.insert(id.to_owned())
+        };
+        self.save()?;
+        Ok(added)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let content = serde_json::to_string(&self.ids).map_err(|e| e.to_string())?;
+        fs::write(&self.path, content)
+            .map_err(|e| format!("Failed to write '{}', {:?}", self.path.display(), e))
+    }
+}
@@ -1,45 +1,102 @@
 #[allow(unused_imports)]
 use crate::GetM3u8;
 use crate::{
-    get_mut_ref,
+    config::Location,
+    downloader::Fetched,
+    epg, get_mut_ref,
     parser::{Parser, WatchedFind},
-    Configuration, OfflineParser, OnlineParser, Playlist, MAX_TRIES,
+    playlistdiff::{push_diff_history, PlaylistDiff},
+    watchhistory::{HistoryEntry, WatchHistory},
+    Configuration, Epg, M3u8, OfflineParser, OnlineParser, Playlist, Programme,
 };
-use std::{fs, rc::Rc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    path::PathBuf,
+    rc::Rc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::oneshot::{self, error::TryRecvError};
 
 type Error = String;
 
+/**
+ * The playlists and EPG of a refresh that was fetched on another thread
+ */
+pub struct Refreshed {
+    /// By the name of their source
+    playlists: Vec<(String, Result<Fetched, Error>)>,
+    epg_files: Vec<PathBuf>,
+}
+
 pub struct GrandMother {
     pub parser: Box<dyn Parser>,
-    pub playlist: Option<Playlist>,
+    /// One playlist per source in the active profile, empty in offline mode
+    pub playlists: Vec<Playlist>,
     pub config: Rc<Configuration>,
+    /// What changed the last time the playlists were refreshed
+    pub last_diff: Option<PlaylistDiff>,
+    /// Programmes of the live channels, empty in offline mode
+    pub epg: Epg,
 }
 
 impl GrandMother {
+    /**
+     * Load the playlists of all sources in the active profile. A source that fails
+     * is skipped, as long as there is at least one that works
+     */
     pub async fn new_online(config: Rc<Configuration>) -> Result<Self, Error> {
-        let playlist = Playlist::new(config.playlist_path.clone(), config.playlist_url.clone());
+        let mut playlists = Vec::new();
+        let mut errors = Vec::new();
+        for source in config.active_sources() {
+            match Playlist::new(source, &config.cache_dir).await {
+                Ok(playlist) => playlists.push(playlist),
+                Err(e) => errors.push(e),
+            }
+        }
+        if playlists.is_empty() {
+            return Err(errors.join(", "));
+        }
+        errors.iter().for_each(|e| println!("{}", e));
+
+        let epg = Epg::load(&playlists, &config.cache_dir).await;
         let seen_links = config.seen_links.iter().map(|x| x.as_str()).collect();
-        let playlist = playlist.await?;
-        let playlist_content = playlist.get_saved_or_download().await?;
         let parser: Box<dyn Parser> =
-            Box::new(OnlineParser::new(&playlist_content, &seen_links).await);
+            Box::new(OnlineParser::new(&playlists, &seen_links, config.identity, &epg).await);
 
         Ok(Self {
             parser,
-            playlist: Some(playlist),
+            playlists,
             config,
+            last_diff: None,
+            epg,
         })
     }
 
     pub fn demote_to_offline(&mut self) {
         let offline_mother = GrandMother::new_offline(self.config.clone());
-        (self.parser, self.playlist) = (offline_mother.parser, offline_mother.playlist);
+        (self.parser, self.playlists) = (offline_mother.parser, offline_mother.playlists);
+        self.epg = offline_mother.epg;
     }
 
     pub async fn promote_to_online(&mut self) -> Result<(), Error> {
         let online_mother = GrandMother::new_online(self.config.clone()).await?;
-        (self.parser, self.playlist) = (online_mother.parser, online_mother.playlist);
+        (self.parser, self.playlists) = (online_mother.parser, online_mother.playlists);
+        self.epg = online_mother.epg;
+
+        Ok(())
+    }
 
+    /**
+     * Load the sources of another profile, keeping the current ones if that fails
+     */
+    pub async fn switch_profile(&mut self, profile: &str, persist: bool) -> Result<(), Error> {
+        let previous = self.config.active_profile.clone();
+        self.config.set_active_profile_ugly(profile, persist)?;
+        if let Err(e) = self.promote_to_online().await {
+            self.config.set_active_profile_ugly(&previous, persist)?;
+            return Err(e);
+        }
         Ok(())
     }
 
@@ -47,8 +104,10 @@ impl GrandMother {
         let parser: Box<dyn Parser> = Box::new(OfflineParser::new(&config));
         Self {
             parser,
-            playlist: None,
+            playlists: Vec::new(),
             config,
+            last_diff: None,
+            epg: Epg::default(),
         }
     }
 
@@ -57,41 +116,347 @@ impl GrandMother {
     }
 
     pub async fn refresh(&mut self) -> Result<(), Error> {
-        let mut counter = 0;
-        let content = loop {
-            counter += 1;
-            let content = self
-                .playlist
-                .as_ref()
-                .ok_or_else(|| "Cannot refresh playlist in offlinemode")?
-                .download()
-                .await;
-            if counter > MAX_TRIES {
-                return Ok(());
+        if self.playlists.is_empty() {
+            return Err("Cannot refresh playlist in offlinemode".to_owned());
+        }
+
+        for playlist in self.playlists.iter_mut() {
+            if let Err(e) = playlist.refresh().await {
+                println!("{}, keeping the old one", e);
             }
-            if let Ok(content) = content {
-                break content;
+        }
+        self.epg = Epg::load(&self.playlists, &self.config.cache_dir).await;
+
+        self.reparse().await;
+        Ok(())
+    }
+
+    /**
+     * Fetch the playlists and the EPG like `refresh`, but on another thread so that
+     * a slow source doesn't hold up the caller. They are swapped in by `apply_refresh`.
+     * The EPG is found in the playlists from before the refresh
+     */
+    pub fn fetch_refresh(&self) -> Result<impl Future<Output = Refreshed> + Send + 'static, Error> {
+        if self.playlists.is_empty() {
+            return Err("Cannot refresh playlist in offlinemode".to_owned());
+        }
+
+        let fetches: Vec<_> = self
+            .playlists
+            .iter()
+            .filter_map(|playlist| {
+                Some((
+                    playlist.source.name.to_string(),
+                    playlist.fetch_in_background()?,
+                ))
+            })
+            .collect();
+        let epg = Epg::downloads(&self.playlists, &self.config.cache_dir);
+        Ok(async move {
+            let mut playlists = Vec::with_capacity(fetches.len());
+            for (source, fetch) in fetches {
+                playlists.push((source, fetch.await));
             }
-            println!("Retrying {}/{}", counter, MAX_TRIES);
+            Refreshed {
+                playlists,
+                epg_files: Epg::download(epg).await,
+            }
+        })
+    }
+
+    /**
+     * Swap in what `fetch_refresh` fetched. Local playlists are read again here
+     */
+    pub async fn apply_refresh(&mut self, refreshed: Refreshed) {
+        let mut fetched: HashMap<String, Result<Fetched, Error>> =
+            refreshed.playlists.into_iter().collect();
+        for playlist in self.playlists.iter_mut() {
+            let result = match fetched.remove(playlist.source.name.as_str()) {
+                Some(Ok(fetched)) => {
+                    playlist.apply_fetched(fetched);
+                    Ok(())
+                }
+                Some(Err(e)) => Err(format!(
+                    "Failed to download playlist '{}', {}",
+                    playlist.source.name, e
+                )),
+                // Downloading it here would hold up the caller after all
+                None if matches!(playlist.source.location(), Location::Url(_)) => Ok(()),
+                None => playlist.refresh().await,
+            };
+            if let Err(e) = result {
+                println!("{}, keeping the old one", e);
+            }
+        }
+        self.epg = Epg::read(&refreshed.epg_files);
+
+        self.reparse().await;
+    }
+
+    /**
+     * Read the EPG again, such as when the cached one may have been downloaded again
+     */
+    pub async fn reload_epg(&mut self) {
+        if self.playlists.is_empty() {
+            return;
+        }
+        self.epg = Epg::load(&self.playlists, &self.config.cache_dir).await;
+        self.reparse().await;
+    }
+
+    /**
+     * Parse the playlists again, keeping what has been watched.
+     * What changed is saved to the diff history
+     */
+    async fn reparse(&mut self) {
+        let identity = self.config.identity;
+        let watched_ids = self.parser.get_watched_ids(identity);
+        let watched_ids = watched_ids.iter().map(|x| x.as_str()).collect();
+        let parser: Box<dyn Parser> =
+            Box::new(OnlineParser::new(&self.playlists, &watched_ids, identity, &self.epg).await);
+
+        let diff = PlaylistDiff::between(&self.parser.get_m3u8(), &parser.get_m3u8());
+        self.parser = parser;
+        if !diff.is_empty() {
+            if let Err(e) = push_diff_history(&self.config.diff_history_path, &diff) {
+                println!("Failed to save what changed in the playlist, {}", e);
+            }
+        }
+        self.last_diff = Some(diff);
+    }
+
+    /**
+     * The entries that were added by the last refresh
+     */
+    pub fn new_since_refresh(&self) -> Vec<&M3u8> {
+        let added = match self.last_diff.as_ref() {
+            Some(diff) => &diff.added,
+            None => return Vec::new(),
         };
+        self.parser
+            .get_m3u8()
+            .into_iter()
+            .filter(|m3u8| {
+                added
+                    .iter()
+                    .any(|x| x.link == m3u8.link && x.name == m3u8.name && x.source == m3u8.source)
+            })
+            .collect()
+    }
 
-        let watched_links = self.parser.get_watched_links();
-        let watched_links = watched_links.iter().map(|x| x.as_str()).collect();
-        self.parser = Box::new(OnlineParser::new(&content, &watched_links).await);
+    /**
+     * Programmes that haven't ended yet, with the title or description containing `query`.
+     * Ordered by start, together with the channel that shows them
+     */
+    pub fn search_epg(&self, query: &str) -> Vec<(&Programme, &M3u8)> {
+        let query = query.to_lowercase();
+        let now = epg::now();
+        let mut seen_channels: Vec<String> = Vec::new();
+        let mut found: Vec<(&Programme, &M3u8)> = Vec::new();
+        for m3u8 in self.parser.get_m3u8() {
+            let programmes = match m3u8.programmes.as_ref() {
+                Some(programmes) => programmes,
+                None => continue,
+            };
+            // Several entries can share a tvg-id, such as the HD and SD version of a channel
+            let channel = m3u8.tvg_id.to_lowercase();
+            if seen_channels.contains(&channel) {
+                continue;
+            }
+            seen_channels.push(channel);
 
-        Ok(())
+            found.extend(
+                programmes
+                    .iter()
+                    .filter(|programme| programme.stop > now)
+                    .filter(|programme| {
+                        programme.title.to_lowercase().contains(&query)
+                            || programme.desc.to_lowercase().contains(&query)
+                    })
+                    .map(|programme| (programme, m3u8)),
+            );
+        }
+        found.sort_by_key(|(programme, _)| programme.start);
+        found
     }
 
+    /**
+     * Save the ids of the watched entries. The saved ones are read again first, so that
+     * those marked by the api meanwhile are kept, as are those from another profile.
+     * The ids of loaded entries are saved under the current identity
+     */
     pub fn save_watched(&self) {
-        let watched_items = self.parser.get_watched_links();
+        self.config.reload_seen_links_ugly();
+        let identity = self.config.identity;
+        let loaded = self.parser.get_m3u8();
+        let mut watched_items: Vec<String> = self
+            .config
+            .seen_links
+            .iter()
+            .filter(|id| {
+                !loaded
+                    .iter()
+                    .any(|m3u8| m3u8.watched && (**id == m3u8.id(identity) || **id == *m3u8.link))
+            })
+            .cloned()
+            .collect();
+        watched_items.extend(self.parser.get_watched_ids(identity));
 
-        let resp = fs::write(
-            &self.config.seen_links_path,
-            serde_json::to_string(&watched_items).unwrap(),
-        );
-
-        if let Err(e) = resp {
+        self.config.set_seen_links_ugly(watched_items);
+        if let Err(e) = self.config.write_seen_links() {
             eprintln!("Failed to write watched links {:?}", e);
         }
     }
+
+    /**
+     * The headers of the source that the entry comes from, needed to fetch it outside of mpv
+     */
+    pub fn headers_of(&self, m3u8: &M3u8) -> BTreeMap<String, String> {
+        self.playlists
+            .iter()
+            .find(|playlist| m3u8.source.as_deref() == Some(&playlist.source.name))
+            .map(|playlist| playlist.source.headers.clone())
+            .unwrap_or_default()
+    }
+
+    /**
+     * Add a play that started at `started` and just ended to the watch history
+     */
+    pub fn record_watch(&self, m3u8: &M3u8, started: SystemTime) {
+        let offline = self.playlists.is_empty();
+        let mut history = WatchHistory::new(&self.config.watch_history_path);
+        if let Err(e) = history.record(HistoryEntry::new(m3u8, started, offline)) {
+            println!("Failed to save the watch history, {}", e);
+        }
+    }
+
+    /**
+     * The loaded entries that have been played, most recently played first
+     */
+    pub fn recently_watched(&self, limit: usize) -> Vec<&M3u8> {
+        let identity = self.config.identity;
+        let loaded = self.parser.get_m3u8();
+        WatchHistory::new(&self.config.watch_history_path)
+            .recent_ids(identity)
+            .iter()
+            .filter_map(|id| loaded.iter().find(|m3u8| m3u8.id(identity) == *id))
+            .take(limit)
+            .copied()
+            .collect()
+    }
+}
+
+/// How long to wait before trying again when a background refresh failed
+const BACKGROUND_RETRY_DELAY: Duration = Duration::from_secs(60 * 10);
+
+type BackgroundResults = Vec<(Rc<String>, Result<Fetched, Error>)>;
+
+/**
+ * Refreshes the playlists that are due on another thread, so that the prompt isn't blocked.
+ * The new playlists are swapped in when polled
+ */
+#[derive(Default)]
+pub struct BackgroundRefresher {
+    running: Option<oneshot::Receiver<Vec<Result<Fetched, Error>>>>,
+    sources: Vec<Rc<String>>,
+    retry_after: Option<Instant>,
+}
+
+impl BackgroundRefresher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Swap in the playlists of a finished refresh, or start one if any playlist is due.
+     * Returns the names of the sources that changed. This replaces the parser,
+     * so nothing may borrow from it while polling
+     */
+    pub async fn poll(&mut self, gm: &mut GrandMother) -> Vec<Rc<String>> {
+        let results = match self.take_results() {
+            Some(results) => results,
+            None => {
+                self.spawn(gm);
+                return Vec::new();
+            }
+        };
+
+        let mut changed = Vec::new();
+        let mut failed = false;
+        for (source, result) in results {
+            // The profile may have been switched while refreshing
+            let playlist = gm
+                .playlists
+                .iter_mut()
+                .find(|playlist| playlist.source.name == source);
+            match (playlist, result) {
+                (Some(playlist), Ok(fetched)) => {
+                    if playlist.apply_fetched(fetched) {
+                        changed.push(source);
+                    }
+                }
+                (_, Err(_)) => failed = true,
+                (None, _) => {}
+            }
+        }
+        if failed {
+            self.retry_after = Some(Instant::now() + BACKGROUND_RETRY_DELAY);
+        }
+        if !changed.is_empty() {
+            gm.reparse().await;
+        }
+        changed
+    }
+
+    fn take_results(&mut self) -> Option<BackgroundResults> {
+        let receiver = self.running.as_mut()?;
+        let results = match receiver.try_recv() {
+            Ok(results) => results,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Closed) => Vec::new(),
+        };
+        self.running = None;
+
+        Some(
+            std::mem::take(&mut self.sources)
+                .into_iter()
+                .zip(results)
+                .collect(),
+        )
+    }
+
+    fn spawn(&mut self, gm: &GrandMother) {
+        let may_retry = self
+            .retry_after
+            .is_none_or(|retry_after| Instant::now() > retry_after);
+        if self.running.is_some() || !may_retry {
+            return;
+        }
+
+        let (sources, fetches): (Vec<_>, Vec<_>) = gm
+            .playlists
+            .iter()
+            .filter(|playlist| playlist.needs_refresh())
+            .filter_map(|playlist| {
+                Some((
+                    playlist.source.name.clone(),
+                    playlist.fetch_in_background()?,
+                ))
+            })
+            .unzip();
+        if fetches.is_empty() {
+            return;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut results = Vec::with_capacity(fetches.len());
+            for fetch in fetches {
+                results.push(fetch.await);
+            }
+            let _ = sender.send(results);
+        });
+        self.running = Some(receiver);
+        self.sources = sources;
+    }
 }
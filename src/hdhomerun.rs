@@ -0,0 +1,104 @@
+use std::net::SocketAddr;
+
+use quick_xml::escape::escape;
+use serde_json::json;
+
+use crate::{Relay, ServeConf, Snapshot, DEFAULT_TUNER_COUNT};
+
+/**
+ * Pretends to be an HDHomeRun network tuner, so that Plex, Jellyfin and Emby can use the
+ * served entries as live TV. The channels are tuned through the relay
+ */
+pub struct Tuner {
+    /// Eight hex digits, the same for as long as the server listens on the same address
+    device_id: String,
+    count: u32,
+}
+
+impl Tuner {
+    /**
+     * The tuner of a server listening on `address`, the one it was bound to
+     */
+    pub fn new(conf: &ServeConf, address: &SocketAddr) -> Self {
+        Self {
+            device_id: Relay::key(&address.to_string())[..8].to_uppercase(),
+            count: conf.tuners.unwrap_or(DEFAULT_TUNER_COUNT),
+        }
+    }
+
+    /**
+     * What the media servers ask for when the tuner is added by its address
+     */
+    pub fn discover(&self, host: &str) -> String {
+        json!({
+            "FriendlyName": "ilovetv",
+            "Manufacturer": "Silicondust",
+            "ModelNumber": "HDTC-2US",
+            "FirmwareName": "hdhomeruntc_atsc",
+            "FirmwareVersion": "20200101",
+            "DeviceID": self.device_id,
+            "DeviceAuth": "ilovetv",
+            "BaseURL": format!("http://{}", host),
+            "LineupURL": format!("http://{}/lineup.json", host),
+            "TunerCount": self.count,
+        })
+        .to_string()
+    }
+
+    /**
+     * The same as discover.json, for the media servers that look for the device over UPnP
+     */
+    pub fn device_xml(&self, host: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\">
+  <URLBase>http://{}</URLBase>
+  <specVersion><major>1</major><minor>0</minor></specVersion>
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:MediaServer:1</deviceType>
+    <friendlyName>ilovetv</friendlyName>
+    <manufacturer>Silicondust</manufacturer>
+    <modelName>HDTC-2US</modelName>
+    <modelNumber>HDTC-2US</modelNumber>
+    <serialNumber>{}</serialNumber>
+    <UDN>uuid:{}</UDN>
+  </device>
+</root>
+",
+            escape(host),
+            self.device_id,
+            self.device_id
+        )
+    }
+
+    /**
+     * The channels with the numbers they have in the served playlist
+     */
+    pub fn lineup(&self, snapshot: &Snapshot, host: &str) -> String {
+        let channels: Vec<_> = snapshot
+            .entries()
+            .iter()
+            .map(|entry| {
+                json!({
+                    "GuideNumber": entry.number.to_string(),
+                    "GuideName": entry.name,
+                    "URL": format!("http://{}/relay/{}", host, entry.key),
+                })
+            })
+            .collect();
+        serde_json::Value::from(channels).to_string()
+    }
+
+    /**
+     * There is nothing to scan, the lineup is always the served playlist
+     */
+    pub fn lineup_status(&self) -> String {
+        json!({
+            "ScanInProgress": 0,
+            "ScanPossible": 1,
+            "Source": "Cable",
+            "SourceList": ["Cable"],
+        })
+        .to_string()
+    }
+}
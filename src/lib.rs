@@ -1,52 +1,125 @@
+mod api;
+mod catchup;
 mod config;
 mod downloader;
+mod downloadqueue;
+mod epg;
+mod favorites;
 mod grandmother;
+mod hdhomerun;
+mod listing;
 mod m3u8;
+mod offlinelibrary;
 mod offlineparser;
 mod onlineparser;
 mod opt;
 pub mod parser;
+mod player;
 mod playlist;
+mod playlistdiff;
+mod recorder;
+mod relay;
+mod selection;
+mod server;
+mod tui;
+mod userstate;
+mod watchhistory;
+mod xtream;
 
 use std::{
-    io::{stdin, stdout, Stdin, StdoutLock, Write},
+    fs::File,
+    io::{stdin, stdout, BufRead, BufReader, Stdout, Write},
     rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
+pub use api::run_api;
 use async_recursion::async_recursion;
-pub use config::Configuration;
-pub use downloader::download_with_progress;
-pub use grandmother::GrandMother;
-pub use m3u8::{M3u8, OfflineEntry};
+pub use catchup::{Catchup, CatchupKind};
+pub use config::{
+    parse_duration, ApiConf, Conf, Configuration, Location, OfflineConf, PromptConf, ServeConf,
+    Source, ALL_SOURCES_PROFILE, CONFIG_VERSION, DEFAULT_API_ADDRESS, DEFAULT_PAGE_SIZE,
+    DEFAULT_SERVE_ADDRESS, DEFAULT_TUNER_COUNT,
+};
+pub use downloader::{
+    download_with_headers, download_with_progress, fetch_if_modified, remote_content_length,
+    DownloadProgress, Fetched, Validators,
+};
+pub use downloadqueue::{Download, DownloadQueue, DownloadStatus};
+pub use epg::{local_time, Epg, Programme};
+pub use favorites::Favorites;
+pub use grandmother::{BackgroundRefresher, GrandMother};
+pub use hdhomerun::Tuner;
+pub use listing::{Listing, SortKey};
+pub use m3u8::{Identity, M3u8, OfflineEntry};
+pub use offlinelibrary::{
+    check_entry, checksum_file, file_ending, DiskUsage, EntryStatus, EvictionPolicy,
+    EvictionReport, FileDeletion, OfflineLibrary,
+};
 pub use offlineparser::OfflineParser;
 pub use onlineparser::OnlineParser;
-pub use opt::{Mode, Opt};
+pub use opt::{HistoryCommand, Mode, OfflineCommand, Opt, RecordCommand, SubCommand};
 pub use parser::{GetM3u8, GetPlayPath, WatchedFind};
+pub use player::Player;
 pub use playlist::Playlist;
+pub use playlistdiff::{
+    push_diff_history, read_diff_history, ChangedLink, DiffEntry, PlaylistDiff,
+};
+pub use recorder::{
+    parse_local_time, recording_window, run_daemon, JobStatus, RecordingJob, Schedule,
+};
+pub use relay::{Relay, RelayMode, Upstream};
+pub use selection::select;
+pub use server::{serve, ServedEntry, Snapshot};
+pub use tui::run_tui;
+pub use userstate::{ImportReport, UserState, USER_STATE_VERSION};
+pub use watchhistory::{format_duration, HistoryEntry, Tally, WatchHistory, WatchStats};
+pub use xtream::{XtreamEntry, XtreamLogin, XtreamPanel, XtreamPlaylist};
 
 pub const JSON_CONFIG_FILENAME: &'static str = "config.json";
+pub const TOML_CONFIG_FILENAME: &'static str = "config.toml";
 pub const APP_IDENTIFIER: [&'static str; 3] = ["com", "billenius", "ilovetv"];
 pub const STANDARD_PLAYLIST_FILENAME: &'static str = "playlist.m3u8";
 pub const STANDARD_SEEN_LINKS_FILENAME: &'static str = "watched_links.json";
 pub const STANDARD_OFFLINE_FILENAME: &'static str = "ilovetv_offline.json";
+pub const STANDARD_DIFF_HISTORY_FILENAME: &'static str = "playlist_diffs.json";
+pub const STANDARD_WATCH_HISTORY_FILENAME: &'static str = "watch_history.json";
+pub const STANDARD_RECORDINGS_FILENAME: &'static str = "recordings.json";
+pub const STANDARD_FAVORITES_FILENAME: &'static str = "favorites.json";
+pub const STANDARD_LAST_SEARCH_FILENAME: &'static str = "last_search.json";
 pub const MAX_TRIES: u8 = 4;
 
-pub struct Readline<'a> {
-    stdout: StdoutLock<'a>,
-    stdin: Stdin,
+/// Set when a playlist has been read from stdin
+pub static STDIN_CONSUMED: AtomicBool = AtomicBool::new(false);
+
+pub struct Readline {
+    /// Not locked for longer than a print, so that other threads such as the api can print
+    stdout: Stdout,
+    stdin: Box<dyn BufRead>,
+    reads_tty: bool,
 }
 
-impl<'a> Readline<'a> {
+impl Readline {
     pub fn new() -> Self {
         Self {
-            stdout: stdout().lock(),
-            stdin: stdin(),
+            stdout: stdout(),
+            stdin: Box::new(BufReader::new(stdin())),
+            reads_tty: false,
         }
     }
 
     pub fn input(&mut self, toprint: &str) -> String {
         print!("{}", toprint);
         self.stdout.flush().unwrap();
+
+        // Read from the terminal directly if stdin has been used for a playlist
+        if !self.reads_tty && STDIN_CONSUMED.load(Ordering::SeqCst) {
+            if let Ok(tty) = File::open("/dev/tty") {
+                self.stdin = Box::new(BufReader::new(tty));
+            }
+            self.reads_tty = true;
+        }
+
         let mut buffer = String::new();
         self.stdin.read_line(&mut buffer).unwrap();
         buffer
@@ -66,7 +139,7 @@ pub unsafe fn get_mut_ref<T>(reference: &T) -> &mut T {
 #[async_recursion(?Send)]
 pub async fn get_gm(
     mode: Mode,
-    readline: &mut Readline<'_>,
+    readline: &mut Readline,
     config: Rc<Configuration>,
 ) -> Result<(GrandMother, bool), String> {
     match mode {
@@ -0,0 +1,190 @@
+use std::{collections::HashMap, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+#[allow(unused_imports)]
+use crate::GetM3u8;
+use crate::{GrandMother, M3u8, WatchHistory};
+
+type Error = String;
+
+/**
+ * What search results are sorted by. Ties keep the order of the playlists
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortKey {
+    /// The position in the playlists, which the channels are numbered by
+    #[default]
+    Number,
+    Name,
+    Group,
+    /// What hasn't been watched first
+    Watched,
+    /// What was played last first, and then what hasn't been played
+    Recent,
+}
+
+impl SortKey {
+    pub const ALL: [SortKey; 5] = [
+        SortKey::Number,
+        SortKey::Name,
+        SortKey::Group,
+        SortKey::Watched,
+        SortKey::Recent,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortKey::Number => "number",
+            SortKey::Name => "name",
+            SortKey::Group => "group",
+            SortKey::Watched => "watched",
+            SortKey::Recent => "recent",
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|key| key.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::ALL.iter().map(SortKey::name).collect();
+                format!("'{}' isn't one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/**
+ * Search results shown a page at a time. Every entry keeps its number on all pages,
+ * so the numbers can be used to play and download whichever page is shown
+ */
+pub struct Listing<'a> {
+    entries: Vec<&'a M3u8>,
+    sort: SortKey,
+    page: usize,
+    page_size: usize,
+}
+
+impl<'a> Listing<'a> {
+    pub fn new(gm: &GrandMother, found: Vec<&'a M3u8>, sort: SortKey, page_size: usize) -> Self {
+        let mut listing = Self {
+            entries: found,
+            sort,
+            page: 0,
+            page_size: page_size.max(1),
+        };
+        listing.sort(gm, sort);
+        listing
+    }
+
+    /**
+     * Sort the entries again, which numbers them anew and goes back to the first page
+     */
+    pub fn sort(&mut self, gm: &GrandMother, sort: SortKey) {
+        // Pointers tell the loaded entries apart, even those with the same name and link
+        let loaded = gm.parser.get_m3u8();
+        let numbers: HashMap<*const M3u8, usize> = loaded
+            .iter()
+            .enumerate()
+            .map(|(idx, m3u8)| (*m3u8 as *const M3u8, idx))
+            .collect();
+        self.entries
+            .sort_by_key(|m3u8| numbers.get(&(*m3u8 as *const M3u8)).copied());
+
+        match sort {
+            SortKey::Number => {}
+            SortKey::Name => self
+                .entries
+                .sort_by_cached_key(|m3u8| m3u8.name.to_lowercase()),
+            SortKey::Group => self
+                .entries
+                .sort_by_cached_key(|m3u8| m3u8.group_title.to_lowercase()),
+            SortKey::Watched => self.entries.sort_by_key(|m3u8| m3u8.watched),
+            SortKey::Recent => {
+                let identity = gm.config.identity;
+                let recent = WatchHistory::new(&gm.config.watch_history_path).recent_ids(identity);
+                let recent: HashMap<&str, usize> = recent
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, id)| (id.as_str(), idx))
+                    .collect();
+                self.entries.sort_by_cached_key(|m3u8| {
+                    recent
+                        .get(m3u8.id(identity).as_str())
+                        .copied()
+                        .unwrap_or(usize::MAX)
+                });
+            }
+        }
+        self.sort = sort;
+        self.page = 0;
+    }
+
+    pub fn sorted_by(&self) -> SortKey {
+        self.sort
+    }
+
+    pub fn entries(&self) -> &[&'a M3u8] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /**
+     * The entries on the current page with their numbers
+     */
+    pub fn page_entries(&self) -> Vec<(usize, &'a M3u8)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .skip(self.page * self.page_size)
+            .take(self.page_size)
+            .map(|(idx, m3u8)| (idx + 1, *m3u8))
+            .collect()
+    }
+
+    /// The current page, from 1
+    pub fn page(&self) -> usize {
+        self.page + 1
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.entries.len().div_ceil(self.page_size).max(1)
+    }
+
+    pub fn next_page(&mut self) -> Result<(), Error> {
+        self.go_to_page(self.page() + 1)
+            .map_err(|_| "This is the last page".to_owned())
+    }
+
+    pub fn previous_page(&mut self) -> Result<(), Error> {
+        self.go_to_page(self.page() - 1)
+            .map_err(|_| "This is the first page".to_owned())
+    }
+
+    /**
+     * Show another page, counted from 1
+     */
+    pub fn go_to_page(&mut self, page: usize) -> Result<(), Error> {
+        if page == 0 || page > self.page_count() {
+            return Err(format!(
+                "There is no page {}, only 1 to {}",
+                page,
+                self.page_count()
+            ));
+        }
+        self.page = page - 1;
+        Ok(())
+    }
+}
@@ -4,6 +4,7 @@ use std::{fmt::Display, ops::Deref, rc::Rc};
 
 #[allow(unused_imports)]
 use crate::GetM3u8;
+use crate::{epg, Catchup, Programme};
 
 #[derive(Serialize, Deserialize, Clone, Hash)]
 pub struct M3u8 {
@@ -14,6 +15,88 @@ pub struct M3u8 {
     pub name: String,
     pub link: Rc<String>,
     pub watched: bool,
+    /// Name of the source that the entry comes from
+    #[serde(default)]
+    pub source: Option<Rc<String>>,
+    /// How to play what the channel showed earlier, if the provider has an archive
+    #[serde(default)]
+    pub catchup: Option<Catchup>,
+    /// The EPG of the channel, if it's live and there is one
+    #[serde(skip)]
+    pub programmes: Option<Rc<Vec<Programme>>>,
+}
+
+/**
+ * How an entry is recognized when the playlist changes, which is what watched state
+ * and offline downloads are tied to. Urls with session tokens change on every refresh,
+ * while tvg-id or name and group usually don't
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Identity {
+    #[default]
+    Url,
+    UrlWithoutQuery,
+    TvgId,
+    NameGroup,
+}
+
+impl M3u8 {
+    /**
+     * The id of the entry under the identity strategy. Entries without a tvg-id
+     * fall back to name and group
+     */
+    pub fn id(&self, identity: Identity) -> String {
+        match identity {
+            Identity::Url => self.link.to_string(),
+            Identity::UrlWithoutQuery => self.link.split(['?', '#']).next().unwrap().to_owned(),
+            Identity::TvgId if !self.tvg_id.is_empty() => format!("tvg-id:{}", self.tvg_id),
+            Identity::TvgId | Identity::NameGroup => {
+                format!("name+group:{}/{}", self.group_title, self.name)
+            }
+        }
+    }
+
+    /**
+     * The programme that is airing, and the one after that
+     */
+    pub fn now_and_next(&self) -> (Option<&Programme>, Option<&Programme>) {
+        let programmes = match self.programmes.as_ref() {
+            Some(programmes) => programmes,
+            None => return (None, None),
+        };
+        let now = epg::now();
+        let mut upcoming = programmes.iter().filter(|x| x.stop > now);
+        match upcoming.next() {
+            Some(first) if first.is_airing(now) => (Some(first), upcoming.next()),
+            first => (None, first),
+        }
+    }
+
+    /**
+     * Such as "now: News (until 21:00), next: Weather"
+     */
+    pub fn describe_now_and_next(&self) -> Option<String> {
+        match self.now_and_next() {
+            (Some(now), Some(next)) => Some(format!(
+                "now: {} (until {}), next: {}",
+                now.title,
+                now.local_stop(),
+                next.title
+            )),
+            (Some(now), None) => Some(format!("now: {} (until {})", now.title, now.local_stop())),
+            (None, Some(next)) => Some(format!("next: {} (at {})", next.title, next.local_start())),
+            (None, None) => None,
+        }
+    }
+
+    /**
+     * Whether the list of ids contains this entry. Plain links are also accepted,
+     * since that is what was saved before there were identity strategies
+     */
+    pub fn is_in(&self, ids: &[&str], identity: Identity) -> bool {
+        ids.contains(&self.id(identity).as_str()) || ids.contains(&self.link.as_str())
+    }
 }
 
 impl Display for M3u8 {
@@ -24,6 +107,9 @@ impl Display for M3u8 {
             self.name.bold()
         };
         f.write_fmt(format_args!("{} ({})", colored_name, self.link))?;
+        if let Some(now_and_next) = self.describe_now_and_next() {
+            f.write_fmt(format_args!(" {}", now_and_next.italic()))?;
+        }
         Ok(())
     }
 }
@@ -32,11 +118,21 @@ impl Display for M3u8 {
 pub struct OfflineEntry {
     m3u8: M3u8,
     pub path: Rc<String>,
+    /// Size and sha256 of the file when the download completed
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 
 impl OfflineEntry {
     pub fn new(m3u8: M3u8, path: Rc<String>) -> Self {
-        Self { m3u8, path }
+        Self {
+            m3u8,
+            path,
+            size: None,
+            sha256: None,
+        }
     }
 }
 
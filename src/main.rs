@@ -1,23 +1,55 @@
-use std::num::ParseIntError;
-use std::process::Command;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
 use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use colored::Colorize;
 use structopt::StructOpt;
 
+use indicatif::HumanBytes;
+
 use ilovetv::{
-    download_with_progress, get_gm, get_mut_ref, Configuration, M3u8, OfflineEntry, Opt, Readline,
-    WatchedFind,
+    check_entry, download_with_progress, file_ending, format_duration, get_gm, get_mut_ref,
+    local_time, parse_duration, read_diff_history, remote_content_length, run_tui, select,
+    BackgroundRefresher, ChangedLink, Configuration, DiffEntry, EntryStatus, EvictionReport,
+    FileDeletion, GrandMother, HistoryCommand, HistoryEntry, JobStatus, Listing, M3u8,
+    OfflineCommand, OfflineEntry, OfflineLibrary, Opt, Programme, Readline, RecordCommand,
+    RecordingJob, Schedule, SortKey, SubCommand, Tally, UserState, WatchHistory, WatchedFind,
 };
 #[allow(unused_imports)]
 use ilovetv::{GetM3u8, GetPlayPath, OfflineParser};
 
+/// What mpv is told to print when it starts an entry of a playlist, before its position
+const PLAYLIST_POSITION_PREFIX: &str = "ilovetv-playlist-pos ";
+
 #[tokio::main]
 async fn main() {
     let opt = Opt::from_args();
 
+    if let Some(command) = opt.command {
+        let config = match Configuration::new() {
+            Ok(config) => Rc::new(config),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        // Commands such as catchup load the playlists of the profile
+        if let Some(profile) = opt.profile.as_ref() {
+            if let Err(e) = config.set_active_profile_ugly(profile, false) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        run_command(command, config).await;
+        return;
+    }
+
     // Greet the user
-    [
+    let greeting = [
         format!(
             "Welcome to {}, a {} iptv client written in rust\n",
             "ilovetv".bold(),
@@ -34,32 +66,85 @@ async fn main() {
         format!(" {} is to switch between modes (toggle)", "m".bold()),
         format!(" {} is to perform a new search", "s".bold()),
         format!(" {} is to select all", "a".bold()),
+        format!(
+            " {}/{} is to go to the next/previous page of results, and {} to jump to a page",
+            ">".bold(),
+            "<".bold(),
+            "g <page>".bold()
+        ),
+        format!(
+            " {} is to sort the results by name, group, number (the position in the playlists), watched or recent",
+            "sort <key>".bold()
+        ),
         format!(" {} is to toggtle fullscreen for mpv", "f".bold()),
         format!(
             " {} is to redo the last search (mainly for use in the last session)",
             "l".bold()
         ),
         format!(" {} is to clean the latest search", "c".bold()),
-    ]
-    .iter()
-    .for_each(|s| println!("{}", &s));
+        format!(" {} is to switch between profiles", "p".bold()),
+        format!(
+            " {} is to browse the channels that were new in the last refresh",
+            "n".bold()
+        ),
+        format!(" {} is to replay something watched recently", "h".bold()),
+        format!(
+            " {} is to search the programmes in the EPG, such as to find a match tonight",
+            "e".bold()
+        ),
+    ];
+    if !opt.tui {
+        greeting.iter().for_each(|s| println!("{}", &s));
+    }
 
     let mut mpv_fs = false;
-    let mut search_result: Option<Rc<Vec<&M3u8>>> = None;
+    let mut search_result: Option<Listing> = None;
+    let config = match Configuration::new() {
+        Ok(config) => Rc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(profile) = opt.profile.as_ref() {
+        if let Err(e) = config.set_active_profile_ugly(profile, false) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
     let mut readline = Readline::new();
-    let (gm, mut in_online) = get_gm(
-        opt.mode,
-        &mut readline,
-        Rc::new(Configuration::new().expect("Failed to write to configfile")),
-    )
-    .await
-    .expect("Failed to retrive online playlist");
+    let (gm, mut in_online) = get_gm(opt.mode, &mut readline, config)
+        .await
+        .expect("Failed to retrive online playlist");
 
+    if opt.api {
+        start_api_in_background(opt.profile.clone());
+    }
+    if opt.tui {
+        if let Err(e) = run_tui(gm, in_online).await {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mut background_refresher = BackgroundRefresher::new();
     loop {
         // Dont't perform a search if user has just watched, instead present the previous search
         if search_result.is_none() {
+            // Nothing borrows from the parser now, so a refreshed playlist can be swapped in
+            let refreshed = background_refresher.poll(unsafe { get_mut_ref(&gm) }).await;
+            if !refreshed.is_empty() {
+                let refreshed: Vec<&str> = refreshed.iter().map(|x| x.as_str()).collect();
+                println!(
+                    "Refreshed {} in the background",
+                    refreshed.join(", ").bold()
+                );
+                print_refresh_summary(&gm);
+            }
+
             let search = readline
-                .input("Search by name [ r/q/f/l/m ]: ")
+                .input("Search by name [ r/q/f/l/m/p/n/h/e ]: ")
                 .to_lowercase();
             let mut search = search.trim();
 
@@ -70,11 +155,12 @@ async fn main() {
                 // Refresh playlist
                 "r" => {
                     search_result = None;
-                    if let Err(e) = gm.refresh_dirty().await {
-                        println!(
+                    match gm.refresh_dirty().await {
+                        Ok(()) => print_refresh_summary(&gm),
+                        Err(e) => println!(
                             "Cannot refresh. This is probably due to offlinemode {:?}",
                             e
-                        );
+                        ),
                     };
                     continue;
                 }
@@ -99,6 +185,33 @@ async fn main() {
                     gm.config.update_last_search_ugly(None);
                     continue;
                 }
+                // Browse what the last refresh added
+                "n" => {
+                    let new_entries = gm.new_since_refresh();
+                    if new_entries.is_empty() {
+                        println!("No new channels since the last refresh");
+                        continue;
+                    }
+                    search_result = Some(listing(&gm, new_entries, None));
+                }
+                // Replay something that was watched recently
+                "h" => {
+                    let recent = gm.recently_watched(20);
+                    if recent.is_empty() {
+                        println!("Nothing in the current playlist has been watched yet");
+                        continue;
+                    }
+                    search_result = Some(listing(&gm, recent, Some(SortKey::Recent)));
+                }
+                // Search the programmes of the EPG
+                "e" => {
+                    if gm.epg.is_empty() {
+                        println!("There is no EPG for the playlists in use");
+                    } else {
+                        search_epg(&gm, &mut readline, mpv_fs);
+                    }
+                    continue;
+                }
                 "m" => {
                     if in_online {
                         unsafe { get_mut_ref(&gm) }.demote_to_offline();
@@ -107,17 +220,49 @@ async fn main() {
                         let result = unsafe { get_mut_ref(&gm) }.promote_to_online().await;
                         if let Err(e) = result {
                             println!("Failed to switch to onlinemode {:?}", e);
+                            continue;
                         } else {
                             println!("Switched to online mode");
-                            continue;
                         }
                     }
                     in_online = !in_online;
                     continue;
                 }
+                "p" => {
+                    let profiles = gm.config.profile_names();
+                    for (idx, profile) in profiles.iter().enumerate() {
+                        let active = if *profile == gm.config.active_profile {
+                            " (active)"
+                        } else {
+                            ""
+                        };
+                        println!("  {}: {}{}", idx + 1, profile.bold(), active);
+                    }
+                    let choice = readline.input("Which profile? ").trim().parse::<usize>();
+                    let profile = match choice.ok().and_then(|x| profiles.get(x.wrapping_sub(1))) {
+                        Some(profile) => profile,
+                        None => {
+                            println!("There is no such profile");
+                            continue;
+                        }
+                    };
+                    match unsafe { get_mut_ref(&gm) }
+                        .switch_profile(profile, true)
+                        .await
+                    {
+                        Ok(()) => {
+                            in_online = true;
+                            println!("Switched to the profile {}", profile.bold());
+                        }
+                        Err(e) => println!("Failed to switch profile, {}", e),
+                    }
+                    continue;
+                }
                 _ => {}
             }
-            search_result = Some(Rc::new(gm.parser.find(search)));
+            if search_result.is_none() {
+                search_result = Some(listing(&gm, gm.parser.find(search), None));
+            }
 
             if search_result.as_ref().unwrap().is_empty() {
                 println!("Nothing found");
@@ -128,15 +273,37 @@ async fn main() {
         }
 
         // Let them choose which one to stream
-        for (idx, m3u8_item) in search_result.as_ref().unwrap().iter().enumerate().rev() {
-            println!("  {}: {}", idx + 1, m3u8_item);
-        }
+        print_listing(&gm, search_result.as_ref().unwrap());
 
         let user_wish = readline
-            .input("Which one do you wish to stream? [ q/f/s/r/d/o/m ]: ")
+            .input("Which one do you wish to stream? [ q/f/s/r/d/o/m/>/</g/sort ]: ")
             .to_lowercase();
         let user_wish = user_wish.trim();
 
+        // Paging and sorting, which keep the results
+        let listing = search_result.as_mut().unwrap();
+        let paged = match user_wish.split_once(' ') {
+            _ if user_wish == ">" => Some(listing.next_page()),
+            _ if user_wish == "<" => Some(listing.previous_page()),
+            Some(("g", page)) => Some(match page.trim().parse::<usize>() {
+                Ok(page) => listing.go_to_page(page),
+                Err(_) => Err(format!("'{}' isn't a page number", page.trim())),
+            }),
+            Some(("sort", key)) => Some(key.trim().parse::<SortKey>().map(|key| {
+                listing.sort(&gm, key);
+            })),
+            _ if user_wish == "sort" => {
+                Some(Err(format!("Sort by what? Such as {}", "sort name".bold())))
+            }
+            _ => None,
+        };
+        if let Some(paged) = paged {
+            if let Err(e) = paged {
+                println!("{}", e);
+            }
+            continue;
+        }
+
         // If they want to quit, let them-
         match user_wish {
             // Quit
@@ -150,11 +317,12 @@ async fn main() {
             "r" => {
                 println!("Refreshing local m3u8-file");
                 search_result = None;
-                if let Err(e) = gm.refresh_dirty().await {
-                    println!(
+                match gm.refresh_dirty().await {
+                    Ok(()) => print_refresh_summary(&gm),
+                    Err(e) => println!(
                         "Cannot refresh. This is probably due to offlinemode {:?}",
                         e
-                    );
+                    ),
                 };
                 continue;
             }
@@ -169,7 +337,7 @@ async fn main() {
             // Downloadmode
             "d" => {
                 let download_selections =
-                    ask_which_to_download(&mut readline, &search_result.as_ref().unwrap());
+                    ask_which_to_download(&mut readline, search_result.as_ref().unwrap().entries());
 
                 for to_download in download_selections.iter() {
                     download_m3u8(to_download, None).await;
@@ -179,36 +347,36 @@ async fn main() {
             // Save to offlinemode
             "o" => {
                 let download_selections =
-                    ask_which_to_download(&mut readline, &search_result.as_ref().unwrap());
+                    ask_which_to_download(&mut readline, search_result.as_ref().unwrap().entries());
+
+                // The api may have changed the offline catalog since it was read.
+                // The results borrow from it in offline mode, so it can't be read again then
+                if in_online {
+                    gm.config.reload_offlinefile_ugly();
+                }
+                if !make_room_for(&gm, &download_selections).await {
+                    continue;
+                }
 
                 for to_download in download_selections.iter() {
-                    let file_ending = to_download
-                        .name
-                        .rfind(".")
-                        .map(|dot_idx| {
-                            if dot_idx < 6 {
-                                &to_download.name[dot_idx..]
-                            } else {
-                                ".mkv"
-                            }
-                        })
-                        .unwrap_or_else(|| ".mkv");
-
-                    let path = gm
-                        .config
-                        .data_dir
-                        .join(format!("{}{}", &to_download.name, file_ending));
+                    let path = gm.config.data_dir.join(format!(
+                        "{}{}",
+                        &to_download.name,
+                        file_ending(&to_download.link)
+                    ));
                     let path = Rc::new(path.to_string_lossy().to_string());
-                    download_m3u8(to_download, Some(&path)).await;
+                    let completed = download_m3u8(to_download, Some(&path)).await;
                     let data_entry = OfflineEntry::new((*to_download).clone(), path);
-                    gm.config.push_offlinefile_ugly(data_entry);
-                }
-
-                if let Err(e) = gm.config.write_datafile() {
-                    println!(
-                        "Failed to information about downloaded entries for offline use {:?}",
-                        e
-                    )
+                    if in_online {
+                        gm.config.reload_offlinefile_ugly();
+                    }
+                    OfflineLibrary::new(gm.config.clone()).add_download(data_entry, completed);
+                    if let Err(e) = gm.config.write_datafile() {
+                        println!(
+                            "Failed to information about downloaded entries for offline use {:?}",
+                            e
+                        )
+                    }
                 }
                 continue;
             }
@@ -222,85 +390,246 @@ async fn main() {
             _ => {}
         }
 
-        let choosen = user_wish.parse::<usize>();
-        match choosen {
-            Ok(k) => {
-                let search_result = search_result.as_ref().unwrap();
-                let to_play = search_result[k - 1];
-                let path_link = if let Ok(link) = gm.parser.get_path_to_play(to_play.link.clone()) {
-                    link
-                } else {
-                    println!("Not possible to refresh playlist while in offlinemode");
-                    continue;
-                };
-                stream(to_play, &*path_link, mpv_fs);
-                gm.save_watched();
+        // Several entries are played as a playlist
+        let to_play = match select(search_result.as_ref().unwrap().entries(), user_wish) {
+            Ok(to_play) => to_play,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        if let [to_play] = to_play[..] {
+            let path_link = if let Ok(link) = gm.parser.get_path_to_play(to_play) {
+                link
+            } else {
+                println!("Not possible to refresh playlist while in offlinemode");
+                continue;
+            };
+            if !in_online && !check_before_playback(&gm, &mut readline, to_play).await {
+                continue;
+            }
+            let started = SystemTime::now();
+            stream(to_play, &*path_link, mpv_fs);
+            gm.save_watched();
+            gm.record_watch(to_play, started);
+        } else {
+            let mut playlist = Vec::new();
+            for m3u8 in to_play {
+                match gm.parser.get_path_to_play(m3u8) {
+                    Ok(link) => {
+                        if in_online || check_before_playback(&gm, &mut readline, m3u8).await {
+                            playlist.push((m3u8, link));
+                        }
+                    }
+                    Err(e) => println!("Leaving out {}, {}", m3u8.name.bold(), e),
+                }
             }
-            Err(e) => println!("Have to be a valid number! {:?}", e),
+            if playlist.is_empty() {
+                continue;
+            }
+            println!("Playing {} entries as a playlist", playlist.len());
+            stream_playlist(&gm, &playlist, mpv_fs);
+            gm.save_watched();
         }
     }
 }
 
 fn ask_which_to_download<'a>(
     readline: &mut Readline,
-    search_result: &Rc<Vec<&'a M3u8>>,
+    search_result: &[&'a M3u8],
 ) -> Rc<Vec<&'a M3u8>> {
-    let selections = loop {
+    loop {
         // Ask for userinput
         let selection = readline
-            .input("Download all or select in comma separated [a | 1,2,3,4]: ")
+            .input("Download all or select [a | 1,2,3 | 1-10 | a,!3 | unwatched | group=news]: ")
             .to_lowercase();
-        let selection = selection.trim();
 
-        // Download all
-        if selection == "a" {
-            println!("Downloading all");
-            return search_result.clone();
+        // Ask again until the selection makes sense
+        match select(search_result, selection.trim()) {
+            Ok(selections) => {
+                if selections.len() == search_result.len() {
+                    println!("Downloading all");
+                }
+                return Rc::new(selections);
+            }
+            Err(e) => println!("{}", e),
         }
+    }
+}
 
-        // Convert to numbers
-        let selections = selection
-            .split(",")
-            .map(|x| x.trim().parse::<usize>())
-            .collect::<Vec<Result<usize, ParseIntError>>>();
+/**
+ * Search results sorted as configured, unless `sort` says otherwise
+ */
+fn listing<'a>(gm: &GrandMother, found: Vec<&'a M3u8>, sort: Option<SortKey>) -> Listing<'a> {
+    let prompt = &gm.config.prompt;
+    let sort = sort.or(prompt.sort).unwrap_or_default();
+    Listing::new(gm, found, sort, prompt.page_size())
+}
 
-        // Ask again if any number wasn't a valid number
-        let wrong_input = selections.iter().any(|x| x.is_err());
-        if wrong_input {
-            println!("Invalid input. Has to be either {}, a number or a sequence of numbers separated by commas","a".bold());
-            continue;
+/**
+ * The current page of the results, the first one last so that it's next to the prompt
+ */
+fn print_listing(gm: &GrandMother, listing: &Listing) {
+    let show_source = gm.playlists.len() > 1;
+    for (number, m3u8_item) in listing.page_entries().iter().rev() {
+        match m3u8_item.source.as_ref().filter(|_| show_source) {
+            Some(source) => println!("  {}: [{}] {}", number, source.dimmed(), m3u8_item),
+            None => println!("  {}: {}", number, m3u8_item),
         }
+    }
+    if listing.page_count() > 1 {
+        println!(
+            "Page {} of {}, {} results sorted by {}. {}/{} for the next/previous page, {} to jump",
+            listing.page(),
+            listing.page_count(),
+            listing.len(),
+            listing.sorted_by().name(),
+            ">".bold(),
+            "<".bold(),
+            "g <page>".bold()
+        );
+    }
+}
 
-        break selections;
-    };
+/**
+ * Evict old entries if the offline quota would be exceeded by the new downloads.
+ * Returns false if there still isn't room for them
+ */
+async fn make_room_for(gm: &GrandMother, to_download: &[&M3u8]) -> bool {
+    let library = OfflineLibrary::new(gm.config.clone());
+    let mut incoming = 0;
+    if let Ok(Some(_)) = library.quota() {
+        for m3u8 in to_download.iter() {
+            incoming += remote_content_length(&m3u8.link).await.unwrap_or_default();
+        }
+    }
 
-    Rc::new(
-        selections
-            .into_iter()
-            .map(|x| x.unwrap() - 1) // Since all numbers are valid, remap them
-            .map(|x| search_result[x]) // We don't want the numbers, but the &M3u8 in those positions
-            .collect(),
-    )
+    let watched_ids = gm.parser.get_watched_ids(gm.config.identity);
+    let watched_ids: Vec<&str> = watched_ids.iter().map(String::as_str).collect();
+    match library.enforce_quota(incoming, &watched_ids) {
+        Ok(Some(report)) => {
+            print_eviction_report(&report);
+            if !report.fits(incoming) {
+                println!(
+                    "Not enough room for {} within the offline quota of {}, not downloading",
+                    HumanBytes(incoming),
+                    HumanBytes(report.quota)
+                );
+                return false;
+            }
+            true
+        }
+        Ok(None) => true,
+        Err(e) => {
+            println!("Could not enforce the offline quota, {}", e);
+            true
+        }
+    }
 }
 
-async fn download_m3u8(file_to_download: &M3u8, path: Option<&str>) {
-    let file_ending_place = file_to_download.link.rfind(".").unwrap();
-    let potential_file_ending = &file_to_download.link[file_ending_place..];
-    let file_ending = if potential_file_ending.len() > 6 {
-        ".mkv"
-    } else {
-        potential_file_ending
-    };
-    let file_name = format!("{}{}", file_to_download.name, file_ending);
+fn print_eviction_report(report: &EvictionReport) {
+    for (entry, size) in report.evicted.iter() {
+        println!(
+            "Evicted {} [{}] ({})",
+            entry.name.bold(),
+            HumanBytes(*size),
+            entry.path
+        );
+    }
+    if !report.evicted.is_empty() {
+        println!(
+            "Freed {}, the offline store went from {} to {}",
+            HumanBytes(report.usage_before - report.usage_after)
+                .to_string()
+                .bold(),
+            HumanBytes(report.usage_before),
+            HumanBytes(report.usage_after)
+        );
+    }
+}
+
+/**
+ * Download the entry to the given path, or to the current directory if there is none.
+ * The path is used as is, so it should already have a file ending
+ */
+async fn download_m3u8(file_to_download: &M3u8, path: Option<&str>) -> bool {
+    let file_name = format!(
+        "{}{}",
+        file_to_download.name,
+        file_ending(&file_to_download.link)
+    );
     println!("Downloading {}", &file_name);
-    let path = if let Some(path) = path {
-        format!("{}{}", path, file_ending)
-    } else {
-        file_name.clone()
-    };
+    let path = path.map(str::to_owned).unwrap_or_else(|| file_name.clone());
 
     if let Err(e) = download_with_progress(&file_to_download.link, Some(&path)).await {
         eprintln!("Failed to download {}, {:?}", &file_name, e);
+        return false;
+    }
+    true
+}
+
+fn describe_status(status: &EntryStatus) -> Option<String> {
+    Some(match status {
+        EntryStatus::Ok(_) => return None,
+        EntryStatus::Missing => "missing".red().to_string(),
+        EntryStatus::Corrupt => "checksum mismatch".red().to_string(),
+        EntryStatus::Truncated {
+            size,
+            expected: Some(expected),
+        } => format!(
+            "truncated, {} of {}",
+            HumanBytes(*size),
+            HumanBytes(*expected)
+        )
+        .yellow()
+        .to_string(),
+        EntryStatus::Truncated { size, .. } => format!("truncated, {}", HumanBytes(*size))
+            .yellow()
+            .to_string(),
+    })
+}
+
+async fn is_available_online(entry: &OfflineEntry) -> bool {
+    entry.link.starts_with("http") && remote_content_length(&entry.link).await.is_some()
+}
+
+/**
+ * Make sure that the offline file of the entry is intact before playing it.
+ * Offers to download it again if it isn't, returns false if it shouldn't be played
+ */
+async fn check_before_playback(gm: &GrandMother, readline: &mut Readline, m3u8: &M3u8) -> bool {
+    let library = OfflineLibrary::new(gm.config.clone());
+    let idx = match library.position(m3u8) {
+        Some(idx) => idx,
+        None => return true,
+    };
+    let entry = &library.entries()[idx];
+    let status = match describe_status(&check_entry(entry)) {
+        Some(status) => status,
+        None => return true,
+    };
+
+    println!("The offline file of {} is {}", entry.name.bold(), status);
+    if !is_available_online(entry).await {
+        return readline
+            .input("It's no longer available online, play anyway? (y/N) ")
+            .trim()
+            .eq_ignore_ascii_case("y");
+    }
+    if readline
+        .input("Download it again? (Y/n) ")
+        .trim()
+        .eq_ignore_ascii_case("n")
+    {
+        return true;
+    }
+
+    match library.redownload(idx).await {
+        Ok(()) => true,
+        Err(e) => {
+            println!("{}", e);
+            false
+        }
     }
 }
 
@@ -322,3 +651,886 @@ fn stream(m3u8item: &M3u8, link: &String, launch_in_fullscreen: bool) {
         .output()
         .expect("Could not listen for output");
 }
+
+/**
+ * Play entries one after another in the same mpv. mpv says when it moves on to the
+ * next one, so that every entry gets its own time in the watch history
+ */
+fn stream_playlist(gm: &GrandMother, playlist: &[(&M3u8, Rc<String>)], launch_in_fullscreen: bool) {
+    let mut command = Command::new("mpv");
+    command
+        .args(playlist.iter().map(|(_, link)| link.as_str()))
+        .arg(format!(
+            "--term-playing-msg={}${{playlist-pos}}",
+            PLAYLIST_POSITION_PREFIX
+        ))
+        .stdout(Stdio::piped());
+    if launch_in_fullscreen {
+        command.arg("--fs");
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            println!("Failed to start mpv, {}", e);
+            return;
+        }
+    };
+
+    let mut playing: Option<(&M3u8, SystemTime)> = None;
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let position = line
+                .strip_prefix(PLAYLIST_POSITION_PREFIX)
+                .and_then(|x| x.trim().parse::<usize>().ok());
+            let m3u8 = match position.and_then(|x| playlist.get(x)) {
+                Some((m3u8, _)) => *m3u8,
+                None => continue,
+            };
+            if let Some((previous, started)) = playing.take() {
+                gm.record_watch(previous, started);
+            }
+            unsafe { get_mut_ref(m3u8) }.watched = true;
+            playing = Some((m3u8, SystemTime::now()));
+        }
+    }
+    let _ = child.wait();
+    if let Some((m3u8, started)) = playing {
+        gm.record_watch(m3u8, started);
+    }
+}
+
+/**
+ * Search the titles and descriptions of upcoming programmes, and play the channel of one
+ */
+fn search_epg(gm: &GrandMother, readline: &mut Readline, mpv_fs: bool) {
+    let query = readline.input("Search programmes: ");
+    let query = query.trim();
+    if query.is_empty() {
+        return;
+    }
+    let found = gm.search_epg(query);
+    if found.is_empty() {
+        println!("No upcoming programmes match '{}'", query);
+        return;
+    }
+
+    for (idx, (programme, m3u8)) in found.iter().enumerate().rev() {
+        let day = if programme.is_airing_now() {
+            "now".green().to_string()
+        } else {
+            local_time(programme.start, "%a %d %b")
+        };
+        println!(
+            "  {}: {} {}-{} {} on {}",
+            idx + 1,
+            day,
+            programme.local_start(),
+            programme.local_stop(),
+            programme.title.bold(),
+            m3u8.name
+        );
+        if !programme.desc.is_empty() {
+            println!("      {}", programme.desc.dimmed());
+        }
+    }
+
+    let choice = readline
+        .input("Which channel do you wish to play, or record with r? [ number/r number/q ]: ");
+    let choice = choice.trim();
+    let (to_record, choice) = match choice.strip_prefix('r') {
+        Some(number) => (true, number.trim()),
+        None => (false, choice),
+    };
+    let (programme, m3u8) = match choice.parse::<usize>() {
+        Ok(k) if (1..=found.len()).contains(&k) => found[k - 1],
+        _ => return,
+    };
+    if to_record {
+        schedule_programme(gm, programme, m3u8);
+        return;
+    }
+    if !programme.is_airing_now() {
+        println!(
+            "{} starts at {}, playing {} now",
+            programme.title.bold(),
+            programme.local_start(),
+            m3u8.name.bold()
+        );
+    }
+
+    let started = SystemTime::now();
+    stream(m3u8, &m3u8.link, mpv_fs);
+    gm.save_watched();
+    gm.record_watch(m3u8, started);
+}
+
+fn schedule_programme(gm: &GrandMother, programme: &Programme, m3u8: &M3u8) {
+    let mut job = RecordingJob::from_programme(m3u8, programme);
+    job.headers = gm.headers_of(m3u8);
+    add_to_schedule(&gm.config, job);
+}
+
+fn add_to_schedule(config: &Configuration, job: RecordingJob) {
+    let mut schedule = match Schedule::new(&config.recordings_path) {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    match schedule.add(job) {
+        Ok(job) => println!(
+            "Scheduled {} on {} {}, it's recorded while {} runs",
+            job.title.bold(),
+            job.channel,
+            job.describe_time(),
+            "ilovetv record daemon".bold()
+        ),
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn print_refresh_summary(gm: &GrandMother) {
+    match gm.last_diff.as_ref() {
+        Some(diff) if diff.is_empty() => println!("The playlist is unchanged"),
+        Some(diff) => println!(
+            "{}, browse the new ones with {}",
+            diff.summary().bold(),
+            "n".bold()
+        ),
+        None => {}
+    }
+}
+
+async fn run_command(command: SubCommand, config: Rc<Configuration>) {
+    match command {
+        SubCommand::Changes { limit, search } => print_changes(&config, limit, search),
+        SubCommand::History { limit, command } => match command {
+            None => print_history(&config, limit),
+            Some(command) => run_history_command(command, &config),
+        },
+        SubCommand::Stats { limit } => print_stats(&config, limit),
+        SubCommand::Export {
+            file,
+            include_secrets,
+        } => export_state(&config, file, include_secrets),
+        SubCommand::Import { file } => import_state(&config, file),
+        SubCommand::Offline(offline_command) => run_offline_command(offline_command, config).await,
+        SubCommand::Catchup {
+            channel,
+            ago,
+            duration,
+        } => play_catchup(config, &channel, ago, &duration).await,
+        SubCommand::Serve { address } => serve(config, address).await,
+        SubCommand::Api { address } => api(config, address).await,
+        SubCommand::Record(record_command) => run_record_command(record_command, config).await,
+    }
+}
+
+async fn run_record_command(command: RecordCommand, config: Rc<Configuration>) {
+    match command {
+        RecordCommand::Add {
+            channel,
+            from,
+            to,
+            title,
+        } => add_recording(config, &channel, &from, &to, title).await,
+        RecordCommand::List => print_recordings(&config),
+        RecordCommand::Remove { numbers } => {
+            if numbers.contains(&0) {
+                println!("The recordings are numbered from 1");
+                return;
+            }
+            let indexes: Vec<usize> = numbers.iter().map(|x| x - 1).collect();
+            match Schedule::new(&config.recordings_path)
+                .and_then(|mut schedule| schedule.remove(&indexes))
+            {
+                Ok(removed) => removed.iter().for_each(|job| match job.status {
+                    JobStatus::Recording => println!(
+                        "Removed {}, the daemon stops recording it",
+                        job.title.bold()
+                    ),
+                    _ => println!("Removed {}", job.title.bold()),
+                }),
+                Err(e) => println!("{}", e),
+            }
+        }
+        RecordCommand::Daemon => ilovetv::run_daemon(config).await,
+    }
+}
+
+/**
+ * Schedule a recording of a channel, named after what the EPG says is on when it starts
+ */
+async fn add_recording(
+    config: Rc<Configuration>,
+    channel: &str,
+    from: &str,
+    to: &str,
+    title: Option<String>,
+) {
+    let (start, stop) = match ilovetv::recording_window(from, to) {
+        Ok(window) => window,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let gm = match GrandMother::new_online(config.clone()).await {
+        Ok(gm) => gm,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let m3u8 = match find_channel(&gm, channel) {
+        Some(m3u8) => m3u8,
+        None => return,
+    };
+
+    let title = title
+        .or_else(|| {
+            m3u8.programmes
+                .as_ref()?
+                .iter()
+                .find(|programme| programme.is_airing(start))
+                .map(|programme| programme.title.clone())
+        })
+        .unwrap_or_else(|| m3u8.name.clone());
+    let mut job = RecordingJob::new(m3u8, title, start, stop);
+    job.headers = gm.headers_of(m3u8);
+    add_to_schedule(&config, job);
+}
+
+/**
+ * The channel with exactly that name, or the only one with a name containing it
+ */
+fn find_channel<'a>(gm: &'a GrandMother, channel: &str) -> Option<&'a M3u8> {
+    let exact = gm
+        .parser
+        .get_m3u8()
+        .into_iter()
+        .find(|m3u8| m3u8.name.to_lowercase() == channel.to_lowercase());
+    if exact.is_some() {
+        return exact;
+    }
+    match gm.parser.find(channel).as_slice() {
+        [m3u8] => Some(*m3u8),
+        [] => {
+            println!("There is no channel called '{}'", channel);
+            None
+        }
+        found => {
+            println!("'{}' matches several channels, be more specific:", channel);
+            found.iter().for_each(|m3u8| println!("  {}", m3u8.name));
+            None
+        }
+    }
+}
+
+/**
+ * Play from the archive of a channel, either a past programme in the EPG
+ * or a window that started some time ago
+ */
+async fn play_catchup(
+    config: Rc<Configuration>,
+    channel: &str,
+    ago: Option<String>,
+    duration: &str,
+) {
+    let gm = match GrandMother::new_online(config).await {
+        Ok(gm) => gm,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let m3u8 = match find_channel(&gm, channel) {
+        Some(m3u8) => m3u8,
+        None => return,
+    };
+    let catchup = match m3u8.catchup.as_ref() {
+        Some(catchup) => catchup,
+        None => {
+            println!("{} has no archive in the playlist", m3u8.name.bold());
+            return;
+        }
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or_default();
+    let (start, stop) = match ago {
+        Some(ago) => match (parse_duration(&ago), parse_duration(duration)) {
+            (Ok(ago), Ok(duration)) => {
+                let start = now - ago.as_secs() as i64;
+                (start, start + duration.as_secs() as i64)
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                println!("{}", e);
+                return;
+            }
+        },
+        None => match ask_for_past_programme(m3u8) {
+            Some(programme) => (programme.start, programme.stop),
+            None => return,
+        },
+    };
+    if !catchup.covers(start) {
+        println!(
+            "The archive of {} doesn't go back to {}",
+            m3u8.name.bold(),
+            local_time(start, "%a %d %b %H:%M")
+        );
+        return;
+    }
+
+    let link = match catchup.url(&m3u8.link, start, stop) {
+        Ok(link) => link,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let started = SystemTime::now();
+    stream(m3u8, &link, false);
+    gm.record_watch(m3u8, started);
+}
+
+/**
+ * List the programmes of the channel that are in its archive, and let the user pick one
+ */
+fn ask_for_past_programme(m3u8: &M3u8) -> Option<&Programme> {
+    let catchup = m3u8.catchup.as_ref()?;
+    // Newest first, since that usually is what's wanted
+    let past: Vec<&Programme> = m3u8
+        .programmes
+        .iter()
+        .flat_map(|programmes| programmes.iter().rev())
+        .filter(|programme| catchup.covers(programme.start))
+        .collect();
+    if past.is_empty() {
+        println!(
+            "There is no EPG for the archive of {}, use --ago",
+            m3u8.name.bold()
+        );
+        return None;
+    }
+
+    // Printed oldest first, so that the newest is closest to the prompt
+    for (idx, programme) in past.iter().enumerate().rev() {
+        println!(
+            "  {}: {} {}-{} {}",
+            idx + 1,
+            local_time(programme.start, "%a %d %b"),
+            programme.local_start(),
+            programme.local_stop(),
+            programme.title.bold()
+        );
+    }
+    let choice = Readline::new().input("Which programme do you wish to play? [ number/q ]: ");
+    match choice.trim().parse::<usize>() {
+        Ok(k) if (1..=past.len()).contains(&k) => Some(past[k - 1]),
+        _ => None,
+    }
+}
+
+async fn serve(config: Rc<Configuration>, address: Option<String>) {
+    let address = address.unwrap_or_else(|| config.serve.address().to_owned());
+    let gm = match GrandMother::new_online(config).await {
+        Ok(gm) => gm,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = ilovetv::serve(gm, &address).await {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+async fn api(config: Rc<Configuration>, address: Option<String>) {
+    let address = address.unwrap_or_else(|| config.api.address().to_owned());
+    let gm = match GrandMother::new_online(config).await {
+        Ok(gm) => gm,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = ilovetv::run_api(gm, &address).await {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/**
+ * Run the api next to the prompt. It gets a thread and playlists of its own,
+ * since the prompt blocks while waiting for input
+ */
+fn start_api_in_background(profile: Option<String>) {
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                println!("Failed to start the api, {:?}", e);
+                return;
+            }
+        };
+        runtime.block_on(async move {
+            let config = match Configuration::new() {
+                Ok(config) => Rc::new(config),
+                Err(e) => {
+                    println!("Failed to start the api, {}", e);
+                    return;
+                }
+            };
+            if let Some(profile) = profile.as_ref() {
+                if let Err(e) = config.set_active_profile_ugly(profile, false) {
+                    println!("Failed to start the api, {}", e);
+                    return;
+                }
+            }
+            let address = config.api.address().to_owned();
+            let result = match GrandMother::new_online(config).await {
+                Ok(gm) => ilovetv::run_api(gm, &address).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                println!("The api stopped, {}", e);
+            }
+        });
+    });
+}
+
+fn print_recordings(config: &Configuration) {
+    let schedule = match Schedule::new(&config.recordings_path) {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    if schedule.jobs().is_empty() {
+        println!("Nothing is scheduled to be recorded");
+        return;
+    }
+    for (idx, job) in schedule.jobs().iter().enumerate() {
+        let status = match &job.status {
+            JobStatus::Scheduled => "scheduled".to_owned(),
+            JobStatus::Recording => "recording".green().to_string(),
+            JobStatus::Done { path, size } => format!("{}, {}", path, HumanBytes(*size)),
+            JobStatus::Failed { error } => error.red().to_string(),
+        };
+        println!(
+            "  {}: {} {} on {} ({})",
+            idx + 1,
+            job.describe_time(),
+            job.title.bold(),
+            job.channel,
+            status
+        );
+    }
+}
+
+async fn run_offline_command(command: OfflineCommand, config: Rc<Configuration>) {
+    let library = OfflineLibrary::new(config.clone());
+    match command {
+        OfflineCommand::List => {
+            for (idx, (entry, size)) in library.list().iter().enumerate() {
+                let size = size
+                    .map(|size| HumanBytes(size).to_string())
+                    .unwrap_or_else(|| "missing".red().to_string());
+                println!(
+                    "  {}: {} [{}] ({})",
+                    idx + 1,
+                    entry.name.bold(),
+                    size,
+                    entry.path
+                );
+            }
+            print_disk_usage(&library);
+        }
+        OfflineCommand::Remove {
+            numbers,
+            keep_files,
+            delete_files,
+        } => {
+            if numbers.contains(&0) {
+                println!("The entries are numbered from 1");
+                return;
+            }
+            let indexes: Vec<usize> = numbers.iter().map(|x| x - 1).collect();
+            let delete_files = if keep_files {
+                FileDeletion::Keep
+            } else if delete_files {
+                FileDeletion::All
+            } else {
+                FileDeletion::InDataDir
+            };
+            match library.remove(&indexes, delete_files) {
+                Ok(removed) => removed
+                    .iter()
+                    .for_each(|entry| println!("Removed {}", entry.name.bold())),
+                Err(e) => println!("{}", e),
+            }
+        }
+        OfflineCommand::Verify { repair } => {
+            let mut broken = Vec::new();
+            for (idx, (entry, status)) in library.verify().await.iter().enumerate() {
+                if let Some(status) = describe_status(status) {
+                    broken.push(idx);
+                    println!(
+                        "  {}: {} [{}] ({})",
+                        idx + 1,
+                        entry.name.bold(),
+                        status,
+                        entry.path
+                    );
+                }
+            }
+
+            if broken.is_empty() {
+                println!("All {} offline entries are intact", library.entries().len());
+            } else if repair {
+                for idx in broken {
+                    let entry = &library.entries()[idx];
+                    if !is_available_online(entry).await {
+                        println!("{} is no longer available online", entry.name.bold());
+                    } else if let Err(e) = library.redownload(idx).await {
+                        println!("{}", e);
+                    }
+                }
+            } else {
+                println!(
+                    "{} of {} offline entries are broken, download them again with {} or remove them with {}",
+                    broken.len(),
+                    library.entries().len(),
+                    "offline verify --repair".bold(),
+                    "offline remove".bold()
+                );
+            }
+        }
+        OfflineCommand::Import { dir } => {
+            // Use the playlist if it can be had, but importing is still useful without it
+            let gm = match GrandMother::new_online(config).await {
+                Ok(gm) => Some(gm),
+                Err(e) => {
+                    println!("Importing without matching against the playlist, {}", e);
+                    None
+                }
+            };
+            let playlist = gm
+                .as_ref()
+                .map(|gm| gm.parser.get_m3u8())
+                .unwrap_or_default();
+
+            match library.import(&dir, &playlist) {
+                Ok(imported) if imported.is_empty() => println!("No new media files found"),
+                Ok(imported) => {
+                    for (entry, matched) in imported.iter() {
+                        let matched = if *matched {
+                            "matched".green()
+                        } else {
+                            "unmatched".yellow()
+                        };
+                        println!(
+                            "Imported {} [{}] ({})",
+                            entry.name.bold(),
+                            matched,
+                            entry.path
+                        );
+                    }
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
+        OfflineCommand::Evict => match library.enforce_quota(0, &[]) {
+            Ok(Some(report)) => {
+                print_eviction_report(&report);
+                if report.evicted.is_empty() {
+                    println!("Nothing had to be evicted");
+                }
+                if !report.fits(0) {
+                    println!("Still over the quota, there are no more watched entries to evict");
+                }
+            }
+            Ok(None) => println!("There is no offline quota configured"),
+            Err(e) => println!("{}", e),
+        },
+        OfflineCommand::Rescan => match library.rescan() {
+            Ok(added) if added.is_empty() => println!("No new media files found"),
+            Ok(added) => added
+                .iter()
+                .for_each(|entry| println!("Added {} ({})", entry.name.bold(), entry.path)),
+            Err(e) => println!("{}", e),
+        },
+    }
+}
+
+fn print_disk_usage(library: &OfflineLibrary) {
+    let usage = library.disk_usage();
+    println!(
+        "{} in {} offline entries",
+        HumanBytes(usage.tracked).to_string().bold(),
+        library.entries().len()
+    );
+    match library.quota() {
+        Ok(Some(quota)) => println!(
+            "{} of the {} quota is used by the offline entries in the data directory",
+            HumanBytes(library.store_usage()),
+            HumanBytes(quota)
+        ),
+        Ok(None) => {}
+        Err(e) => println!("{}", e),
+    }
+    if usage.missing > 0 {
+        println!("{} entries are missing on disk", usage.missing);
+    }
+    if usage.untracked > 0 {
+        println!(
+            "{} in media files not in the catalog, add them with {}",
+            HumanBytes(usage.untracked),
+            "offline rescan".bold()
+        );
+    }
+}
+
+fn print_changes(config: &Configuration, limit: usize, search: Option<String>) {
+    let search = search.map(|x| x.to_lowercase());
+    let matches = |entry: &DiffEntry| {
+        search
+            .as_ref()
+            .is_none_or(|search| entry.name.to_lowercase().contains(search))
+    };
+
+    let history = read_diff_history(&config.diff_history_path);
+    let mut printed = 0;
+    for diff in history.iter().rev() {
+        let added: Vec<&DiffEntry> = diff.added.iter().filter(|x| matches(x)).collect();
+        let removed: Vec<&DiffEntry> = diff.removed.iter().filter(|x| matches(x)).collect();
+        let changed: Vec<&ChangedLink> =
+            diff.changed.iter().filter(|x| matches(&x.entry)).collect();
+        if search.is_some() && added.is_empty() && removed.is_empty() && changed.is_empty() {
+            continue;
+        }
+        if printed == limit {
+            break;
+        }
+        printed += 1;
+
+        println!("{}: {}", diff.local_time().bold(), diff.summary());
+        for entry in added {
+            println!("  {} {} [{}]", "+".green(), entry.name, entry.group_title);
+        }
+        for entry in removed {
+            println!("  {} {} [{}]", "-".red(), entry.name, entry.group_title);
+        }
+        for change in changed {
+            println!(
+                "  {} {} ({} -> {})",
+                "~".yellow(),
+                change.entry.name,
+                change.old_link,
+                change.entry.link
+            );
+        }
+    }
+
+    if printed == 0 {
+        println!("No changes to the playlists have been seen yet");
+    }
+}
+
+fn print_history(config: &Configuration, limit: usize) {
+    let history = WatchHistory::new(&config.watch_history_path);
+    let recent = history.recent(limit);
+    if recent.is_empty() {
+        println!("Nothing has been watched yet");
+        return;
+    }
+    for (idx, entry) in recent.iter().enumerate() {
+        println!(
+            "  {}: {} {} [{}] {} ({})",
+            idx + 1,
+            entry.local_time(),
+            entry.name.bold(),
+            entry.group_title,
+            format_duration(Duration::from_secs(entry.duration)),
+            entry.mode()
+        );
+    }
+}
+
+fn run_history_command(command: HistoryCommand, config: &Configuration) {
+    let mut history = WatchHistory::new(&config.watch_history_path);
+    let result = match command {
+        HistoryCommand::Play { number } => {
+            replay(config, &mut history, number);
+            return;
+        }
+        HistoryCommand::Clear => history.clear(),
+        HistoryCommand::Prune { older_than } => match parse_duration(&older_than) {
+            Ok(older_than) => history.prune(older_than),
+            Err(e) => Err(e),
+        },
+    };
+    match result {
+        Ok(removed) => println!("Removed {} plays from the history", removed),
+        Err(e) => println!("{}", e),
+    }
+}
+
+/**
+ * Play an entry from the history again, from the offline store if it's there
+ */
+fn replay(config: &Configuration, history: &mut WatchHistory, number: usize) {
+    let m3u8 = match number
+        .checked_sub(1)
+        .and_then(|idx| history.recent(number).get(idx).copied())
+    {
+        Some(entry) => M3u8::clone(entry),
+        None => {
+            println!("There is no play number {} in the history", number);
+            return;
+        }
+    };
+
+    let identity = config.identity;
+    let id = m3u8.id(identity);
+    let offline_entry = config
+        .offlinefile_content
+        .iter()
+        .find(|entry| entry.id(identity) == id && Path::new(&*entry.path).exists());
+    let (path, offline) = match offline_entry {
+        Some(entry) => (entry.path.clone(), true),
+        None => (m3u8.link.clone(), false),
+    };
+
+    let started = SystemTime::now();
+    stream(&m3u8, &path, false);
+    if let Err(e) = history.record(HistoryEntry::new(&m3u8, started, offline)) {
+        println!("Failed to save the watch history, {}", e);
+    }
+
+    // Something else may have been watched while this was playing
+    config.reload_seen_links_ugly();
+    let seen_links: Vec<&str> = config.seen_links.iter().map(String::as_str).collect();
+    if !m3u8.is_in(&seen_links, identity) {
+        let mut seen_links = config.seen_links.clone();
+        seen_links.push(id);
+        config.set_seen_links_ugly(seen_links);
+        if let Err(e) = config.write_seen_links() {
+            eprintln!("Failed to write watched links {:?}", e);
+        }
+    }
+}
+
+fn print_stats(config: &Configuration, limit: usize) {
+    let stats = WatchHistory::new(&config.watch_history_path).stats(config.identity);
+    if stats.plays == 0 {
+        println!("Nothing has been watched yet");
+        return;
+    }
+
+    println!(
+        "Watched for {:.1} hours over {} plays",
+        stats.duration.as_secs_f64() / 3600.0,
+        stats.plays
+    );
+    println!("{}", "Top channels".bold());
+    for (idx, channel) in stats.channels.iter().take(limit).enumerate() {
+        println!("  {}: {}", idx + 1, describe_tally(channel));
+    }
+    println!("{}", "Groups".bold());
+    for group in stats.groups.iter() {
+        println!("  {}", describe_tally(group));
+    }
+}
+
+fn describe_tally(tally: &Tally) -> String {
+    format!(
+        "{} {} ({} {})",
+        tally.name.bold(),
+        format_duration(tally.duration),
+        tally.plays,
+        if tally.plays == 1 { "play" } else { "plays" }
+    )
+}
+
+fn export_state(config: &Configuration, file: Option<PathBuf>, include_secrets: bool) {
+    let content = UserState::export(config, include_secrets)
+        .and_then(|state| serde_json::to_string_pretty(&state).map_err(|e| e.to_string()));
+    let content = match content {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    match file {
+        Some(file) => match fs::write(&file, content) {
+            Ok(()) => println!("Exported everything to '{}'", file.display()),
+            Err(e) => {
+                eprintln!("Failed to write '{}', {:?}", file.display(), e);
+                process::exit(1);
+            }
+        },
+        None => println!("{}", content),
+    }
+}
+
+fn import_state(config: &Configuration, file: PathBuf) {
+    let content = if file.as_os_str() == "-" {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content).map(|_| content)
+    } else {
+        fs::read_to_string(&file)
+    };
+    let state = content
+        .map_err(|e| format!("Failed to read '{}', {:?}", file.display(), e))
+        .and_then(|content| {
+            UserState::parse(&content)
+                .map_err(|e| format!("Failed to import '{}', {}", file.display(), e))
+        });
+    let report = match state.and_then(|state| state.import(config)) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    if report.settings_replaced {
+        println!("The imported settings are newer and replaced the local ones");
+    } else {
+        println!("The local settings are newer and were kept");
+    }
+    if let Some(backup) = report.config_backup {
+        println!("The previous configfile is saved as '{}'", backup);
+    }
+    if !report.sources_added.is_empty() {
+        println!(
+            "Added the sources {}",
+            report.sources_added.join(", ").bold()
+        );
+    }
+    println!(
+        "Added {} watched entries, {} offline entries, {} favorites and {} plays to the history",
+        report.watched_added, report.offline_added, report.favorites_added, report.history_added
+    );
+    if report.offline_missing > 0 {
+        println!(
+            "{} of the offline entries have no file on this machine, see {}",
+            report.offline_missing,
+            "ilovetv offline verify".bold()
+        );
+    }
+}
@@ -0,0 +1,641 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    download_with_progress, downloader::remote_content_length, Configuration, M3u8, OfflineEntry,
+    WatchHistory,
+};
+
+type Error = String;
+
+pub const MEDIA_FILE_ENDINGS: [&str; 12] = [
+    "mkv", "mp4", "m4v", "avi", "ts", "webm", "mov", "wmv", "flv", "mpg", "mpeg", "mp3",
+];
+
+/**
+ * Which files are deleted when their entries are removed from the catalog
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileDeletion {
+    Keep,
+    /// Only what was downloaded or recorded into the data directory, not imported files
+    InDataDir,
+    All,
+}
+
+pub enum EntryStatus {
+    Ok(u64),
+    Missing,
+    Truncated {
+        size: u64,
+        expected: Option<u64>,
+    },
+    /// The size is right but the content doesn't match the recorded checksum
+    Corrupt,
+}
+
+impl EntryStatus {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Self::Ok(_))
+    }
+}
+
+pub struct DiskUsage {
+    pub tracked: u64,
+    pub untracked: u64,
+    pub missing: usize,
+}
+
+/**
+ * Which entries that may be removed when the offline store is over its quota.
+ * Only watched entries in the data dir are ever evicted, those watched the longest ago first
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// By when they were last watched, or downloaded if they never were played here
+    #[default]
+    OldestWatched,
+    /// Only those downloaded more than this many days ago
+    WatchedOlderThan(u64),
+}
+
+pub struct EvictionReport {
+    pub quota: u64,
+    pub usage_before: u64,
+    pub usage_after: u64,
+    pub evicted: Vec<(OfflineEntry, u64)>,
+}
+
+impl EvictionReport {
+    pub fn fits(&self, incoming: u64) -> bool {
+        self.usage_after + incoming <= self.quota
+    }
+}
+
+pub struct OfflineLibrary {
+    config: Rc<Configuration>,
+}
+
+impl OfflineLibrary {
+    pub fn new(config: Rc<Configuration>) -> Self {
+        Self { config }
+    }
+
+    pub fn entries(&self) -> &Vec<OfflineEntry> {
+        &self.config.offlinefile_content
+    }
+
+    /**
+     * Size on disk for every entry, None if the file is gone
+     */
+    pub fn list(&self) -> Vec<(&OfflineEntry, Option<u64>)> {
+        self.entries()
+            .iter()
+            .map(|entry| (entry, file_size(Path::new(&*entry.path))))
+            .collect()
+    }
+
+    /**
+     * Remove the entries with the given indexes from the catalog, and their files as
+     * chosen by `delete_files`. Files that are already gone from disk are not considered an error
+     */
+    pub fn remove(
+        &self,
+        indexes: &[usize],
+        delete_files: FileDeletion,
+    ) -> Result<Vec<OfflineEntry>, Error> {
+        if let Some(idx) = indexes.iter().find(|idx| **idx >= self.entries().len()) {
+            return Err(format!("There is no offline entry with number {}", idx + 1));
+        }
+
+        let mut indexes = indexes.to_vec();
+        indexes.sort_unstable();
+        indexes.dedup();
+
+        // Remove from the back so that the indexes stay valid
+        let mut removed = Vec::with_capacity(indexes.len());
+        for idx in indexes.into_iter().rev() {
+            let entry = self.config.remove_offlinefile_ugly(idx);
+            let delete = match delete_files {
+                FileDeletion::Keep => false,
+                FileDeletion::InDataDir => self.is_in_data_dir(Path::new(&*entry.path)),
+                FileDeletion::All => true,
+            };
+            if delete {
+                if let Err(e) = fs::remove_file(&*entry.path) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        println!("Failed to remove '{}', {:?}", entry.path, e);
+                    }
+                }
+            }
+            removed.push(entry);
+        }
+        removed.reverse();
+
+        self.config
+            .write_datafile()
+            .map_err(|e| format!("Failed to write offline catalog {:?}", e))?;
+        Ok(removed)
+    }
+
+    /**
+     * Check that every entry still is on disk and matches the size and checksum recorded
+     * when it was downloaded. For entries without a recorded size we compare against the
+     * size the server reports, if the original link still answers
+     */
+    pub async fn verify(&self) -> Vec<(&OfflineEntry, EntryStatus)> {
+        let mut statuses = Vec::with_capacity(self.entries().len());
+        for entry in self.entries() {
+            let status = match check_entry(entry) {
+                EntryStatus::Ok(size) if entry.size.is_none() && entry.link.starts_with("http") => {
+                    match remote_content_length(&entry.link).await {
+                        Some(expected) if expected > size => EntryStatus::Truncated {
+                            size,
+                            expected: Some(expected),
+                        },
+                        _ => EntryStatus::Ok(size),
+                    }
+                }
+                status => status,
+            };
+            statuses.push((entry, status));
+        }
+        statuses
+    }
+
+    /**
+     * Add an entry that just finished downloading, recording its size and checksum
+     * if the download completed
+     */
+    pub fn add_download(&self, mut entry: OfflineEntry, completed: bool) {
+        if completed {
+            match checksum_file(Path::new(&*entry.path)) {
+                Ok((size, sha256)) => {
+                    entry.size = Some(size);
+                    entry.sha256 = Some(sha256);
+                }
+                Err(e) => println!("Failed to checksum '{}', {:?}", entry.path, e),
+            }
+        }
+        self.config.push_offlinefile_ugly(entry);
+    }
+
+    /**
+     * Where the entry is in the library, going by the identity strategy
+     */
+    pub fn position(&self, m3u8: &M3u8) -> Option<usize> {
+        let identity = self.config.identity;
+        let id = m3u8.id(identity);
+        self.entries()
+            .iter()
+            .position(|entry| entry.id(identity) == id)
+    }
+
+    /**
+     * Throw away the file of the entry and download it again from its link
+     */
+    pub async fn redownload(&self, index: usize) -> Result<(), Error> {
+        let mut entry = self
+            .entries()
+            .get(index)
+            .ok_or_else(|| format!("There is no offline entry with number {}", index + 1))?
+            .clone();
+
+        if let Err(e) = fs::remove_file(&*entry.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(format!("Failed to remove '{}', {:?}", entry.path, e));
+            }
+        }
+        println!("Downloading {}", &entry.name);
+        download_with_progress(&entry.link, Some(&entry.path))
+            .await
+            .map_err(|e| format!("Failed to download {}, {:?}", entry.name, e))?;
+
+        let (size, sha256) = checksum_file(Path::new(&*entry.path))
+            .map_err(|e| format!("Failed to checksum '{}', {:?}", entry.path, e))?;
+        entry.size = Some(size);
+        entry.sha256 = Some(sha256);
+        self.config.replace_offlinefile_ugly(index, entry);
+
+        self.config
+            .write_datafile()
+            .map_err(|e| format!("Failed to write offline catalog {:?}", e))
+    }
+
+    /**
+     * Add media files in the data dir that aren't in the catalog yet.
+     * Returns the entries that were added
+     */
+    pub fn rescan(&self) -> Result<Vec<OfflineEntry>, Error> {
+        let new_entries: Vec<OfflineEntry> = self
+            .untracked_files()
+            .into_iter()
+            .map(|path| entry_from_file(&path))
+            .collect();
+
+        self.add_entries(new_entries)
+    }
+
+    /**
+     * Add all media files found in a directory outside of the data dir, such as a NAS mount.
+     * Every file is matched against the playlist by name or tvg-id so that it keeps the
+     * metadata of the playlist entry. Returns the added entries and whether they were matched
+     */
+    pub fn import(
+        &self,
+        dir: &Path,
+        playlist: &[&M3u8],
+    ) -> Result<Vec<(OfflineEntry, bool)>, Error> {
+        if !dir.is_dir() {
+            return Err(format!("'{}' is not a directory", dir.display()));
+        }
+
+        let imported: Vec<(OfflineEntry, bool)> = find_media_files(dir)
+            .into_iter()
+            .filter(|path| !self.is_tracked(path))
+            .map(|path| match find_in_playlist(&path, playlist) {
+                Some(m3u8) => {
+                    let path = Rc::new(path.to_string_lossy().to_string());
+                    (OfflineEntry::new(m3u8.clone(), path), true)
+                }
+                None => (entry_from_file(&path), false),
+            })
+            .collect();
+
+        self.add_entries(imported.iter().map(|(entry, _)| entry.clone()).collect())?;
+        Ok(imported)
+    }
+
+    fn add_entries(&self, new_entries: Vec<OfflineEntry>) -> Result<Vec<OfflineEntry>, Error> {
+        for entry in new_entries.iter() {
+            self.config.push_offlinefile_ugly(entry.clone());
+        }
+        if !new_entries.is_empty() {
+            self.config
+                .write_datafile()
+                .map_err(|e| format!("Failed to write offline catalog {:?}", e))?;
+        }
+
+        Ok(new_entries)
+    }
+
+    pub fn disk_usage(&self) -> DiskUsage {
+        let mut usage = DiskUsage {
+            tracked: 0,
+            untracked: 0,
+            missing: 0,
+        };
+        for (_, size) in self.list() {
+            match size {
+                Some(size) => usage.tracked += size,
+                None => usage.missing += 1,
+            }
+        }
+        usage.untracked = self
+            .untracked_files()
+            .iter()
+            .filter_map(|path| file_size(path))
+            .sum();
+
+        usage
+    }
+
+    pub fn quota(&self) -> Result<Option<u64>, Error> {
+        self.config
+            .offline
+            .quota
+            .as_deref()
+            .map(parse_size)
+            .transpose()
+    }
+
+    /**
+     * Size of the offline entries that are stored in the data dir, which are the ones that
+     * can be evicted. Files that aren't in the catalog don't count, see `disk_usage`
+     */
+    pub fn store_usage(&self) -> u64 {
+        let mut counted = HashSet::new();
+        self.entries()
+            .iter()
+            .filter(|entry| self.is_in_data_dir(Path::new(&*entry.path)))
+            .filter(|entry| counted.insert(entry.path.clone()))
+            .filter_map(|entry| file_size(Path::new(&*entry.path)))
+            .sum()
+    }
+
+    /**
+     * Evict entries according to the eviction policy until `incoming` more bytes fit within
+     * the quota. Entries count as watched if they are marked so, are in the watched links
+     * or in `watched_ids`, which are those watched since they were saved.
+     * Returns None if there is no quota configured
+     */
+    pub fn enforce_quota(
+        &self,
+        incoming: u64,
+        watched_ids: &[&str],
+    ) -> Result<Option<EvictionReport>, Error> {
+        let quota = match self.quota()? {
+            Some(quota) => quota,
+            None => return Ok(None),
+        };
+        let usage_before = self.store_usage();
+        let mut report = EvictionReport {
+            quota,
+            usage_before,
+            usage_after: usage_before,
+            evicted: Vec::new(),
+        };
+        if report.fits(incoming) {
+            return Ok(Some(report));
+        }
+
+        let policy = self.config.offline.eviction_policy.unwrap_or_default();
+        let now = SystemTime::now();
+        let identity = self.config.identity;
+        let seen_links: Vec<&str> = self.config.seen_links.iter().map(String::as_str).collect();
+        let mut candidates: Vec<(usize, SystemTime, u64)> = self
+            .entries()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| {
+                entry.watched
+                    || entry.is_in(watched_ids, identity)
+                    || entry.is_in(&seen_links, identity)
+            })
+            .filter(|(_, entry)| self.is_in_data_dir(Path::new(&*entry.path)))
+            .filter_map(|(idx, entry)| {
+                let metadata = fs::metadata(&*entry.path).ok()?;
+                Some((idx, metadata.modified().ok()?, metadata.len()))
+            })
+            .filter(|(_, downloaded, _)| match policy {
+                EvictionPolicy::OldestWatched => true,
+                EvictionPolicy::WatchedOlderThan(days) => now
+                    .duration_since(*downloaded)
+                    .map(|age| age > Duration::from_secs(60 * 60 * 24 * days))
+                    .unwrap_or(false),
+            })
+            .collect();
+        match policy {
+            EvictionPolicy::OldestWatched => {
+                // The history knows when they were watched, the modification time is
+                // only when they were downloaded
+                let last_watched = WatchHistory::new(&self.config.watch_history_path)
+                    .last_watched(self.config.identity);
+                let entries = self.entries();
+                candidates.sort_by_cached_key(|(idx, downloaded, _)| {
+                    last_watched
+                        .get(&entries[*idx].id(self.config.identity))
+                        .copied()
+                        .unwrap_or(*downloaded)
+                });
+            }
+            EvictionPolicy::WatchedOlderThan(_) => {
+                candidates.sort_by_key(|(_, downloaded, _)| *downloaded)
+            }
+        }
+
+        let mut to_evict = Vec::new();
+        for (idx, _, size) in candidates {
+            if report.fits(incoming) {
+                break;
+            }
+            report.usage_after = report.usage_after.saturating_sub(size);
+            to_evict.push((idx, size));
+        }
+
+        // The removed entries come back ordered by index
+        to_evict.sort_by_key(|(idx, _)| *idx);
+        let indexes: Vec<usize> = to_evict.iter().map(|(idx, _)| *idx).collect();
+        if !indexes.is_empty() {
+            report.evicted = self
+                .remove(&indexes, FileDeletion::InDataDir)?
+                .into_iter()
+                .zip(to_evict.into_iter().map(|(_, size)| size))
+                .collect();
+        }
+
+        Ok(Some(report))
+    }
+
+    /**
+     * Whether a file is stored in the data directory, as opposed to imported from elsewhere
+     */
+    fn is_in_data_dir(&self, path: &Path) -> bool {
+        let data_dir = &self.config.data_dir;
+        path.starts_with(data_dir)
+            || match (path.canonicalize(), data_dir.canonicalize()) {
+                (Ok(path), Ok(data_dir)) => path.starts_with(data_dir),
+                _ => false,
+            }
+    }
+
+    fn untracked_files(&self) -> Vec<PathBuf> {
+        find_media_files(&self.config.data_dir)
+            .into_iter()
+            .filter(|path| !self.is_tracked(path))
+            .collect()
+    }
+
+    fn is_tracked(&self, path: &Path) -> bool {
+        self.entries()
+            .iter()
+            .any(|entry| Path::new(&*entry.path) == path)
+    }
+}
+
+/**
+ * Parse a human readable size such as "50GB", "1.5 GiB" or "700m" into bytes
+ */
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let size = size.trim();
+    let unit_start = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(unit_start);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid size", size))?;
+
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1 << 20,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "gib" => 1 << 30,
+        "t" | "tb" => 1000 * 1000 * 1000 * 1000,
+        "tib" => 1 << 40,
+        unit => return Err(format!("Unknown size unit '{}' in '{}'", unit, size)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/**
+ * Compare the file of an entry against what was recorded when it was downloaded.
+ * This reads through the whole file if there is a recorded checksum
+ */
+pub fn check_entry(entry: &OfflineEntry) -> EntryStatus {
+    let size = match file_size(Path::new(&*entry.path)) {
+        Some(size) => size,
+        None => return EntryStatus::Missing,
+    };
+
+    match entry.size {
+        _ if size == 0 => EntryStatus::Truncated {
+            size,
+            expected: entry.size,
+        },
+        Some(expected) if size < expected => EntryStatus::Truncated {
+            size,
+            expected: Some(expected),
+        },
+        Some(expected) if size > expected => EntryStatus::Corrupt,
+        _ => match &entry.sha256 {
+            Some(expected) => match checksum_file(Path::new(&*entry.path)) {
+                Ok((_, sha256)) if sha256 == *expected => EntryStatus::Ok(size),
+                Ok(_) => EntryStatus::Corrupt,
+                Err(_) => EntryStatus::Missing,
+            },
+            None => EntryStatus::Ok(size),
+        },
+    }
+}
+
+/**
+ * Size and hex encoded sha256 of a file
+ */
+pub fn checksum_file(path: &Path) -> Result<(u64, String), io::Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+pub fn is_media_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ending| ending.to_str())
+        .map(|ending| MEDIA_FILE_ENDINGS.contains(&ending.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/**
+ * Walk the directory recursively and collect every media file in it
+ */
+pub fn find_media_files(dir: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut to_visit = vec![dir.to_path_buf()];
+
+    while let Some(dir) = to_visit.pop() {
+        let read_dir = match fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir,
+            Err(e) => {
+                println!("Could not read directory '{}', {:?}", dir.display(), e);
+                continue;
+            }
+        };
+        for path in read_dir
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if path.is_dir() {
+                to_visit.push(path);
+            } else if is_media_file(&path) {
+                found.push(path);
+            }
+        }
+    }
+
+    found.sort();
+    found
+}
+
+/**
+ * Only keep letters and digits, so that "The.Show.S01E01" matches "The Show S01E01"
+ */
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn find_in_playlist<'a>(path: &Path, playlist: &[&'a M3u8]) -> Option<&'a M3u8> {
+    let stem = path.file_stem()?.to_string_lossy();
+    let normalized_stem = normalize_name(&stem);
+    if normalized_stem.is_empty() {
+        return None;
+    }
+
+    playlist
+        .iter()
+        .find(|m3u8| normalize_name(&m3u8.name) == normalized_stem)
+        .or_else(|| {
+            playlist
+                .iter()
+                .find(|m3u8| !m3u8.tvg_id.is_empty() && m3u8.tvg_id == stem)
+        })
+        .copied()
+}
+
+/**
+ * The file ending of the link, such as ".mp4", or ".mkv" if it doesn't seem to have one
+ */
+pub fn file_ending(link: &str) -> &str {
+    let potential_file_ending = link.rfind('.').map(|idx| &link[idx..]).unwrap_or_default();
+    if potential_file_ending.len() > 6 || potential_file_ending.contains('/') {
+        ".mkv"
+    } else {
+        potential_file_ending
+    }
+}
+
+/**
+ * Create an entry for a file that wasn't downloaded through a playlist.
+ * The path is used as link, since there is no link to be found
+ */
+pub fn entry_from_file(path: &Path) -> OfflineEntry {
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let path = Rc::new(path.to_string_lossy().to_string());
+
+    let m3u8 = M3u8 {
+        tvg_id: String::new(),
+        tvg_name: name.clone(),
+        tvg_logo: String::new(),
+        group_title: String::new(),
+        name,
+        link: path.clone(),
+        watched: false,
+        source: None,
+        catchup: None,
+        programmes: None,
+    };
+    OfflineEntry::new(m3u8, path)
+}
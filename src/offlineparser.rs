@@ -2,16 +2,21 @@ use std::{ops::Deref, rc::Rc};
 
 use serde::Serialize;
 
-use crate::{m3u8::M3u8, Configuration, GetM3u8, GetPlayPath, OfflineEntry};
+use crate::{
+    m3u8::{Identity, M3u8},
+    Configuration, GetM3u8, GetPlayPath, OfflineEntry,
+};
 
 #[derive(Serialize)]
 pub struct OfflineParser {
     offline_entries: Rc<Vec<OfflineEntry>>,
+    identity: Identity,
 }
 impl OfflineParser {
     pub fn new(config: &Configuration) -> Self {
         Self {
             offline_entries: config.offlinefile_content.clone(),
+            identity: config.identity,
         }
     }
 }
@@ -25,9 +30,10 @@ impl Deref for OfflineParser {
 }
 
 impl GetPlayPath for OfflineParser {
-    fn get_path_to_play(&self, link: Rc<String>) -> Result<Rc<String>, String> {
+    fn get_path_to_play(&self, m3u8: &M3u8) -> Result<Rc<String>, String> {
+        let id = m3u8.id(self.identity);
         for offline_entry in &*self.offline_entries {
-            if *offline_entry.link == *link {
+            if offline_entry.id(self.identity) == id {
                 return Ok(offline_entry.path.clone());
             }
         }
@@ -1,18 +1,92 @@
 use std::{ops::Deref, rc::Rc};
 
-use crate::{m3u8::M3u8, GetM3u8, GetPlayPath};
+use crate::{
+    m3u8::{Identity, M3u8},
+    Catchup, Epg, GetM3u8, GetPlayPath, Playlist, XtreamPlaylist,
+};
 
 pub struct OnlineParser {
     m3u8_items: Vec<M3u8>,
+    identity: Identity,
 }
 
 impl OnlineParser {
-    pub async fn new(m3u_content: &str, watched_links: &Vec<&str>) -> Self {
+    /**
+     * Parse all the playlists into one list, where every entry is tagged with its source.
+     * Entries are recognized as watched by their id under the identity strategy
+     */
+    pub async fn new(
+        playlists: &[Playlist],
+        watched_ids: &Vec<&str>,
+        identity: Identity,
+        epg: &Epg,
+    ) -> Self {
+        let mut m3u8_items = Self::parse_playlists(playlists, watched_ids, identity);
+        Self::attach_epg(&mut m3u8_items, epg);
         Self {
-            m3u8_items: Self::parse_m3u8(m3u_content, watched_links),
+            m3u8_items,
+            identity,
         }
     }
 
+    /**
+     * Give every entry the programmes of its tvg-id
+     */
+    fn attach_epg(m3u8_items: &mut [M3u8], epg: &Epg) {
+        if epg.is_empty() {
+            return;
+        }
+        for m3u8 in m3u8_items.iter_mut() {
+            m3u8.programmes = epg.programmes(&m3u8.tvg_id);
+        }
+    }
+
+    fn parse_playlists(
+        playlists: &[Playlist],
+        watched_ids: &Vec<&str>,
+        identity: Identity,
+    ) -> Vec<M3u8> {
+        playlists
+            .iter()
+            .flat_map(|playlist| match playlist.source.xtream {
+                Some(_) => Self::parse_xtream(
+                    &playlist.content,
+                    watched_ids,
+                    &playlist.source.name,
+                    identity,
+                ),
+                None => Self::parse_m3u8(
+                    &playlist.content,
+                    watched_ids,
+                    &playlist.source.name,
+                    identity,
+                ),
+            })
+            .collect()
+    }
+
+    /**
+     * The entries of an Xtream Codes panel, which are saved as json instead of a playlist
+     */
+    fn parse_xtream(
+        content: &str,
+        watched_ids: &Vec<&str>,
+        source: &Rc<String>,
+        identity: Identity,
+    ) -> Vec<M3u8> {
+        let mut m3u8_items = match XtreamPlaylist::parse(content) {
+            Ok(playlist) => playlist.to_m3u8s(source),
+            Err(e) => {
+                println!("{} in '{}'", e, source);
+                return Vec::new();
+            }
+        };
+        for m3u8_item in m3u8_items.iter_mut() {
+            m3u8_item.watched = m3u8_item.is_in(watched_ids, identity);
+        }
+        m3u8_items
+    }
+
     pub fn find(&self, name: &str) -> Vec<&M3u8> {
         let name = name.to_lowercase();
         self.m3u8_items
@@ -21,55 +95,101 @@ impl OnlineParser {
             .collect()
     }
 
-    pub async fn forcefully_update(&mut self, content: &str) {
-        let seen_links: &Vec<&str> = &self
+    pub async fn forcefully_update(&mut self, playlists: &[Playlist], epg: &Epg) {
+        let seen_ids: Vec<String> = self
             .m3u8_items
             .iter()
             .filter(|x| x.watched)
-            .map(|x| x.link.as_str())
+            .map(|x| x.id(self.identity))
             .collect();
+        let seen_ids = seen_ids.iter().map(String::as_str).collect();
 
-        self.m3u8_items = Self::parse_m3u8(content, seen_links);
+        self.m3u8_items = Self::parse_playlists(playlists, &seen_ids, self.identity);
+        Self::attach_epg(&mut self.m3u8_items, epg);
     }
 
-    fn parse_m3u8(content: &str, watched_links: &Vec<&str>) -> Vec<M3u8> {
+    fn parse_m3u8(
+        content: &str,
+        watched_ids: &Vec<&str>,
+        source: &Rc<String>,
+        identity: Identity,
+    ) -> Vec<M3u8> {
         let mut m3u8_items: Vec<M3u8> = Vec::new();
+        // The header may have attributes such as url-tvg, so it's not always a plain #EXTM3U
         let interesting_lines: Vec<String> = content
-            .replacen("#EXTM3U\n", "", 1)
             .lines()
             .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with("#EXTM3U"))
             .map(String::from)
             .collect();
 
         for i in (0..interesting_lines.len()).step_by(2) {
-            let mut items = Vec::new();
-            for to_find in ["tvg-id", "tvg-name", "tvg-logo", "group-title"] {
-                let offset: usize = format!("{}=", to_find).bytes().len();
-                let start: usize =
-                    interesting_lines[i].find(&format!("{}=", to_find)).unwrap() as usize + offset;
-
-                let end: usize = interesting_lines[i].rfind("=").unwrap();
-                items.push(&interesting_lines[i][start..=end])
-            }
+            let items: Vec<&str> = ["tvg-id", "tvg-name", "tvg-logo", "group-title"]
+                .iter()
+                .map(|key| get_attribute(&interesting_lines[i], key).unwrap_or_default())
+                .collect();
             let name_start = interesting_lines[i].rfind(",").unwrap() + 1;
             let name = &interesting_lines[i][name_start..];
             let link = interesting_lines[i + 1].as_str();
-            let is_watched = watched_links.contains(&link);
-            let m3u8_item = M3u8 {
+            let mut m3u8_item = M3u8 {
                 tvg_id: items[0].to_owned(),
                 tvg_name: items[1].to_owned(),
                 tvg_logo: items[2].to_owned(),
                 group_title: items[3].to_owned(),
                 name: name.to_owned(),
                 link: Rc::new(link.to_string()),
-                watched: is_watched,
+                watched: false,
+                source: Some(source.clone()),
+                catchup: parse_catchup(&interesting_lines[i]),
+                programmes: None,
             };
+            m3u8_item.watched = m3u8_item.is_in(watched_ids, identity);
             m3u8_items.push(m3u8_item);
         }
         m3u8_items
     }
 }
 
+/**
+ * Archive playback from catchup="..." and its related attributes, or the older timeshift="..."
+ */
+fn parse_catchup(line: &str) -> Option<Catchup> {
+    let kind = get_attribute(line, "catchup").or_else(|| {
+        get_attribute(line, "timeshift")
+            .filter(|x| !x.is_empty())
+            .map(|_| "shift")
+    })?;
+    let days = get_attribute(line, "catchup-days").or_else(|| get_attribute(line, "tvg-rec"));
+    let days = days.or_else(|| get_attribute(line, "timeshift"));
+    Catchup::new(
+        kind,
+        get_attribute(line, "catchup-source").unwrap_or_default(),
+        days,
+    )
+}
+
+/**
+ * Get the value of an attribute such as tvg-id="value" from an #EXTINF line
+ */
+pub fn get_attribute<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let pattern = format!("{}=\"", key);
+    let mut search_from = 0;
+    loop {
+        let start = search_from + line[search_from..].find(&pattern)?;
+        search_from = start + pattern.len();
+
+        // Make sure that we didn't just find the end of another key, like x-tvg-id for tvg-id
+        let is_whole_key = line[..start]
+            .chars()
+            .last()
+            .is_none_or(|c| c.is_whitespace() || c == ',');
+        if is_whole_key {
+            let end = search_from + line[search_from..].find('"')?;
+            return Some(&line[search_from..end]);
+        }
+    }
+}
+
 impl Deref for OnlineParser {
     type Target = Vec<M3u8>;
 
@@ -85,7 +205,7 @@ impl GetM3u8 for OnlineParser {
 }
 
 impl GetPlayPath for OnlineParser {
-    fn get_path_to_play<'a>(&'a self, link: Rc<String>) -> Result<Rc<String>, String> {
-        Ok(link.clone())
+    fn get_path_to_play(&self, m3u8: &M3u8) -> Result<Rc<String>, String> {
+        Ok(m3u8.link.clone())
     }
 }
@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -7,6 +7,158 @@ pub struct Opt {
     #[structopt(short, long, default_value = "ask")]
     /// Possible options: online, offline and ask
     pub mode: Mode,
+
+    #[structopt(short, long)]
+    /// Profile or source to use instead of the active profile in the configfile
+    pub profile: Option<String>,
+
+    #[structopt(long)]
+    /// Run the json api in the background while using the prompt, see `ilovetv api`
+    pub api: bool,
+
+    #[structopt(long, conflicts_with = "api")]
+    /// Browse, play and download in a full-screen terminal ui instead of the prompt
+    pub tui: bool,
+
+    #[structopt(subcommand)]
+    pub command: Option<SubCommand>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum SubCommand {
+    /// Manage the entries that are stored for offline use
+    Offline(OfflineCommand),
+    /// Show what changed in the playlists the latest refreshes
+    Changes {
+        #[structopt(short, long, default_value = "5")]
+        /// How many refreshes to show
+        limit: usize,
+        #[structopt(short, long)]
+        /// Only show channels with names containing this, such as to find when one disappeared
+        search: Option<String>,
+    },
+    /// List what has been played recently, or replay, clear and prune it
+    History {
+        #[structopt(short, long, default_value = "20")]
+        /// How many plays to list
+        limit: usize,
+        #[structopt(subcommand)]
+        command: Option<HistoryCommand>,
+    },
+    /// Bundle the config, watched entries, offline catalog and history into one json file
+    Export {
+        #[structopt(parse(from_os_str))]
+        /// Where to write it, stdout if left out
+        file: Option<PathBuf>,
+        #[structopt(long)]
+        /// Include api.token and the passwords of Xtream Codes panels, in plain text
+        include_secrets: bool,
+    },
+    /// Merge an exported file into this installation, - reads from stdin
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Play what a channel showed earlier, from the archive of the provider
+    Catchup {
+        /// The name of the channel, or a part of it that only one channel matches
+        channel: String,
+        #[structopt(long)]
+        /// How long ago to start, such as "90m" or "2h". Pick from the EPG if left out
+        ago: Option<String>,
+        #[structopt(long, default_value = "1h")]
+        /// How much to play when starting from --ago
+        duration: String,
+    },
+    /// Share the playlist and EPG of the profile over http, with the filters in [serve]
+    Serve {
+        #[structopt(long)]
+        /// Where to listen, such as "0.0.0.0:8080". Overrides serve.address in the configfile
+        address: Option<String>,
+    },
+    /// Schedule recordings of live channels, and run them with the daemon
+    Record(RecordCommand),
+    /// Control ilovetv over http with a json api, such as from a phone, instead of the prompt
+    Api {
+        #[structopt(long)]
+        /// Where to listen, such as "0.0.0.0:8081". Overrides api.address in the configfile
+        address: Option<String>,
+    },
+    /// Show the most watched channels and groups, and the total time watched
+    Stats {
+        #[structopt(short, long, default_value = "10")]
+        /// How many channels to show
+        limit: usize,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum HistoryCommand {
+    /// Play an entry again by its number in the history
+    Play { number: usize },
+    /// Forget everything that has been played
+    Clear,
+    /// Forget plays older than a duration, such as "30d" or "12w"
+    Prune { older_than: String },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum RecordCommand {
+    /// Record a channel, such as `record add "News HD" --from 20:00 --to 22:00`
+    Add {
+        /// The name of the channel, or a part of it that only one channel matches
+        channel: String,
+        #[structopt(long)]
+        /// When to start, such as "20:00", "2024-05-01 20:00" or "now"
+        from: String,
+        #[structopt(long)]
+        /// When to stop, a time before the start is the next day
+        to: String,
+        #[structopt(long)]
+        /// What to call the recording, the programme in the EPG if left out
+        title: Option<String>,
+    },
+    /// List the scheduled and finished recordings
+    List,
+    /// Remove recordings by their number in the list, recorded files are kept
+    Remove {
+        #[structopt(required = true)]
+        numbers: Vec<usize>,
+    },
+    /// Record everything that is scheduled, until stopped
+    Daemon,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum OfflineCommand {
+    /// List all offline entries together with their size on disk
+    List,
+    /// Remove entries by their number in the list, deleting their files in the data directory
+    Remove {
+        #[structopt(required = true)]
+        numbers: Vec<usize>,
+        #[structopt(long)]
+        /// Only remove the entries from the catalog, keeping all the files
+        keep_files: bool,
+        #[structopt(long, conflicts_with = "keep-files")]
+        /// Delete the files outside the data directory as well, such as imported ones
+        delete_files: bool,
+    },
+    /// Look for offline entries that are missing, truncated or don't match their checksum
+    Verify {
+        #[structopt(long)]
+        /// Download broken entries again if they still are available online
+        repair: bool,
+    },
+    /// Add media files in the data directory that aren't in the offline catalog
+    Rescan,
+    /// Evict watched entries until the offline store is within its quota
+    Evict,
+    /// Import media files from a directory, matching them against the playlist
+    Import {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
 }
 
 #[derive(Debug)]
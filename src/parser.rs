@@ -1,6 +1,6 @@
 use std::rc::Rc;
 
-use crate::M3u8;
+use crate::{m3u8::Identity, M3u8};
 
 pub trait GetM3u8 {
     fn get_m3u8(&self) -> Vec<&M3u8>;
@@ -8,7 +8,7 @@ pub trait GetM3u8 {
 
 pub trait WatchedFind {
     fn find(&self, name: &str) -> Vec<&M3u8>;
-    fn get_watched_links(&self) -> Vec<Rc<String>>;
+    fn get_watched_ids(&self, identity: Identity) -> Vec<String>;
 }
 
 impl<T: ?Sized + GetM3u8> WatchedFind for T {
@@ -20,16 +20,16 @@ impl<T: ?Sized + GetM3u8> WatchedFind for T {
             .collect()
     }
 
-    fn get_watched_links(&self) -> Vec<Rc<String>> {
+    fn get_watched_ids(&self, identity: Identity) -> Vec<String> {
         self.get_m3u8()
             .into_iter()
             .filter(|x| x.watched)
-            .map(|x| x.link.clone())
+            .map(|x| x.id(identity))
             .collect()
     }
 }
 pub trait GetPlayPath {
-    fn get_path_to_play(&self, link: Rc<String>) -> Result<Rc<String>, String>;
+    fn get_path_to_play(&self, m3u8: &M3u8) -> Result<Rc<String>, String>;
 }
 
 pub trait Parser: GetM3u8 + GetPlayPath {}
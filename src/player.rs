@@ -0,0 +1,91 @@
+use std::{
+    process::{Child, Command, Stdio},
+    time::SystemTime,
+};
+
+use crate::{get_mut_ref, GrandMother, M3u8};
+#[allow(unused_imports)]
+use crate::{GetM3u8, GetPlayPath};
+
+type Error = String;
+
+struct Playing {
+    child: Child,
+    entry: M3u8,
+    started: SystemTime,
+}
+
+/**
+ * mpv started in the background, so that the api or the terminal ui can go on
+ * while it plays. Plays are saved to the watch history when mpv is closed
+ */
+#[derive(Default)]
+pub struct Player {
+    playing: Option<Playing>,
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn playing(&self) -> Option<&M3u8> {
+        self.playing.as_ref().map(|playing| &playing.entry)
+    }
+
+    /**
+     * Play the entry in mpv instead of what's playing, and mark it as watched
+     */
+    pub fn play(&mut self, gm: &GrandMother, m3u8: &M3u8, fullscreen: bool) -> Result<(), Error> {
+        self.stop(gm);
+
+        let link = gm.parser.get_path_to_play(m3u8)?;
+        let mut command = Command::new("mpv");
+        command
+            .arg(&*link)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if fullscreen {
+            command.arg("--fs");
+        }
+        let child = command
+            .spawn()
+            .map_err(|e| format!("Failed to start mpv, {}", e))?;
+
+        unsafe { get_mut_ref(m3u8) }.watched = true;
+        gm.save_watched();
+        self.playing = Some(Playing {
+            child,
+            entry: m3u8.clone(),
+            started: SystemTime::now(),
+        });
+        Ok(())
+    }
+
+    /**
+     * Close mpv if it's playing, and return what was playing
+     */
+    pub fn stop(&mut self, gm: &GrandMother) -> Option<M3u8> {
+        let mut playing = self.playing.take()?;
+        let _ = playing.child.kill();
+        let _ = playing.child.wait();
+        gm.record_watch(&playing.entry, playing.started);
+        Some(playing.entry)
+    }
+
+    /**
+     * Notice when mpv has been closed by someone in front of it
+     */
+    pub fn poll(&mut self, gm: &GrandMother) {
+        let closed = match self.playing.as_mut() {
+            Some(playing) => !matches!(playing.child.try_wait(), Ok(None)),
+            None => false,
+        };
+        if closed {
+            if let Some(playing) = self.playing.take() {
+                gm.record_watch(&playing.entry, playing.started);
+            }
+        }
+    }
+}
@@ -1,91 +1,365 @@
-use std::{fs, ops::Deref, path::PathBuf, rc::Rc};
+use std::{
+    fs::{self, File},
+    future::Future,
+    io::{self, Read},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::SystemTime,
+};
 
-use crate::{download_with_progress, MAX_TRIES};
+use futures_util::future::{BoxFuture, FutureExt};
+
+use crate::{
+    config::{Location, Source},
+    downloader::{download_with_headers, fetch_if_modified, Fetched, Validators},
+    onlineparser::get_attribute,
+    xtream::fetch_xtream,
+    XtreamPlaylist, MAX_TRIES, STDIN_CONSUMED,
+};
 
 type Error = String;
 
 pub struct Playlist {
     pub content: String,
-    path_to_playlist: Rc<PathBuf>,
-    url: Option<Rc<String>>,
+    pub source: Source,
+    path_to_playlist: PathBuf,
+    /// Modification time of a local playlist when it was read
+    modified: Option<SystemTime>,
 }
 
 impl Playlist {
-    pub async fn new(path_to_playlist: Rc<PathBuf>, url: Rc<String>) -> Result<Self, String> {
+    pub async fn new(source: Source, cache_dir: &Path) -> Result<Self, String> {
         let mut me = Self {
             content: String::new(),
-            path_to_playlist,
-            url: Some(url),
+            path_to_playlist: cache_dir.join(source.cache_filename()),
+            source,
+            modified: None,
+        };
+        me.content = match me.source.location() {
+            Location::Stdin => Self::read_stdin()?,
+            Location::File(path) => {
+                me.modified = Self::modified(&path);
+                Self::read_local(&path)?
+            }
+            Location::Url(_) => me.get_saved_or_download().await?,
         };
-        me.content = me.get_saved_or_download().await?;
 
         Ok(me)
     }
 
+    /**
+     * Stdin can only be read once, the prompt has to read from the terminal after that
+     */
+    fn read_stdin() -> Result<String, Error> {
+        if STDIN_CONSUMED.swap(true, Ordering::SeqCst) {
+            return Err("Only one playlist can be read from stdin".to_owned());
+        }
+        let mut content = String::new();
+        io::stdin()
+            .read_to_string(&mut content)
+            .map_err(|e| format!("Failed to read playlist from stdin, {:?}", e))?;
+        Ok(content)
+    }
+
+    fn read_local(path: &Path) -> Result<String, Error> {
+        fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read playlist '{}', {:?}", path.display(), e))
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|x| x.modified()).ok()
+    }
+
     fn get_saved(&self) -> Option<String> {
-        if !self.should_update() {
-            return fs::read_to_string(&*self.path_to_playlist).ok();
-        }
-        None
-    }
-
-    fn should_update(&self) -> bool {
-        fs::metadata(&*self.path_to_playlist)
-            .and_then(|metadata| {
-                Ok({
-                    metadata
-                        .modified()?
-                        .elapsed()
-                        .map(|x| x.as_secs() > 60 * 60 * 24 * 3)
-                        .unwrap_or_else(|_| {
-                            println!("Could not get systemtime, trying to download new file");
-                            true
-                        })
-                })
-            })
-            .unwrap_or_else(|_| {
-                println!("Could not find a saved playlist, Downloading a new one");
-                false
-            })
+        if self.needs_refresh() {
+            return None;
+        }
+        let content = fs::read_to_string(&self.path_to_playlist).ok()?;
+        // A panel saved as a playlist by an older version has to be fetched again
+        if self.source.xtream.is_some() && XtreamPlaylist::parse(&content).is_err() {
+            return None;
+        }
+        Some(content)
+    }
+
+    /**
+     * Whether a downloaded playlist is missing or older than the refresh interval of its source.
+     * Playlists that aren't downloaded never need it
+     */
+    pub fn needs_refresh(&self) -> bool {
+        if !matches!(self.source.location(), Location::Url(_)) {
+            return false;
+        }
+
+        let max_age = self.source.refresh_interval();
+        fs::metadata(&self.path_to_playlist)
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| modified.elapsed().map(|x| x > max_age).unwrap_or(true))
+            .unwrap_or(true)
     }
 
     pub async fn get_saved_or_download(&self) -> Result<String, Error> {
-        let content = if let Some(content) = self.get_saved() {
-            content
+        match self.source.location() {
+            Location::Url(_) => {}
+            Location::File(path) => return Self::read_local(&path),
+            Location::Stdin => return Ok(self.content.clone()),
+        }
+
+        if let Some(content) = self.get_saved() {
+            return Ok(content);
+        }
+        if !self.path_to_playlist.exists() {
+            println!("Could not find a saved playlist, Downloading a new one");
+        }
+
+        match self.fetch_with_retries().await? {
+            Fetched::NotModified => {
+                self.touch();
+                if let Ok(content) = fs::read_to_string(&self.path_to_playlist) {
+                    return Ok(content);
+                }
+                // The saved playlist disappeared after the server was asked, so get it again
+                let downloaded = self.download().await?;
+                self.save(&downloaded);
+                Ok(downloaded)
+            }
+            Fetched::Modified {
+                content,
+                validators,
+            } => {
+                self.save(&content);
+                self.save_validators(&validators);
+                Ok(content)
+            }
+        }
+    }
+
+    /**
+     * Ask the server for the playlist again, regardless of how old the saved one is.
+     * Nothing is downloaded if the server says that it's unchanged.
+     * Local playlists are only read again if they have been modified
+     */
+    pub async fn refresh(&mut self) -> Result<(), Error> {
+        match self.source.location() {
+            Location::Url(_) => {}
+            Location::File(path) => {
+                let modified = Self::modified(&path);
+                if modified.is_none() || modified != self.modified {
+                    self.content = Self::read_local(&path)?;
+                    self.modified = modified;
+                }
+                return Ok(());
+            }
+            Location::Stdin => {
+                return Err(format!(
+                    "The playlist '{}' was read from stdin and can't be refreshed",
+                    self.source.name
+                ))
+            }
+        }
+
+        let fetched = self.fetch_with_retries().await?;
+        self.apply_fetched(fetched);
+        Ok(())
+    }
+
+    /**
+     * A conditional request for the playlist that can be sent to another thread,
+     * None if the playlist isn't downloaded
+     */
+    pub fn fetch_in_background(
+        &self,
+    ) -> Option<impl Future<Output = Result<Fetched, Error>> + Send + 'static> {
+        match self.source.location() {
+            Location::Url(_) => Some(self.fetch(self.read_validators(), false)),
+            _ => None,
+        }
+    }
+
+    /**
+     * A conditional request for the playlist, or the playlist made from the panel
+     * for Xtream Codes sources. Those are always fetched whole
+     */
+    fn fetch(
+        &self,
+        validators: Validators,
+        show_progress: bool,
+    ) -> BoxFuture<'static, Result<Fetched, Error>> {
+        match self.source.xtream.as_ref() {
+            Some(login) => fetch_xtream(
+                self.source.url.to_string(),
+                login.clone(),
+                self.source.headers.clone(),
+                show_progress,
+            )
+            .boxed(),
+            None => fetch_if_modified(
+                self.source.url.to_string(),
+                self.source.headers.clone(),
+                validators,
+                show_progress,
+            )
+            .boxed(),
+        }
+    }
+
+    /**
+     * Save the result of a conditional request. Returns true if the playlist changed
+     */
+    pub fn apply_fetched(&mut self, fetched: Fetched) -> bool {
+        match fetched {
+            Fetched::NotModified => {
+                self.touch();
+                false
+            }
+            Fetched::Modified {
+                content,
+                validators,
+            } => {
+                self.save(&content);
+                self.save_validators(&validators);
+                self.content = content;
+                true
+            }
+        }
+    }
+
+    async fn fetch_with_retries(&self) -> Result<Fetched, Error> {
+        // Without a saved playlist there is nothing to compare with
+        let validators = if self.path_to_playlist.exists() {
+            self.read_validators()
         } else {
-            let downloaded = self.download().await?;
-            if let Err(e) = fs::write(&*self.path_to_playlist, &downloaded) {
-                println!(
-                    "Failed to save downloaded playlist to file, {:?}, path: '{}'",
-                    e,
-                    &self.path_to_playlist.as_os_str().to_str().unwrap()
-                );
+            Validators::default()
+        };
+
+        let mut counter: u8 = 0;
+        loop {
+            counter += 1;
+            let fetched = self.fetch(validators.clone(), true).await;
+
+            match fetched {
+                Ok(fetched) => break Ok(fetched),
+                Err(e) if counter > MAX_TRIES => {
+                    break Err(format!(
+                        "Failed to download playlist '{}', {}",
+                        self.source.name, e
+                    ))
+                }
+                Err(_) => println!("Retrying {}/{}", counter + 1, MAX_TRIES),
             }
-            downloaded
+        }
+    }
+
+    /**
+     * Where to get the EPG, from the source or the url-tvg/x-tvg-url in the header
+     * of the playlist. The header may list several, separated by commas
+     */
+    pub fn epg_urls(&self) -> Vec<String> {
+        if let Some(epg) = self.source.epg.as_ref() {
+            return vec![epg.clone()];
+        }
+        if self.source.xtream.is_some() {
+            return XtreamPlaylist::parse(&self.content)
+                .map(|playlist| vec![playlist.epg_url])
+                .unwrap_or_default();
+        }
+        let header = match self.content.lines().next() {
+            Some(line) if line.starts_with("#EXTM3U") => line,
+            _ => return Vec::new(),
         };
+        get_attribute(header, "url-tvg")
+            .or_else(|| get_attribute(header, "x-tvg-url"))
+            .map(|urls| {
+                urls.split(',')
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
 
-        Ok(content)
+    fn validators_path(&self) -> PathBuf {
+        let mut path = self.path_to_playlist.clone().into_os_string();
+        path.push(".validators.json");
+        path.into()
+    }
+
+    fn read_validators(&self) -> Validators {
+        fs::read_to_string(self.validators_path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_validators(&self, validators: &Validators) {
+        let path = self.validators_path();
+        let result = if validators.is_empty() {
+            fs::remove_file(&path).or(Ok(()))
+        } else {
+            serde_json::to_string(validators)
+                .map_err(io::Error::from)
+                .and_then(|content| fs::write(&path, content))
+        };
+        if let Err(e) = result {
+            println!("Failed to save '{}', {:?}", path.display(), e);
+        }
+    }
+
+    /**
+     * Mark the saved playlist as fresh, since the server said it's unchanged
+     */
+    fn touch(&self) {
+        let _ = File::options()
+            .write(true)
+            .open(&self.path_to_playlist)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+    }
+
+    fn save(&self, content: &str) {
+        if let Err(e) = fs::write(&self.path_to_playlist, content) {
+            println!(
+                "Failed to save downloaded playlist to file, {:?}, path: '{}'",
+                e,
+                &self.path_to_playlist.display()
+            );
+        }
     }
 
     pub async fn download(&self) -> Result<String, Error> {
+        if !matches!(self.source.location(), Location::Url(_)) {
+            return Err(format!(
+                "The playlist '{}' is not downloaded from an url",
+                self.source.name
+            ));
+        }
+
+        if self.source.xtream.is_some() {
+            return match self.fetch(Validators::default(), true).await? {
+                Fetched::Modified { content, .. } => Ok(content),
+                Fetched::NotModified => Err(format!(
+                    "Failed to download playlist '{}'",
+                    self.source.name
+                )),
+            };
+        }
+
         let mut counter: u8 = 0;
         loop {
             counter += 1;
 
-            let url = self
-                .url
-                .as_ref()
-                .ok_or_else(|| String::from("In offline mode"))?
-                .clone();
-
-            let downloaded = download_with_progress(&url, None)
+            let downloaded = download_with_headers(&self.source.url, None, &self.source.headers)
                 .await
                 .map(TryInto::try_into);
 
             if let Ok(Ok(content)) = downloaded {
                 break Ok(content);
             } else if counter > MAX_TRIES {
-                break Err("Failed to download playlist".to_owned());
+                break Err(format!(
+                    "Failed to download playlist '{}'",
+                    self.source.name
+                ));
             }
             println!("Retrying {}/{}", counter + 1, MAX_TRIES);
         }
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::M3u8;
+
+/// How many diffs to keep in the history
+pub const MAX_DIFF_HISTORY: usize = 30;
+
+/**
+ * The parts of an entry that are worth remembering in the history
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiffEntry {
+    pub tvg_id: String,
+    pub name: String,
+    pub group_title: String,
+    pub link: Rc<String>,
+    pub source: Option<Rc<String>>,
+}
+
+impl From<&M3u8> for DiffEntry {
+    fn from(m3u8: &M3u8) -> Self {
+        Self {
+            tvg_id: m3u8.tvg_id.clone(),
+            name: m3u8.name.clone(),
+            group_title: m3u8.group_title.clone(),
+            link: m3u8.link.clone(),
+            source: m3u8.source.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChangedLink {
+    pub entry: DiffEntry,
+    pub old_link: Rc<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlaylistDiff {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub added: Vec<DiffEntry>,
+    pub removed: Vec<DiffEntry>,
+    pub changed: Vec<ChangedLink>,
+}
+
+type Key<'a> = (Option<&'a str>, &'a str, &'a str, &'a str);
+
+impl PlaylistDiff {
+    /**
+     * Compare two versions of the playlists. Entries are the same if they have the same
+     * source, tvg-id, name and group, which means that a new url counts as a change
+     */
+    pub fn between(old: &[&M3u8], new: &[&M3u8]) -> Self {
+        let mut old_by_key = group_by_key(old);
+        let new_by_key = group_by_key(new);
+
+        let mut diff = Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+
+        for (key, new_entries) in new_by_key.into_iter() {
+            let old_entries = old_by_key.remove(&key).unwrap_or_default();
+            // Entries that kept their link are unchanged, the rest are paired up as changed
+            let kept_new: Vec<&&M3u8> = new_entries
+                .iter()
+                .filter(|x| !old_entries.iter().any(|y| x.link == y.link))
+                .collect();
+            let kept_old: Vec<&&M3u8> = old_entries
+                .iter()
+                .filter(|x| !new_entries.iter().any(|y| x.link == y.link))
+                .collect();
+
+            for (idx, new_entry) in kept_new.iter().enumerate() {
+                match kept_old.get(idx) {
+                    Some(old_entry) => diff.changed.push(ChangedLink {
+                        entry: (**new_entry).into(),
+                        old_link: old_entry.link.clone(),
+                    }),
+                    None => diff.added.push((**new_entry).into()),
+                }
+            }
+            for old_entry in kept_old.iter().skip(kept_new.len()) {
+                diff.removed.push((**old_entry).into());
+            }
+        }
+        for old_entries in old_by_key.into_values() {
+            diff.removed
+                .extend(old_entries.into_iter().map(DiffEntry::from));
+        }
+
+        diff.added.sort_by(|a, b| a.name.cmp(&b.name));
+        diff.removed.sort_by(|a, b| a.name.cmp(&b.name));
+        diff.changed.sort_by(|a, b| a.entry.name.cmp(&b.entry.name));
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} new channels, {} removed, {} URLs changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+
+    pub fn local_time(&self) -> String {
+        chrono::DateTime::from_timestamp(self.timestamp as i64, 0)
+            .map(|x| {
+                x.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+}
+
+fn group_by_key<'a>(entries: &[&'a M3u8]) -> HashMap<Key<'a>, Vec<&'a M3u8>> {
+    let mut by_key: HashMap<Key, Vec<&M3u8>> = HashMap::new();
+    for entry in entries.iter() {
+        let key = (
+            entry.source.as_deref().map(String::as_str),
+            entry.tvg_id.as_str(),
+            entry.name.as_str(),
+            entry.group_title.as_str(),
+        );
+        by_key.entry(key).or_default().push(*entry);
+    }
+    by_key
+}
+
+/**
+ * The latest diffs, oldest first
+ */
+pub fn read_diff_history(path: &Path) -> Vec<PlaylistDiff> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+pub fn push_diff_history(path: &Path, diff: &PlaylistDiff) -> Result<(), String> {
+    let mut history = read_diff_history(path);
+    history.push(diff.clone());
+    if history.len() > MAX_DIFF_HISTORY {
+        history.drain(..history.len() - MAX_DIFF_HISTORY);
+    }
+
+    let content = serde_json::to_string(&history).map_err(|e| e.to_string())?;
+    fs::write(path, content).map_err(|e| format!("Failed to write '{}', {:?}", path.display(), e))
+}
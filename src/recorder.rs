@@ -0,0 +1,706 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinHandle, time};
+
+use crate::{
+    epg::{self, local_time},
+    Configuration, M3u8, OfflineEntry, OfflineLibrary, Programme,
+};
+
+type Error = String;
+
+/// Recordings of EPG programmes start this much earlier and end this much later
+pub const RECORDING_PADDING: i64 = 60;
+/// How often the daemon looks for recordings to start
+const DAEMON_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait before reconnecting when a live stream drops
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", tag = "state")]
+pub enum JobStatus {
+    Scheduled,
+    Recording,
+    Done { path: String, size: u64 },
+    Failed { error: String },
+}
+
+/**
+ * A live channel to record between two times, in seconds since the unix epoch
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordingJob {
+    pub id: u64,
+    pub title: String,
+    pub channel: String,
+    pub group_title: String,
+    pub link: String,
+    /// The headers of the source, such as User-Agent
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub start: i64,
+    pub stop: i64,
+    pub status: JobStatus,
+}
+
+impl RecordingJob {
+    pub fn new(m3u8: &M3u8, title: String, start: i64, stop: i64) -> Self {
+        Self {
+            id: 0,
+            title,
+            channel: m3u8.name.clone(),
+            group_title: m3u8.group_title.clone(),
+            link: m3u8.link.to_string(),
+            headers: BTreeMap::new(),
+            start,
+            stop,
+            status: JobStatus::Scheduled,
+        }
+    }
+
+    /**
+     * A recording of a programme in the EPG, padded since the times rarely are exact
+     */
+    pub fn from_programme(m3u8: &M3u8, programme: &Programme) -> Self {
+        Self::new(
+            m3u8,
+            programme.title.clone(),
+            programme.start - RECORDING_PADDING,
+            programme.stop + RECORDING_PADDING,
+        )
+    }
+
+    pub fn describe_time(&self) -> String {
+        format!(
+            "{}-{}",
+            local_time(self.start, "%a %d %b %H:%M"),
+            local_time(self.stop, "%H:%M")
+        )
+    }
+
+    fn file_name(&self) -> String {
+        let title: String = self
+            .title
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("{}-{}.ts", title, local_time(self.start, "%Y%m%d-%H%M"))
+    }
+}
+
+/**
+ * The recording jobs, stored in the data dir so that the daemon can pick them up.
+ * Both `record add` and the daemon change them, so every change is made to what
+ * is on disk right then
+ */
+pub struct Schedule {
+    path: PathBuf,
+    jobs: Vec<RecordingJob>,
+}
+
+impl Schedule {
+    /**
+     * Read the jobs. A file that can't be read is an error rather than an empty schedule,
+     * which would replace it when saved
+     */
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            jobs: Self::read_jobs(path)?,
+        })
+    }
+
+    fn read_jobs(path: &Path) -> Result<Vec<RecordingJob>, Error> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Failed to read '{}', {:?}", path.display(), e)),
+        };
+        serde_json::from_str(&content).map_err(|e| {
+            format!(
+                "The recordings in '{}' are broken, fix or remove the file. {}",
+                path.display(),
+                e
+            )
+        })
+    }
+
+    pub fn jobs(&self) -> &[RecordingJob] {
+        &self.jobs
+    }
+
+    /**
+     * Add a job, unless the channel is already being recorded at that time
+     */
+    pub fn add(&mut self, mut job: RecordingJob) -> Result<&RecordingJob, Error> {
+        if job.stop <= job.start {
+            return Err("The recording has to end after it starts".to_owned());
+        }
+        if job.stop <= epg::now() {
+            return Err("The recording would already have ended".to_owned());
+        }
+        let id = self.update(|jobs| {
+            let overlapping = jobs.iter().find(|x| {
+                x.link == job.link
+                    && matches!(x.status, JobStatus::Scheduled | JobStatus::Recording)
+                    && x.start < job.stop
+                    && job.start < x.stop
+            });
+            if let Some(overlapping) = overlapping {
+                return Err(format!(
+                    "{} is already recorded {} for '{}'",
+                    job.channel,
+                    overlapping.describe_time(),
+                    overlapping.title
+                ));
+            }
+
+            let id = jobs.iter().map(|x| x.id).max().unwrap_or_default() + 1;
+            job.id = id;
+            jobs.push(job);
+            jobs.sort_by_key(|x| x.start);
+            Ok(id)
+        })?;
+        Ok(self.jobs.iter().find(|x| x.id == id).unwrap())
+    }
+
+    /**
+     * Remove jobs by their index. Files of finished recordings are kept,
+     * they're in the offline catalog
+     */
+    pub fn remove(&mut self, indexes: &[usize]) -> Result<Vec<RecordingJob>, Error> {
+        self.update(|jobs| {
+            if let Some(idx) = indexes.iter().find(|idx| **idx >= jobs.len()) {
+                return Err(format!("There is no recording with number {}", idx + 1));
+            }
+            let mut indexes = indexes.to_vec();
+            indexes.sort_unstable();
+            indexes.dedup();
+
+            Ok(indexes
+                .into_iter()
+                .rev()
+                .map(|idx| jobs.remove(idx))
+                .collect())
+        })
+    }
+
+    fn set_status(&mut self, id: u64, status: JobStatus) -> Result<(), Error> {
+        self.update(|jobs| {
+            if let Some(job) = jobs.iter_mut().find(|x| x.id == id) {
+                job.status = status;
+            }
+            Ok(())
+        })
+    }
+
+    /**
+     * Read the jobs again, change them and save them, so that what another process
+     * saved meanwhile isn't overwritten
+     */
+    fn update<T>(
+        &mut self,
+        change: impl FnOnce(&mut Vec<RecordingJob>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.jobs = Self::read_jobs(&self.path)?;
+        let changed = change(&mut self.jobs)?;
+        self.save()?;
+        Ok(changed)
+    }
+
+    /**
+     * Write to another file first and move it in place, so that the file is never
+     * read when it's half written
+     */
+    fn save(&self) -> Result<(), Error> {
+        let content = serde_json::to_string(&self.jobs).map_err(|e| e.to_string())?;
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(format!(".{}.partial", std::process::id()));
+        fs::write(&partial, content)
+            .and_then(|()| fs::rename(&partial, &self.path))
+            .map_err(|e| format!("Failed to write '{}', {:?}", self.path.display(), e))
+    }
+}
+
+/**
+ * Run the schedule unattended. Jobs are read from disk every few seconds, so jobs
+ * that are added while running are picked up. Finished recordings are added to the
+ * offline catalog
+ */
+pub async fn run_daemon(config: Rc<Configuration>) {
+    let recordings_dir = config.data_dir.join("recordings");
+    if let Err(e) = fs::create_dir_all(&recordings_dir) {
+        println!("Failed to create '{}', {:?}", recordings_dir.display(), e);
+        return;
+    }
+    println!("Waiting for recordings, stop with ctrl-c");
+
+    let mut running: HashMap<u64, (PathBuf, JoinHandle<Result<u64, Error>>)> = HashMap::new();
+    let mut broken_schedule = None;
+    loop {
+        let mut schedule = match Schedule::new(&config.recordings_path) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                // Said once, not every few seconds until it's fixed
+                if broken_schedule.as_ref() != Some(&e) {
+                    println!("{}", e);
+                    broken_schedule = Some(e);
+                }
+                time::sleep(DAEMON_INTERVAL).await;
+                continue;
+            }
+        };
+        broken_schedule = None;
+        let now = epg::now();
+
+        // Jobs that were removed while recording are stopped, and what was recorded is thrown away
+        let removed: Vec<u64> = running
+            .keys()
+            .filter(|id| !schedule.jobs().iter().any(|x| x.id == **id))
+            .copied()
+            .collect();
+        for id in removed {
+            let (path, handle) = running.remove(&id).unwrap();
+            handle.abort();
+            let _ = handle.await;
+            let _ = fs::remove_file(part_path(&path));
+            println!(
+                "Stopped the recording to '{}', it was removed",
+                path.display()
+            );
+        }
+
+        let finished: Vec<u64> = running
+            .iter()
+            .filter(|(_, (_, handle))| handle.is_finished())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in finished {
+            let (path, handle) = running.remove(&id).unwrap();
+            let result = handle.await.map_err(|e| e.to_string()).and_then(|x| x);
+            let job = match schedule.jobs().iter().find(|x| x.id == id) {
+                Some(job) => job.clone(),
+                None => continue,
+            };
+            let status = match result {
+                Ok(size) => {
+                    register_recording(&config, &job, &path);
+                    println!("Recorded {} to '{}'", job.title, path.display());
+                    JobStatus::Done {
+                        path: path.to_string_lossy().to_string(),
+                        size,
+                    }
+                }
+                Err(error) => {
+                    println!("Failed to record {}, {}", job.title, error);
+                    JobStatus::Failed { error }
+                }
+            };
+            log_error(schedule.set_status(id, status));
+        }
+
+        // Jobs that say that they're recording without being so were interrupted
+        let waiting: Vec<RecordingJob> = schedule
+            .jobs()
+            .iter()
+            .filter(|x| matches!(x.status, JobStatus::Scheduled | JobStatus::Recording))
+            .filter(|x| !running.contains_key(&x.id))
+            .cloned()
+            .collect();
+        for job in waiting {
+            if job.stop <= now {
+                let error = "Missed, the daemon wasn't running".to_owned();
+                log_error(schedule.set_status(job.id, JobStatus::Failed { error }));
+            } else if job.start <= now {
+                println!(
+                    "Recording {} from {} until {}",
+                    job.title.clone(),
+                    job.channel,
+                    local_time(job.stop, "%H:%M")
+                );
+                let path = recordings_dir.join(job.file_name());
+                let handle = tokio::spawn(capture(
+                    job.link.clone(),
+                    job.headers.clone(),
+                    path.clone(),
+                    job.stop,
+                ));
+                running.insert(job.id, (path, handle));
+                log_error(schedule.set_status(job.id, JobStatus::Recording));
+            }
+        }
+
+        time::sleep(DAEMON_INTERVAL).await;
+    }
+}
+
+fn log_error(result: Result<(), Error>) {
+    if let Err(e) = result {
+        println!("{}", e);
+    }
+}
+
+fn register_recording(config: &Rc<Configuration>, job: &RecordingJob, path: &Path) {
+    // Something else may have changed the catalog since the daemon started
+    config.reload_offlinefile_ugly();
+
+    let path = Rc::new(path.to_string_lossy().to_string());
+    let m3u8 = M3u8 {
+        // No tvg-id, so that the recording is never mistaken for the live channel
+        tvg_id: String::new(),
+        tvg_name: job.title.clone(),
+        tvg_logo: String::new(),
+        group_title: job.group_title.clone(),
+        name: format!(
+            "{} ({}, {})",
+            job.title,
+            job.channel,
+            local_time(job.start, "%Y-%m-%d %H:%M")
+        ),
+        link: path.clone(),
+        watched: false,
+        source: None,
+        catchup: None,
+        programmes: None,
+    };
+    OfflineLibrary::new(config.clone()).add_download(OfflineEntry::new(m3u8, path), true);
+    if let Err(e) = config.write_datafile() {
+        println!(
+            "Failed to add the recording to the offline catalog, {:?}",
+            e
+        );
+    }
+}
+
+/**
+ * Record a live stream until `stop`. HLS playlists are followed segment by segment,
+ * anything else is saved as it's streamed. A dropped stream is reconnected until
+ * it's time to stop. Returns the size of the recording
+ *
+ * It's recorded to a .part file next to `path` that is renamed when it's done, so that
+ * nothing takes a recording that is still going for a finished one
+ */
+pub async fn capture(
+    link: String,
+    headers: BTreeMap<String, String>,
+    path: PathBuf,
+    stop: i64,
+) -> Result<u64, Error> {
+    let part = part_path(&path);
+    let recorded = capture_to(&link, &headers, &part, stop).await;
+    match recorded {
+        Ok(size) => fs::rename(&part, &path)
+            .map(|()| size)
+            .map_err(|e| format!("Failed to move '{}', {:?}", part.display(), e)),
+        Err(e) => {
+            let _ = fs::remove_file(&part);
+            Err(e)
+        }
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+async fn capture_to(
+    link: &str,
+    headers: &BTreeMap<String, String>,
+    path: &Path,
+    stop: i64,
+) -> Result<u64, Error> {
+    let client = Client::builder().build().map_err(|e| e.to_string())?;
+    let mut file = File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open '{}', {:?}", path.display(), e))?;
+
+    let mut recorded = 0;
+    let mut seen_segments = HashSet::new();
+    let mut last_error = None;
+    while epg::now() < stop {
+        let result =
+            capture_once(&client, link, headers, &mut file, stop, &mut seen_segments).await;
+        match result {
+            Ok((size, ended)) => {
+                recorded += size;
+                if ended {
+                    break;
+                }
+            }
+            Err((size, e)) => {
+                recorded += size;
+                last_error = Some(e);
+            }
+        }
+        let remaining = stop - epg::now();
+        if remaining > 0 {
+            time::sleep(RECONNECT_DELAY.min(Duration::from_secs(remaining as u64))).await;
+        }
+    }
+
+    match last_error {
+        Some(e) if recorded == 0 => Err(e),
+        _ => Ok(recorded),
+    }
+}
+
+type CaptureResult = Result<(u64, bool), (u64, Error)>;
+
+/**
+ * One connection to the stream. Returns how much was written,
+ * and whether the stream ended by itself
+ */
+async fn capture_once(
+    client: &Client,
+    link: &str,
+    headers: &BTreeMap<String, String>,
+    file: &mut File,
+    stop: i64,
+    seen_segments: &mut HashSet<String>,
+) -> CaptureResult {
+    let resp = get(client, link, headers).await.map_err(|e| (0, e))?;
+    let is_hls = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_lowercase().contains("mpegurl"))
+        .unwrap_or(false)
+        || Url::parse(link)
+            .map(|x| x.path().ends_with(".m3u8"))
+            .unwrap_or(false);
+
+    if is_hls {
+        let playlist_url = resp.url().clone();
+        let playlist = resp.text().await.map_err(|e| (0, e.to_string()))?;
+        return capture_hls(
+            client,
+            playlist_url,
+            playlist,
+            headers,
+            file,
+            stop,
+            seen_segments,
+        )
+        .await;
+    }
+
+    let mut written = 0;
+    let mut resp = resp;
+    loop {
+        let chunk = match time::timeout(until(stop), resp.chunk()).await {
+            Ok(chunk) => chunk.map_err(|e| (written, e.to_string()))?,
+            // Time to stop
+            Err(_) => return Ok((written, true)),
+        };
+        match chunk {
+            Some(bytes) => {
+                file.write_all(&bytes)
+                    .map_err(|e| (written, format!("{:?}", e)))?;
+                written += bytes.len() as u64;
+            }
+            // Live streams have no length, one that has is a file that has been downloaded whole
+            None => {
+                let is_whole = resp
+                    .content_length()
+                    .is_some_and(|length| written >= length);
+                return Ok((written, is_whole));
+            }
+        }
+    }
+}
+
+async fn capture_hls(
+    client: &Client,
+    mut playlist_url: Url,
+    mut playlist: String,
+    headers: &BTreeMap<String, String>,
+    file: &mut File,
+    stop: i64,
+    seen_segments: &mut HashSet<String>,
+) -> CaptureResult {
+    let mut written = 0;
+
+    // A master playlist lists the variants, the one with the highest bandwidth is used
+    if let Some(variant) = best_variant(&playlist) {
+        playlist_url = playlist_url
+            .join(&variant)
+            .map_err(|e| (0, e.to_string()))?;
+        playlist = fetch_text(client, playlist_url.as_str(), headers)
+            .await
+            .map_err(|e| (0, e))?;
+    }
+
+    loop {
+        if playlist
+            .lines()
+            .any(|line| line.starts_with("#EXT-X-KEY") && !line.contains("METHOD=NONE"))
+        {
+            return Err((
+                written,
+                "Encrypted HLS streams can't be recorded".to_owned(),
+            ));
+        }
+
+        for segment in media_segments(&playlist) {
+            if epg::now() >= stop {
+                return Ok((written, true));
+            }
+            let segment_url = playlist_url
+                .join(&segment)
+                .map_err(|e| (written, e.to_string()))?
+                .to_string();
+            if !seen_segments.insert(segment_url.clone()) {
+                continue;
+            }
+            let bytes = get(client, &segment_url, headers)
+                .await
+                .map_err(|e| (written, e))?
+                .bytes()
+                .await
+                .map_err(|e| (written, e.to_string()))?;
+            file.write_all(&bytes)
+                .map_err(|e| (written, format!("{:?}", e)))?;
+            written += bytes.len() as u64;
+        }
+
+        if playlist.contains("#EXT-X-ENDLIST") {
+            return Ok((written, true));
+        }
+        if epg::now() >= stop {
+            return Ok((written, true));
+        }
+        // New segments show up about once per target duration
+        let target_duration = playlist
+            .lines()
+            .find_map(|line| line.strip_prefix("#EXT-X-TARGETDURATION:"))
+            .and_then(|x| x.trim().parse::<u64>().ok())
+            .unwrap_or(6);
+        let wait = Duration::from_secs((target_duration / 2).max(1)).min(until(stop));
+        time::sleep(wait).await;
+
+        playlist = fetch_text(client, playlist_url.as_str(), headers)
+            .await
+            .map_err(|e| (written, e))?;
+    }
+}
+
+fn best_variant(playlist: &str) -> Option<String> {
+    let lines: Vec<&str> = playlist.lines().map(str::trim).collect();
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.starts_with("#EXT-X-STREAM-INF"))
+        .filter_map(|(idx, line)| {
+            let bandwidth = line
+                .split([':', ','])
+                .find_map(|x| x.strip_prefix("BANDWIDTH="))
+                .and_then(|x| x.parse::<u64>().ok())
+                .unwrap_or_default();
+            let uri = lines[idx + 1..]
+                .iter()
+                .find(|x| !x.is_empty() && !x.starts_with('#'))?;
+            Some((bandwidth, uri.to_string()))
+        })
+        .max_by_key(|(bandwidth, _)| *bandwidth)
+        .map(|(_, uri)| uri)
+}
+
+fn media_segments(playlist: &str) -> Vec<String> {
+    playlist
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+async fn get(
+    client: &Client,
+    link: &str,
+    headers: &BTreeMap<String, String>,
+) -> Result<reqwest::Response, Error> {
+    let mut builder = client.get(link);
+    for (key, value) in headers.iter() {
+        builder = builder.header(key, value);
+    }
+    let resp = builder.send().await.map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("The server answered {}", resp.status()));
+    }
+    Ok(resp)
+}
+
+async fn fetch_text(
+    client: &Client,
+    link: &str,
+    headers: &BTreeMap<String, String>,
+) -> Result<String, Error> {
+    get(client, link, headers)
+        .await?
+        .text()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn until(stop: i64) -> Duration {
+    Duration::from_secs((stop - epg::now()).max(0) as u64)
+}
+
+/**
+ * Parse a time such as "20:00", "now" or "2024-05-01 20:00" in local time.
+ * Times without a date are today
+ */
+pub fn parse_local_time(time: &str) -> Result<i64, Error> {
+    let time = time.trim();
+    if time == "now" {
+        return Ok(epg::now());
+    }
+    let parsed = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").or_else(|_| {
+        NaiveTime::parse_from_str(time, "%H:%M")
+            .map(|clock| Local::now().date_naive().and_time(clock))
+    });
+    parsed
+        .ok()
+        .and_then(|x| Local.from_local_datetime(&x).earliest())
+        .map(|x| x.timestamp())
+        .ok_or_else(|| {
+            format!(
+                "'{}' has to be a time such as 20:00, 2024-05-01 20:00 or now",
+                time
+            )
+        })
+}
+
+/**
+ * The window to record from two times given by the user. A stop before the start is the
+ * next day, and a window that already has passed today is for tomorrow
+ */
+pub fn recording_window(from: &str, to: &str) -> Result<(i64, i64), Error> {
+    const DAY: i64 = 60 * 60 * 24;
+    let has_date =
+        |x: &str| NaiveDate::parse_from_str(x.get(..10).unwrap_or_default(), "%Y-%m-%d").is_ok();
+    let mut start = parse_local_time(from)?;
+    let mut stop = parse_local_time(to)?;
+    if stop <= start {
+        stop += DAY;
+    }
+    if stop <= epg::now() && !has_date(from) && !has_date(to) {
+        start += DAY;
+        stop += DAY;
+    }
+    Ok((start, stop))
+}
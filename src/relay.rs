@@ -0,0 +1,250 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    hash::{BuildHasher, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{header, Client, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::server::{Body, Request, Response};
+
+/**
+ * Which entries `ilovetv serve` sends through itself instead of linking to them directly
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RelayMode {
+    Never,
+    /// Entries from sources with headers, which most players can't send themselves
+    #[default]
+    WithHeaders,
+    Always,
+}
+
+/**
+ * Where a relayed entry really is, and the headers of its source
+ */
+pub struct Upstream {
+    pub link: String,
+    pub headers: BTreeMap<String, String>,
+}
+
+/**
+ * Fetches streams with the headers of their source and pipes them to the client.
+ * HLS playlists are rewritten so that the segments also go through the relay.
+ * Those links are signed, so that the relay can't be used to fetch anything else
+ */
+pub struct Relay {
+    client: Client,
+    /// Made up when the server starts, links to segments don't outlive it anyway
+    secret: String,
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        // The hasher of a HashMap is seeded randomly for every process
+        let seed = format!(
+            "{:?}{}",
+            SystemTime::now().duration_since(UNIX_EPOCH),
+            RandomState::new().build_hasher().finish()
+        );
+        Self {
+            // Redirects are followed, so that relative segments resolve against the final url
+            client: Client::builder().build().unwrap_or_default(),
+            secret: hex_sha256(&seed),
+        }
+    }
+
+    /**
+     * A stable key for an entry, so that the links in the playlist survive a refresh
+     */
+    pub fn key(id: &str) -> String {
+        hex_sha256(id)[..16].to_owned()
+    }
+
+    pub async fn forward(&self, request: &Request, key: &str, upstream: &Upstream) -> Response {
+        self.fetch(request, &upstream.link, key, &upstream.headers)
+            .await
+    }
+
+    /**
+     * Forward a link that was put in a rewritten HLS playlist, with the headers of
+     * the entry that the playlist belongs to. `headers_of` finds them by the key of the entry
+     */
+    pub async fn forward_signed(
+        &self,
+        request: &Request,
+        headers_of: impl Fn(&str) -> Option<BTreeMap<String, String>>,
+    ) -> Response {
+        match self.signed_upstream(request, headers_of) {
+            Ok((key, upstream)) => self.forward(request, &key, &upstream).await,
+            Err(status) => Response::error(status),
+        }
+    }
+
+    /**
+     * The key and upstream of a link that was put in a rewritten HLS playlist, or the status
+     * to answer with if it wasn't signed by this relay
+     */
+    pub fn signed_upstream(
+        &self,
+        request: &Request,
+        headers_of: impl Fn(&str) -> Option<BTreeMap<String, String>>,
+    ) -> Result<(String, Upstream), &'static str> {
+        let (link, key, signature) = match (
+            request.query("url"),
+            request.query("key"),
+            request.query("sig"),
+        ) {
+            (Some(link), Some(key), Some(signature)) => (link, key, signature),
+            _ => return Err("400 Bad Request"),
+        };
+        if signature != self.sign(&link, &key) {
+            return Err("403 Forbidden");
+        }
+        match headers_of(&key) {
+            Some(headers) => Ok((key, Upstream { link, headers })),
+            None => Err("404 Not Found"),
+        }
+    }
+
+    async fn fetch(
+        &self,
+        request: &Request,
+        link: &str,
+        key: &str,
+        headers: &BTreeMap<String, String>,
+    ) -> Response {
+        let mut builder = self.client.get(link);
+        for (key, value) in headers.iter() {
+            builder = builder.header(key, value);
+        }
+        // Lets players seek in movies
+        if let Some(range) = request.headers.get("range") {
+            builder = builder.header(header::RANGE, range);
+        }
+        // The path and query may have a username and password, such as for Xtream Codes
+        let server = Url::parse(link)
+            .map(|x| x.origin().ascii_serialization())
+            .unwrap_or_default();
+        let upstream = match builder.send().await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                println!(
+                    "Failed to relay {} from {}, {}",
+                    key,
+                    server,
+                    e.without_url()
+                );
+                return Response::error("502 Bad Gateway");
+            }
+        };
+        if !upstream.status().is_success() {
+            println!(
+                "Failed to relay {} from {}, it answered {}",
+                key,
+                server,
+                upstream.status()
+            );
+            return Response::error("502 Bad Gateway");
+        }
+
+        let content_type = upstream
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let is_hls = content_type.to_lowercase().contains("mpegurl")
+            || upstream.url().path().ends_with(".m3u8");
+        if is_hls {
+            let base = upstream.url().clone();
+            return match upstream.text().await {
+                Ok(playlist) => Response::ok(
+                    "application/vnd.apple.mpegurl",
+                    self.rewrite_hls(&playlist, &base, key, request.host()),
+                ),
+                Err(_) => Response::error("502 Bad Gateway"),
+            };
+        }
+
+        let mut response_headers = Vec::new();
+        for name in [
+            header::CONTENT_TYPE,
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::ACCEPT_RANGES,
+        ] {
+            if let Some(value) = upstream.headers().get(&name).and_then(|x| x.to_str().ok()) {
+                response_headers.push((name.to_string(), value.to_owned()));
+            }
+        }
+        Response {
+            status: upstream.status().to_string(),
+            headers: response_headers,
+            body: Body::Stream(upstream),
+        }
+    }
+
+    /**
+     * Point the variants, segments and keys of an HLS playlist to the relay
+     */
+    fn rewrite_hls(&self, playlist: &str, base: &Url, key: &str, host: &str) -> String {
+        let relayed = |uri: &str| match base.join(uri) {
+            Ok(link) => self.signed_link(link.as_str(), key, host),
+            Err(_) => uri.to_owned(),
+        };
+
+        let mut rewritten = String::with_capacity(playlist.len() * 2);
+        for line in playlist.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !line.starts_with('#') {
+                rewritten.push_str(&relayed(line));
+            } else if let Some((before, after)) = line.split_once("URI=\"") {
+                // Such as #EXT-X-KEY, #EXT-X-MAP and #EXT-X-MEDIA
+                match after.split_once('"') {
+                    Some((uri, rest)) => {
+                        rewritten.push_str(&format!("{}URI=\"{}\"{}", before, relayed(uri), rest))
+                    }
+                    None => rewritten.push_str(line),
+                }
+            } else {
+                rewritten.push_str(line);
+            }
+            rewritten.push('\n');
+        }
+        rewritten
+    }
+
+    fn signed_link(&self, link: &str, key: &str, host: &str) -> String {
+        let mut relayed = match Url::parse(&format!("http://{}/relay/url", host)) {
+            Ok(relayed) => relayed,
+            Err(_) => return link.to_owned(),
+        };
+        relayed
+            .query_pairs_mut()
+            .append_pair("url", link)
+            .append_pair("key", key)
+            .append_pair("sig", &self.sign(link, key));
+        relayed.to_string()
+    }
+
+    fn sign(&self, link: &str, key: &str) -> String {
+        hex_sha256(&format!("{}\n{}\n{}", self.secret, link, key))
+    }
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn hex_sha256(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
@@ -0,0 +1,325 @@
+use crate::M3u8;
+
+type Error = String;
+
+/// What a filter such as `group=news` looks at
+#[derive(Clone, Copy)]
+enum Field {
+    Name,
+    Group,
+    Source,
+    TvgId,
+}
+
+impl Field {
+    fn parse(field: &str) -> Result<Self, Error> {
+        match field {
+            "name" => Ok(Field::Name),
+            "group" => Ok(Field::Group),
+            "source" => Ok(Field::Source),
+            "tvg-id" => Ok(Field::TvgId),
+            _ => Err(format!(
+                "'{}' can't be filtered on, only name, group, source and tvg-id",
+                field
+            )),
+        }
+    }
+
+    fn of<'a>(&self, m3u8: &'a M3u8) -> &'a str {
+        match self {
+            Field::Name => &m3u8.name,
+            Field::Group => &m3u8.group_title,
+            Field::Source => m3u8.source.as_deref().map(String::as_str).unwrap_or(""),
+            Field::TvgId => &m3u8.tvg_id,
+        }
+    }
+}
+
+/// One of the comma separated parts of a selection
+enum Term {
+    All,
+    /// The first and last number of the results to pick, from 1
+    Range(usize, usize),
+    Watched(bool),
+    /// `field=value` for an exact match and `field~value` for containing, ignoring case
+    Filter {
+        field: Field,
+        value: String,
+        exact: bool,
+    },
+}
+
+impl Term {
+    fn parse(term: &str, count: usize) -> Result<Self, Error> {
+        if term == "a" || term == "all" {
+            return Ok(Term::All);
+        }
+        if term == "watched" || term == "unwatched" {
+            return Ok(Term::Watched(term == "watched"));
+        }
+        if let Some(idx) = term.find(['=', '~']) {
+            let (field, value) = (term[..idx].trim(), term[idx + 1..].trim());
+            if value.is_empty() {
+                return Err(format!("'{}' doesn't say what to look for", term));
+            }
+            return Ok(Term::Filter {
+                field: Field::parse(field)?,
+                value: value.to_lowercase(),
+                exact: term[idx..].starts_with('='),
+            });
+        }
+
+        let (first, last) = match term.split_once('-') {
+            Some((first, last)) => (parse_number(first, count)?, parse_number(last, count)?),
+            None => {
+                let number = parse_number(term, count)?;
+                (number, number)
+            }
+        };
+        if first > last {
+            return Err(format!(
+                "The range '{}' has to go from the lower number to the higher",
+                term
+            ));
+        }
+        Ok(Term::Range(first, last))
+    }
+
+    fn matches(&self, number: usize, m3u8: &M3u8) -> bool {
+        match self {
+            Term::All => true,
+            Term::Range(first, last) => (*first..=*last).contains(&number),
+            Term::Watched(watched) => m3u8.watched == *watched,
+            Term::Filter {
+                field,
+                value,
+                exact,
+            } => {
+                let content = field.of(m3u8).to_lowercase();
+                if *exact {
+                    content == *value
+                } else {
+                    content.contains(value.as_str())
+                }
+            }
+        }
+    }
+}
+
+/**
+ * A number of one of the results, which are numbered from 1
+ */
+fn parse_number(number: &str, count: usize) -> Result<usize, Error> {
+    let number = number.trim();
+    match number.parse::<usize>() {
+        Ok(0) => Err("The results are numbered from 1, there is no 0".to_owned()),
+        Ok(parsed) if parsed > count => Err(format!(
+            "There is no result {}, only 1 to {}",
+            parsed, count
+        )),
+        Ok(parsed) => Ok(parsed),
+        Err(_) => Err(format!(
+            "'{}' isn't a number, a range such as 1-10, a, watched, unwatched \
+             or a filter such as group=news or name~hd",
+            number
+        )),
+    }
+}
+
+/**
+ * Pick from the results with comma separated numbers, ranges such as `1-10`, `a` for all,
+ * `watched`, `unwatched` and filters such as `group=news` or `name~hd`. Parts starting
+ * with `!` leave out what they match, from everything if nothing else is picked,
+ * such as `a,!3,!7`. The picked entries are returned in the order of the results
+ */
+pub fn select<'a>(results: &[&'a M3u8], selection: &str) -> Result<Vec<&'a M3u8>, Error> {
+    let mut included = Vec::new();
+    let mut excluded = Vec::new();
+    for term in selection.split(',').map(str::trim) {
+        if term.is_empty() {
+            continue;
+        }
+        match term.strip_prefix('!') {
+            Some(term) => excluded.push(Term::parse(term.trim(), results.len())?),
+            None => included.push(Term::parse(term, results.len())?),
+        }
+    }
+    if included.is_empty() && excluded.is_empty() {
+        return Err("Nothing was selected".to_owned());
+    }
+
+    let selected: Vec<&M3u8> = results
+        .iter()
+        .enumerate()
+        .map(|(idx, m3u8)| (idx + 1, *m3u8))
+        .filter(|(number, m3u8)| {
+            (included.is_empty() || included.iter().any(|x| x.matches(*number, m3u8)))
+                && !excluded.iter().any(|x| x.matches(*number, m3u8))
+        })
+        .map(|(_, m3u8)| m3u8)
+        .collect();
+    if selected.is_empty() {
+        return Err(format!("Nothing in the results matches '{}'", selection));
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn m3u8(name: &str, group: &str, source: &str, tvg_id: &str, watched: bool) -> M3u8 {
+        M3u8 {
+            tvg_id: tvg_id.to_owned(),
+            tvg_name: name.to_owned(),
+            tvg_logo: String::new(),
+            group_title: group.to_owned(),
+            name: name.to_owned(),
+            link: Rc::new(format!("http://example.org/{}", name)),
+            watched,
+            source: Some(Rc::new(source.to_owned())),
+            catchup: None,
+            programmes: None,
+        }
+    }
+
+    fn entries() -> Vec<M3u8> {
+        vec![
+            m3u8("News HD", "News", "home", "news.hd", true),
+            m3u8("News SD", "News", "home", "news.sd", false),
+            m3u8("Sports HD", "Sports", "home", "sports.hd", false),
+            m3u8("Movie", "Movies", "work", "", true),
+            m3u8("Kids", "Kids", "work", "kids", false),
+        ]
+    }
+
+    /// The names of what `selection` picks from the entries
+    fn names(selection: &str) -> Result<Vec<String>, Error> {
+        let entries = entries();
+        let results: Vec<&M3u8> = entries.iter().collect();
+        select(&results, selection).map(|x| x.iter().map(|m3u8| m3u8.name.clone()).collect())
+    }
+
+    #[test]
+    fn picks_numbers_and_ranges_in_the_order_of_the_results() {
+        assert_eq!(names("3"), Ok(vec!["Sports HD".to_owned()]));
+        assert_eq!(
+            names("5, 1-2,2"),
+            Ok(vec![
+                "News HD".to_owned(),
+                "News SD".to_owned(),
+                "Kids".to_owned()
+            ])
+        );
+        assert_eq!(names("4-4"), Ok(vec!["Movie".to_owned()]));
+    }
+
+    #[test]
+    fn picks_everything() {
+        assert_eq!(names("a").unwrap().len(), 5);
+        assert_eq!(names("all").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn leaves_out_exclusions() {
+        assert_eq!(
+            names("a,!2,!4-5"),
+            Ok(vec!["News HD".to_owned(), "Sports HD".to_owned()])
+        );
+        // Only exclusions start from everything
+        assert_eq!(
+            names("!1-3"),
+            Ok(vec!["Movie".to_owned(), "Kids".to_owned()])
+        );
+        assert_eq!(
+            names("group=news, ! name~sd"),
+            Ok(vec!["News HD".to_owned()])
+        );
+    }
+
+    #[test]
+    fn picks_by_watched() {
+        assert_eq!(
+            names("watched"),
+            Ok(vec!["News HD".to_owned(), "Movie".to_owned()])
+        );
+        assert_eq!(
+            names("unwatched,!5"),
+            Ok(vec!["News SD".to_owned(), "Sports HD".to_owned()])
+        );
+    }
+
+    #[test]
+    fn filters_exactly_or_by_containing_ignoring_case() {
+        assert_eq!(names("group=NEWS").unwrap().len(), 2);
+        assert_eq!(
+            names("group=new"),
+            Err("Nothing in the results matches 'group=new'".to_owned())
+        );
+        assert_eq!(
+            names("name~hd"),
+            Ok(vec!["News HD".to_owned(), "Sports HD".to_owned()])
+        );
+        assert_eq!(
+            names("source=work"),
+            Ok(vec!["Movie".to_owned(), "Kids".to_owned()])
+        );
+        assert_eq!(names("tvg-id~sports"), Ok(vec!["Sports HD".to_owned()]));
+        assert_eq!(
+            names("tvg-id=news.sd, 5"),
+            Ok(vec!["News SD".to_owned(), "Kids".to_owned()])
+        );
+    }
+
+    #[test]
+    fn rejects_numbers_outside_the_results() {
+        assert_eq!(
+            names("0"),
+            Err("The results are numbered from 1, there is no 0".to_owned())
+        );
+        assert_eq!(
+            names("2,6"),
+            Err("There is no result 6, only 1 to 5".to_owned())
+        );
+        assert_eq!(
+            names("3-9"),
+            Err("There is no result 9, only 1 to 5".to_owned())
+        );
+        assert_eq!(
+            names("!0"),
+            Err("The results are numbered from 1, there is no 0".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_what_it_doesnt_understand() {
+        assert_eq!(
+            names("4-2"),
+            Err("The range '4-2' has to go from the lower number to the higher".to_owned())
+        );
+        assert_eq!(
+            names("x"),
+            Err(
+                "'x' isn't a number, a range such as 1-10, a, watched, unwatched \
+                 or a filter such as group=news or name~hd"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            names("link=http"),
+            Err("'link' can't be filtered on, only name, group, source and tvg-id".to_owned())
+        );
+        assert_eq!(
+            names("group="),
+            Err("'group=' doesn't say what to look for".to_owned())
+        );
+        assert_eq!(names(""), Err("Nothing was selected".to_owned()));
+        assert_eq!(names(" , ,"), Err("Nothing was selected".to_owned()));
+        assert_eq!(
+            names("a,!a"),
+            Err("Nothing in the results matches 'a,!a'".to_owned())
+        );
+    }
+}
@@ -0,0 +1,662 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use quick_xml::escape::escape;
+use reqwest::Url;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{
+    epg::{self, local_time},
+    hdhomerun::Tuner,
+    relay::{Relay, RelayMode, Upstream},
+    BackgroundRefresher, Favorites, GrandMother, Identity, M3u8, Programme, ServeConf,
+};
+
+type Error = String;
+
+/// How often to look for playlists that are due for a refresh
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the EPG is read again, it's only downloaded when the cached one is too old
+const EPG_RELOAD_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Requests with a bigger head than this are refused
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+/// How long a client may take to send its request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * The entries to serve and their programmes, made from the state of the GrandMother.
+ * It's made again when the playlists are refreshed, and shared with the connections
+ */
+pub struct Snapshot {
+    /// Without the header and relayed links, since they depend on the host
+    entries: Vec<ServedEntry>,
+    epg: String,
+    /// Every entry by its key, they can all be tuned through the relay
+    upstreams: HashMap<String, Upstream>,
+    /// How many entries there are
+    pub len: usize,
+}
+
+pub struct ServedEntry {
+    extinf: String,
+    link: String,
+    /// Whether the playlist links to the relay instead of the entry
+    relayed: bool,
+    pub name: String,
+    /// The channel number, counting from 1 in the order of the playlist
+    pub number: usize,
+    /// Where the entry is at in the relay, /relay/{key}
+    pub key: String,
+}
+
+impl Snapshot {
+    pub fn new(gm: &GrandMother, conf: &ServeConf) -> Self {
+        let served = served_entries(gm, conf);
+        Self::of(&served, conf, gm.config.identity, |m3u8| {
+            gm.headers_of(m3u8)
+        })
+    }
+
+    /**
+     * The snapshot of entries that have already been filtered and put in order
+     */
+    fn of(
+        served: &[M3u8],
+        conf: &ServeConf,
+        identity: Identity,
+        headers_of: impl Fn(&M3u8) -> BTreeMap<String, String>,
+    ) -> Self {
+        let mut entries = Vec::with_capacity(served.len());
+        let mut upstreams = HashMap::new();
+        for (i, m3u8) in served.iter().enumerate() {
+            let number = i + 1;
+            let headers = headers_of(m3u8);
+            let relayed = match conf.relay {
+                RelayMode::Never => false,
+                RelayMode::WithHeaders => !headers.is_empty(),
+                RelayMode::Always => true,
+            };
+            // The id is only unique with some identities, such as the url
+            let id = m3u8.id(identity);
+            let mut key = Relay::key(&id);
+            if upstreams.contains_key(&key) {
+                key = Relay::key(&format!("{}{}", id, number));
+            }
+            let upstream = Upstream {
+                link: m3u8.link.to_string(),
+                headers,
+            };
+            upstreams.insert(key.clone(), upstream);
+            entries.push(ServedEntry {
+                extinf: to_extinf(m3u8, number),
+                link: m3u8.link.to_string(),
+                relayed,
+                name: m3u8.name.clone(),
+                number,
+                key,
+            });
+        }
+
+        // Several entries can share a tvg-id, such as the HD and SD version of a channel
+        let mut channels: Vec<&M3u8> = Vec::new();
+        for m3u8 in served.iter() {
+            if !m3u8.tvg_id.is_empty() && !channels.iter().any(|x| x.tvg_id == m3u8.tvg_id) {
+                channels.push(m3u8);
+            }
+        }
+
+        let mut epg = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<tv generator-info-name=\"ilovetv\">\n",
+        );
+        for m3u8 in channels.iter() {
+            epg.push_str(&format!(
+                "  <channel id=\"{}\">\n    <display-name>{}</display-name>\n",
+                escape(m3u8.tvg_id.as_str()),
+                escape(m3u8.name.as_str())
+            ));
+            if !m3u8.tvg_logo.is_empty() {
+                epg.push_str(&format!(
+                    "    <icon src=\"{}\"/>\n",
+                    escape(m3u8.tvg_logo.as_str())
+                ));
+            }
+            epg.push_str("  </channel>\n");
+        }
+        for m3u8 in channels.iter() {
+            for programme in m3u8.programmes.iter().flat_map(|x| x.iter()) {
+                epg.push_str(&to_xmltv(&m3u8.tvg_id, programme));
+            }
+        }
+        epg.push_str("</tv>\n");
+
+        Self {
+            entries,
+            epg,
+            upstreams,
+            len: served.len(),
+        }
+    }
+
+    pub fn entries(&self) -> &[ServedEntry] {
+        &self.entries
+    }
+
+    /**
+     * The playlist with the EPG of this server in the header, as seen from `host`
+     */
+    pub fn playlist(&self, host: &str) -> String {
+        let mut playlist = format!("#EXTM3U url-tvg=\"http://{}/epg.xml\"\n", host);
+        for entry in self.entries.iter() {
+            playlist.push_str(&entry.extinf);
+            if entry.relayed {
+                playlist.push_str(&format!("http://{}/relay/{}", host, entry.key));
+            } else {
+                playlist.push_str(&entry.link);
+            }
+            playlist.push('\n');
+        }
+        playlist
+    }
+}
+
+/**
+ * The entries of the profile with the filters, renames and favorites applied.
+ * The names in serve.favorites come first in the order they are listed, since that
+ * sets the channel numbers. Then come the favorites from the terminal ui, and the rest,
+ * in playlist order
+ */
+fn served_entries(gm: &GrandMother, conf: &ServeConf) -> Vec<M3u8> {
+    let exclude: Vec<String> = conf.exclude.iter().map(|x| x.to_lowercase()).collect();
+    let mut served: Vec<M3u8> = gm
+        .parser
+        .get_m3u8()
+        .into_iter()
+        .filter(|m3u8| conf.groups.is_empty() || conf.groups.contains(&m3u8.group_title))
+        .filter(|m3u8| {
+            let name = m3u8.name.to_lowercase();
+            let group_title = m3u8.group_title.to_lowercase();
+            !exclude
+                .iter()
+                .any(|x| name.contains(x) || group_title.contains(x))
+        })
+        .cloned()
+        .collect();
+
+    // Sorting is stable, so everything that isn't a favorite keeps its order
+    let favorites = Favorites::new(&gm.config.favorites_path);
+    let identity = gm.config.identity;
+    served.sort_by_cached_key(|m3u8| {
+        conf.favorites
+            .iter()
+            .position(|name| *name == m3u8.name)
+            .unwrap_or_else(|| match favorites.contains(&m3u8.id(identity)) {
+                true => conf.favorites.len(),
+                false => usize::MAX,
+            })
+    });
+    for m3u8 in served.iter_mut() {
+        if let Some(name) = conf.rename.get(&m3u8.name) {
+            m3u8.name = name.clone();
+        }
+    }
+    served
+}
+
+fn to_extinf(m3u8: &M3u8, number: usize) -> String {
+    let attribute = |value: &str| value.replace('"', "'");
+    let catchup = m3u8
+        .catchup
+        .as_ref()
+        .map(|catchup| catchup.to_attributes())
+        .unwrap_or_default();
+    // The name is everything after the last comma, so it can't have any of its own
+    let name: Vec<&str> = m3u8.name.split(',').map(str::trim).collect();
+    format!(
+        "#EXTINF:-1 tvg-id=\"{}\" tvg-chno=\"{}\" tvg-name=\"{}\" tvg-logo=\"{}\" group-title=\"{}\"{},{}\n",
+        attribute(&m3u8.tvg_id),
+        number,
+        attribute(&m3u8.tvg_name),
+        attribute(&m3u8.tvg_logo),
+        attribute(&m3u8.group_title),
+        catchup,
+        name.join(" ")
+    )
+}
+
+fn to_xmltv(channel: &str, programme: &Programme) -> String {
+    let time = |timestamp: i64| {
+        chrono::DateTime::from_timestamp(timestamp, 0)
+            .map(|x| x.format("%Y%m%d%H%M%S +0000").to_string())
+            .unwrap_or_default()
+    };
+    let mut xml = format!(
+        "  <programme start=\"{}\" stop=\"{}\" channel=\"{}\">\n    <title>{}</title>\n",
+        time(programme.start),
+        time(programme.stop),
+        escape(channel),
+        escape(programme.title.as_str())
+    );
+    if !programme.desc.is_empty() {
+        xml.push_str(&format!(
+            "    <desc>{}</desc>\n",
+            escape(programme.desc.as_str())
+        ));
+    }
+    xml.push_str("  </programme>\n");
+    xml
+}
+
+/**
+ * Serve the playlist and EPG of the profile over http until stopped. The playlists are
+ * refreshed in the background as usual, and what's served is made again when they change
+ */
+pub async fn serve(mut gm: GrandMother, address: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|e| format!("Failed to listen on {}, {:?}", address, e))?;
+    let conf = gm.config.serve.clone();
+    let mut snapshot = Arc::new(Snapshot::new(&gm, &conf));
+    let relay = Arc::new(Relay::new());
+    let bound = listener
+        .local_addr()
+        .map_err(|e| format!("Failed to listen on {}, {:?}", address, e))?;
+    let tuner = Arc::new(Tuner::new(&conf, &bound));
+    println!(
+        "Serving {} entries on http://{}/playlist.m3u and the EPG on http://{}/epg.xml",
+        snapshot.len, address, address
+    );
+    println!(
+        "Media servers can add http://{} as an HDHomeRun tuner",
+        address
+    );
+
+    let mut refresher = BackgroundRefresher::new();
+    let mut refresh_check = time::interval(REFRESH_CHECK_INTERVAL);
+    let mut epg_loaded = Instant::now();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(
+                        stream,
+                        snapshot.clone(),
+                        relay.clone(),
+                        tuner.clone(),
+                    ));
+                }
+                Err(e) => println!("Failed to accept a connection, {:?}", e),
+            },
+            _ = refresh_check.tick() => {
+                let mut changed = !refresher.poll(&mut gm).await.is_empty();
+                if epg_loaded.elapsed() > EPG_RELOAD_INTERVAL {
+                    gm.reload_epg().await;
+                    epg_loaded = Instant::now();
+                    changed = true;
+                }
+                if changed {
+                    snapshot = Arc::new(Snapshot::new(&gm, &conf));
+                    println!(
+                        "{} Refreshed, serving {} entries",
+                        local_time(epg::now(), "%H:%M"),
+                        snapshot.len
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Lowercase names
+    pub headers: HashMap<String, String>,
+    /// Empty unless there is a Content-Length
+    pub body: String,
+}
+
+impl Request {
+    /**
+     * The host the client used to reach the server, for links back to it
+     */
+    pub fn host(&self) -> &str {
+        self.headers
+            .get("host")
+            .map(String::as_str)
+            .unwrap_or("localhost")
+    }
+
+    /**
+     * The decoded value of a parameter in the query string
+     */
+    pub fn query(&self, key: &str) -> Option<String> {
+        let url = Url::parse(&format!("http://localhost{}", self.path)).ok()?;
+        url.query_pairs()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.to_string())
+    }
+}
+
+pub enum Body {
+    Text(String),
+    /// Piped to the client as it arrives
+    Stream(reqwest::Response),
+}
+
+pub struct Response {
+    pub status: String,
+    /// Content-Type and such, Content-Length is added for text
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn ok(content_type: &str, body: String) -> Self {
+        Self {
+            status: "200 OK".to_owned(),
+            headers: vec![("Content-Type".to_owned(), content_type.to_owned())],
+            body: Body::Text(body),
+        }
+    }
+
+    pub fn error(status: &str) -> Self {
+        Self {
+            status: status.to_owned(),
+            headers: vec![(
+                "Content-Type".to_owned(),
+                "text/plain; charset=utf-8".to_owned(),
+            )],
+            body: Body::Text(format!("{}\n", status)),
+        }
+    }
+
+    pub fn json(status: &str, value: &serde_json::Value) -> Self {
+        Self {
+            status: status.to_owned(),
+            headers: vec![("Content-Type".to_owned(), "application/json".to_owned())],
+            body: Body::Text(value.to_string()),
+        }
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    snapshot: Arc<Snapshot>,
+    relay: Arc<Relay>,
+    tuner: Arc<Tuner>,
+) {
+    let request = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(status)) => {
+            let _ = write_response(&mut stream, Response::error(status), false).await;
+            return;
+        }
+        Err(_) => return,
+    };
+
+    let is_head = request.method == "HEAD";
+    let response = route(&request, &snapshot, &relay, &tuner).await;
+    let _ = write_response(&mut stream, response, !is_head).await;
+}
+
+async fn route(request: &Request, snapshot: &Snapshot, relay: &Relay, tuner: &Tuner) -> Response {
+    let path = request.path.split('?').next().unwrap_or_default();
+    // Plex asks the tuner to scan its channels with a POST
+    if path == "/lineup.post" {
+        return Response::ok("text/plain; charset=utf-8", String::new());
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::error("405 Method Not Allowed");
+    }
+    let json = "application/json";
+    match path {
+        "/playlist.m3u" | "/playlist.m3u8" => Response::ok(
+            "audio/x-mpegurl; charset=utf-8",
+            snapshot.playlist(request.host()),
+        ),
+        "/epg.xml" => Response::ok("application/xml; charset=utf-8", snapshot.epg.clone()),
+        "/discover.json" => Response::ok(json, tuner.discover(request.host())),
+        "/lineup.json" => Response::ok(json, tuner.lineup(snapshot, request.host())),
+        "/lineup_status.json" => Response::ok(json, tuner.lineup_status()),
+        "/device.xml" => Response::ok("application/xml", tuner.device_xml(request.host())),
+        "/relay/url" => {
+            let headers_of = |key: &str| snapshot.upstreams.get(key).map(|x| x.headers.clone());
+            relay.forward_signed(request, headers_of).await
+        }
+        _ => match path.strip_prefix("/relay/") {
+            Some(key) => match snapshot.upstreams.get(key) {
+                Some(upstream) => relay.forward(request, key, upstream).await,
+                None => Response::error("404 Not Found"),
+            },
+            None => Response::error("404 Not Found"),
+        },
+    }
+}
+
+/**
+ * Read the request line, headers and body. The error is the status to answer with
+ */
+pub async fn read_request(stream: &mut TcpStream) -> Result<Request, &'static str> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 2048];
+    let head_end = loop {
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|_| "400 Bad Request")?;
+        if read == 0 {
+            return Err("400 Bad Request");
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(end) = buffer.windows(4).position(|x| x == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > MAX_REQUEST_SIZE {
+            return Err("431 Request Header Fields Too Large");
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_end]);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Err("400 Bad Request"),
+    };
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_owned()))
+        .collect();
+
+    let body_start = head_end + 4;
+    let body_len: usize = match headers.get("content-length") {
+        Some(len) => len.parse().map_err(|_| "400 Bad Request")?,
+        None => 0,
+    };
+    if body_start + body_len > MAX_REQUEST_SIZE {
+        return Err("413 Payload Too Large");
+    }
+    while buffer.len() < body_start + body_len {
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|_| "400 Bad Request")?;
+        if read == 0 {
+            return Err("400 Bad Request");
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    let body = String::from_utf8_lossy(&buffer[body_start..body_start + body_len]).to_string();
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+pub async fn write_response(
+    stream: &mut TcpStream,
+    response: Response,
+    with_body: bool,
+) -> std::io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", response.status);
+    for (name, value) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Body::Text(text) = &response.body {
+        head.push_str(&format!("Content-Length: {}\r\n", text.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes()).await?;
+
+    if with_body {
+        match response.body {
+            Body::Text(text) => stream.write_all(text.as_bytes()).await?,
+            Body::Stream(mut upstream) => {
+                // Stops when either the upstream ends or the client hangs up
+                while let Ok(Some(bytes)) = upstream.chunk().await {
+                    stream.write_all(&bytes).await?;
+                }
+            }
+        }
+    }
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, rc::Rc};
+
+    use serde_json::Value;
+
+    use super::*;
+
+    fn entry(name: &str, tvg_id: &str, link: &str) -> M3u8 {
+        M3u8 {
+            tvg_id: tvg_id.to_owned(),
+            tvg_name: name.to_owned(),
+            tvg_logo: String::new(),
+            group_title: "News".to_owned(),
+            name: name.to_owned(),
+            link: Rc::new(link.to_owned()),
+            watched: false,
+            source: None,
+            catchup: None,
+            programmes: None,
+        }
+    }
+
+    /**
+     * A server on a free port of localhost with the entries, returns its address
+     */
+    async fn mock_server(served: &[M3u8]) -> SocketAddr {
+        let conf = ServeConf::default();
+        let snapshot = Arc::new(Snapshot::of(served, &conf, Identity::Url, |_| {
+            BTreeMap::new()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bound = listener.local_addr().unwrap();
+        let tuner = Arc::new(Tuner::new(&conf, &bound));
+        let relay = Arc::new(Relay::new());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(
+                    stream,
+                    snapshot.clone(),
+                    relay.clone(),
+                    tuner.clone(),
+                ));
+            }
+        });
+        bound
+    }
+
+    /**
+     * The body of a GET, sent as if the server was reached as `host`
+     */
+    async fn get(address: SocketAddr, path: &str, host: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        body.to_owned()
+    }
+
+    async fn get_json(address: SocketAddr, path: &str, host: &str) -> Value {
+        serde_json::from_str(&get(address, path, host).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn discovers_the_same_device_at_any_host() {
+        let address = mock_server(&[]).await;
+        let by_ip = get_json(address, "/discover.json", &address.to_string()).await;
+        let by_name = get_json(address, "/discover.json", "tv.local:5004").await;
+
+        assert_eq!(by_ip["DeviceID"], by_name["DeviceID"]);
+        assert_eq!(by_ip["DeviceID"].as_str().unwrap().len(), 8);
+        assert_eq!(by_ip["BaseURL"], format!("http://{}", address));
+        assert_eq!(by_name["BaseURL"], "http://tv.local:5004");
+        assert_eq!(by_name["LineupURL"], "http://tv.local:5004/lineup.json");
+
+        let other = mock_server(&[]).await;
+        let other = get_json(other, "/discover.json", "tv.local:5004").await;
+        assert_ne!(other["DeviceID"], by_name["DeviceID"]);
+    }
+
+    #[tokio::test]
+    async fn numbers_the_lineup_like_the_playlist() {
+        let served = [
+            entry("News One", "news.one", "http://example.com/news.ts"),
+            entry("Sports, HD", "sports", "http://example.com/sports.ts"),
+            entry("Weather", "", "http://example.com/weather.ts"),
+        ];
+        let address = mock_server(&served).await;
+        let host = "tv.local:5004";
+        let playlist = get(address, "/playlist.m3u", host).await;
+        let lineup = get_json(address, "/lineup.json", host).await;
+
+        // The number and name of every channel in the playlist
+        let numbered: Vec<(String, String)> = playlist
+            .lines()
+            .filter_map(|line| {
+                let number = line.split("tvg-chno=\"").nth(1)?.split('"').next()?;
+                let name = line.rsplit(',').next()?;
+                Some((number.to_owned(), name.to_owned()))
+            })
+            .collect();
+        let guide_numbers: Vec<&str> = lineup
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["GuideNumber"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            numbered,
+            [
+                ("1".to_owned(), "News One".to_owned()),
+                ("2".to_owned(), "Sports HD".to_owned()),
+                ("3".to_owned(), "Weather".to_owned()),
+            ]
+        );
+        let numbers: Vec<&str> = numbered.iter().map(|(number, _)| number.as_str()).collect();
+        assert_eq!(guide_numbers, numbers);
+        assert_eq!(lineup[0]["GuideName"], "News One");
+        assert!(lineup[0]["URL"]
+            .as_str()
+            .unwrap()
+            .starts_with("http://tv.local:5004/relay/"));
+    }
+}
@@ -0,0 +1,668 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures_util::StreamExt;
+use indicatif::HumanBytes;
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Borders, LineGauge, List, ListItem, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+use tokio::sync::mpsc;
+
+#[allow(unused_imports)]
+use crate::GetM3u8;
+use crate::{
+    downloadqueue::Finished, get_mut_ref, BackgroundRefresher, DownloadQueue, DownloadStatus,
+    Favorites, GrandMother, M3u8, Player, WatchedFind,
+};
+
+type Error = String;
+
+/// How long to wait for a key before looking at mpv and the downloads again
+const TICK: Duration = Duration::from_millis(200);
+/// How often to look for playlists that are due for a refresh
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How many downloads the downloads panel shows at most, the latest ones
+const SHOWN_DOWNLOADS: usize = 4;
+/// The entries in the sidebar before the groups
+const FAVORITES_ROW: usize = 0;
+const ALL_ROW: usize = 1;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Focus {
+    Search,
+    Groups,
+    Results,
+}
+
+/// What takes long enough to tell the user about it first
+enum Slow {
+    Refresh,
+    SwitchMode,
+}
+
+/**
+ * The state of the terminal ui. The results borrow from the parser, so they are cleared
+ * before anything that replaces it, the same way as in the prompt
+ */
+struct Tui<'a> {
+    gm: &'a GrandMother,
+    in_online: bool,
+    player: Player,
+    queue: DownloadQueue,
+    favorites: Favorites,
+    fullscreen: bool,
+    focus: Focus,
+    query: String,
+    /// The name of every group and how many entries it has, in the order of the playlists
+    groups: Vec<(String, usize)>,
+    group_state: ListState,
+    results: Vec<&'a M3u8>,
+    selected: usize,
+    /// The first result that is shown
+    offset: usize,
+    /// How many results fit on the screen, for page up and page down
+    page: usize,
+    message: String,
+    slow: Option<Slow>,
+    quit: bool,
+}
+
+/**
+ * Browse, play and download the entries in a full-screen terminal ui, until quit
+ */
+pub async fn run_tui(gm: GrandMother, in_online: bool) -> Result<(), Error> {
+    let (queue, mut finished) = DownloadQueue::new();
+    let mut tui = Tui::new(&gm, in_online, queue);
+    let mut terminal =
+        ratatui::try_init().map_err(|e| format!("Failed to set up the terminal, {:?}", e))?;
+    let result = tui.run(&mut terminal, &mut finished).await;
+    ratatui::restore();
+
+    if let Some(m3u8) = tui.player.stop(&gm) {
+        println!("Stopped playing {}", m3u8.name);
+    }
+    let pending = tui
+        .queue
+        .downloads()
+        .iter()
+        .filter(|x| x.is_pending())
+        .count();
+    if pending > 0 {
+        println!("Cancelled {} downloads that weren't done", pending);
+    }
+    gm.save_watched();
+    result
+}
+
+impl<'a> Tui<'a> {
+    fn new(gm: &'a GrandMother, in_online: bool, queue: DownloadQueue) -> Self {
+        let mut tui = Self {
+            gm,
+            in_online,
+            player: Player::new(),
+            queue,
+            favorites: Favorites::new(&gm.config.favorites_path),
+            fullscreen: false,
+            focus: Focus::Search,
+            query: String::new(),
+            groups: Vec::new(),
+            group_state: ListState::default().with_selected(Some(ALL_ROW)),
+            results: Vec::new(),
+            selected: 0,
+            offset: 0,
+            page: 10,
+            message: "Type to search, press ? for the keys".to_owned(),
+            slow: None,
+            quit: false,
+        };
+        tui.load_groups();
+        tui.search();
+        tui
+    }
+
+    async fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        finished: &mut mpsc::UnboundedReceiver<Finished>,
+    ) -> Result<(), Error> {
+        let mut refresher = BackgroundRefresher::new();
+        let mut last_refresh_check = Instant::now();
+        // Read the terminal without blocking, so the downloads keep going while waiting for keys
+        let mut events = EventStream::new();
+        while !self.quit {
+            terminal
+                .draw(|frame| self.draw(frame))
+                .map_err(|e| format!("Failed to draw the terminal ui, {:?}", e))?;
+
+            if let Some(slow) = self.slow.take() {
+                match slow {
+                    Slow::Refresh => self.refresh().await,
+                    Slow::SwitchMode => self.switch_mode().await,
+                }
+                // Loading playlists may print about sources that failed
+                let _ = terminal.clear();
+                continue;
+            }
+
+            let event = tokio::select! {
+                event = events.next() => event,
+                _ = tokio::time::sleep(TICK) => None,
+            };
+            match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    self.handle_key(key)
+                }
+                Some(Err(e)) => return Err(format!("Failed to read from the terminal, {:?}", e)),
+                _ => {}
+            }
+
+            self.player.poll(self.gm);
+            while let Ok(done) = finished.try_recv() {
+                self.finish_download(done);
+            }
+            if last_refresh_check.elapsed() >= REFRESH_CHECK_INTERVAL {
+                last_refresh_check = Instant::now();
+                self.poll_refresher(&mut refresher).await;
+                let _ = terminal.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            self.quit = true;
+            return;
+        }
+        if self.focus == Focus::Search {
+            match key.code {
+                KeyCode::Char(c) => {
+                    self.query.push(c);
+                    self.search();
+                }
+                KeyCode::Backspace => {
+                    self.query.pop();
+                    self.search();
+                }
+                KeyCode::Esc | KeyCode::Enter | KeyCode::Down | KeyCode::Tab => {
+                    self.focus = Focus::Results
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match key.code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Char('/') => self.focus = Focus::Search,
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.focus = match self.focus {
+                    Focus::Groups => Focus::Results,
+                    _ => Focus::Groups,
+                }
+            }
+            KeyCode::Left => self.focus = Focus::Groups,
+            KeyCode::Right => self.focus = Focus::Results,
+            KeyCode::Up | KeyCode::Char('k') => self.step(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.step(1),
+            KeyCode::PageUp => self.step(-(self.page as isize)),
+            KeyCode::PageDown => self.step(self.page as isize),
+            KeyCode::Home | KeyCode::Char('g') => self.step(isize::MIN / 2),
+            KeyCode::End | KeyCode::Char('G') => self.step(isize::MAX / 2),
+            KeyCode::Enter if self.focus == Focus::Groups => self.focus = Focus::Results,
+            KeyCode::Enter | KeyCode::Char('p') => self.play(),
+            KeyCode::Char('s') => self.stop(),
+            KeyCode::Char('d') => self.download(false),
+            KeyCode::Char('o') => self.download(true),
+            KeyCode::Char('f') => self.toggle_favorite(),
+            KeyCode::Char('F') => {
+                self.fullscreen = !self.fullscreen;
+                self.message = format!(
+                    "Toggled mpv to {}launch in fullscreen",
+                    if self.fullscreen { "" } else { "not " }
+                );
+            }
+            KeyCode::Char('r') => {
+                self.message = "Refreshing the playlists...".to_owned();
+                self.slow = Some(Slow::Refresh);
+            }
+            KeyCode::Char('m') => {
+                self.message = format!(
+                    "Switching to {} mode...",
+                    if self.in_online { "offline" } else { "online" }
+                );
+                self.slow = Some(Slow::SwitchMode);
+            }
+            KeyCode::Char('?') => {
+                self.message = "/ search, tab switch list, enter/p play, s stop, d download, \
+                                o offline, f favorite, F fullscreen, m mode, r refresh, q quit"
+                    .to_owned()
+            }
+            _ => {}
+        }
+    }
+
+    /**
+     * Move the selection of the focused list, and show the group that was moved to
+     */
+    fn step(&mut self, delta: isize) {
+        let (current, len) = match self.focus {
+            Focus::Groups => (
+                self.group_state.selected().unwrap_or(ALL_ROW),
+                self.groups.len() + 2,
+            ),
+            _ => (self.selected, self.results.len()),
+        };
+        if len == 0 {
+            return;
+        }
+        let moved = current.saturating_add_signed(delta).min(len - 1);
+        match self.focus {
+            Focus::Groups => {
+                if moved != current {
+                    self.group_state.select(Some(moved));
+                    self.search();
+                }
+            }
+            _ => self.selected = moved,
+        }
+    }
+
+    fn selected_entry(&self) -> Option<&'a M3u8> {
+        self.results.get(self.selected).copied()
+    }
+
+    /**
+     * The groups of the loaded entries. The selected group is kept if it's still there
+     */
+    fn load_groups(&mut self) {
+        let previous = self
+            .group_state
+            .selected()
+            .and_then(|idx| idx.checked_sub(2))
+            .and_then(|idx| self.groups.get(idx))
+            .map(|(name, _)| name.clone());
+
+        let mut groups: Vec<(String, usize)> = Vec::new();
+        for m3u8 in self.gm.parser.get_m3u8() {
+            match groups
+                .iter_mut()
+                .find(|(name, _)| *name == m3u8.group_title)
+            {
+                Some((_, count)) => *count += 1,
+                None => groups.push((m3u8.group_title.clone(), 1)),
+            }
+        }
+        self.groups = groups;
+
+        if let Some(previous) = previous {
+            let row = self
+                .groups
+                .iter()
+                .position(|(name, _)| *name == previous)
+                .map(|idx| idx + 2)
+                .unwrap_or(ALL_ROW);
+            self.group_state.select(Some(row));
+        }
+    }
+
+    /**
+     * The entries with names containing the query, in the selected group
+     */
+    fn search(&mut self) {
+        let identity = self.gm.config.identity;
+        let mut results = self.gm.parser.find(self.query.trim());
+        match self.group_state.selected() {
+            Some(FAVORITES_ROW) => {
+                results.retain(|m3u8| self.favorites.contains(&m3u8.id(identity)))
+            }
+            Some(ALL_ROW) | None => {}
+            Some(idx) => {
+                if let Some((group, _)) = self.groups.get(idx - 2) {
+                    results.retain(|m3u8| m3u8.group_title == *group)
+                }
+            }
+        }
+        self.results = results;
+        self.selected = 0;
+        self.offset = 0;
+    }
+
+    fn play(&mut self) {
+        let m3u8 = match self.selected_entry() {
+            Some(m3u8) => m3u8,
+            None => return,
+        };
+        self.message = match self.player.play(self.gm, m3u8, self.fullscreen) {
+            Ok(()) => format!("Playing {}", m3u8.name),
+            Err(e) => e,
+        };
+    }
+
+    fn stop(&mut self) {
+        self.message = match self.player.stop(self.gm) {
+            Some(m3u8) => format!("Stopped playing {}", m3u8.name),
+            None => "Nothing is playing".to_owned(),
+        };
+    }
+
+    /**
+     * Queue the selected entry, to the current directory or for offline use
+     */
+    fn download(&mut self, offline: bool) {
+        let m3u8 = match self.selected_entry() {
+            Some(m3u8) => m3u8,
+            None => return,
+        };
+        if !self.in_online {
+            self.message = "Only entries that are online can be downloaded".to_owned();
+            return;
+        }
+        self.queue.add(self.gm, m3u8.clone(), offline);
+        self.message = format!(
+            "Queued {}{}",
+            m3u8.name,
+            if offline { " for offline use" } else { "" }
+        );
+    }
+
+    /**
+     * In offline mode the results borrow from the offline catalog, which finishing a download
+     * reloads and adds to, so they are cleared first and searched again after, in a catalog that
+     * has the new download
+     */
+    fn finish_download(&mut self, finished: Finished) {
+        if !self.in_online {
+            self.results.clear();
+        }
+        let message = match self.queue.finish(self.gm, finished) {
+            Some(download) => match &download.status {
+                DownloadStatus::Done { .. } => Some(format!("Downloaded {}", download.entry.name)),
+                DownloadStatus::Failed { error } => Some(format!(
+                    "Failed to download {}, {}",
+                    download.entry.name, error
+                )),
+                _ => None,
+            },
+            None => None,
+        };
+        if let Some(message) = message {
+            self.message = message;
+        }
+        if !self.in_online {
+            unsafe { get_mut_ref(self.gm) }.demote_to_offline();
+            let selected = self.selected;
+            self.reload();
+            self.selected = selected.min(self.results.len().saturating_sub(1));
+        }
+    }
+
+    fn toggle_favorite(&mut self) {
+        let m3u8 = match self.selected_entry() {
+            Some(m3u8) => m3u8,
+            None => return,
+        };
+        let id = m3u8.id(self.gm.config.identity);
+        self.message = match self.favorites.toggle(&id) {
+            Ok(true) => format!("Added {} to the favorites", m3u8.name),
+            Ok(false) => format!("Removed {} from the favorites", m3u8.name),
+            Err(e) => e,
+        };
+        if self.group_state.selected() == Some(FAVORITES_ROW) {
+            let selected = self.selected;
+            self.search();
+            self.selected = selected.min(self.results.len().saturating_sub(1));
+        }
+    }
+
+    async fn refresh(&mut self) {
+        self.results.clear();
+        self.message = match unsafe { get_mut_ref(self.gm) }.refresh().await {
+            Ok(()) => {
+                let (added, removed) = self
+                    .gm
+                    .last_diff
+                    .as_ref()
+                    .map(|diff| (diff.added.len(), diff.removed.len()))
+                    .unwrap_or_default();
+                format!("Refreshed, {} added and {} removed", added, removed)
+            }
+            Err(e) => format!("Cannot refresh, {}", e),
+        };
+        self.reload();
+    }
+
+    async fn switch_mode(&mut self) {
+        self.results.clear();
+        let gm = unsafe { get_mut_ref(self.gm) };
+        self.message = if self.in_online {
+            gm.demote_to_offline();
+            self.in_online = false;
+            "Switched to offline mode".to_owned()
+        } else {
+            match gm.promote_to_online().await {
+                Ok(()) => {
+                    self.in_online = true;
+                    "Switched to online mode".to_owned()
+                }
+                Err(e) => format!("Failed to switch to online mode, {}", e),
+            }
+        };
+        self.reload();
+    }
+
+    async fn poll_refresher(&mut self, refresher: &mut BackgroundRefresher) {
+        self.results.clear();
+        let refreshed = refresher.poll(unsafe { get_mut_ref(self.gm) }).await;
+        if !refreshed.is_empty() {
+            let refreshed: Vec<&str> = refreshed.iter().map(|x| x.as_str()).collect();
+            self.message = format!("Refreshed {} in the background", refreshed.join(", "));
+        }
+        let selected = self.selected;
+        self.reload();
+        self.selected = selected.min(self.results.len().saturating_sub(1));
+    }
+
+    /**
+     * Show the entries of a new parser
+     */
+    fn reload(&mut self) {
+        self.load_groups();
+        self.search();
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let shown_downloads = self.queue.downloads().len().min(SHOWN_DOWNLOADS);
+        let downloads_height = if shown_downloads == 0 {
+            0
+        } else {
+            shown_downloads as u16 + 2
+        };
+        let [search_area, body, downloads_area, status_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(0),
+            Constraint::Length(downloads_height),
+            Constraint::Length(2),
+        ])
+        .areas(frame.area());
+        let [groups_area, results_area] =
+            Layout::horizontal([Constraint::Length(30), Constraint::Min(0)]).areas(body);
+
+        self.draw_search(frame, search_area);
+        self.draw_groups(frame, groups_area);
+        self.draw_results(frame, results_area);
+        if shown_downloads > 0 {
+            self.draw_downloads(frame, downloads_area);
+        }
+        self.draw_status(frame, status_area);
+    }
+
+    fn block(&self, title: String, focus: Focus) -> Block<'static> {
+        let block = Block::default().borders(Borders::ALL).title(title);
+        if self.focus == focus {
+            block.border_style(Style::default().fg(Color::Magenta))
+        } else {
+            block
+        }
+    }
+
+    fn draw_search(&self, frame: &mut Frame, area: Rect) {
+        let search = Paragraph::new(self.query.as_str())
+            .block(self.block(" Search by name ".to_owned(), Focus::Search));
+        frame.render_widget(search, area);
+        if self.focus == Focus::Search {
+            let x = area.x + 1 + self.query.chars().count() as u16;
+            frame.set_cursor_position((x.min(area.right().saturating_sub(2)), area.y + 1));
+        }
+    }
+
+    fn draw_groups(&mut self, frame: &mut Frame, area: Rect) {
+        let all = self.gm.parser.get_m3u8().len();
+        let mut items = vec![
+            ListItem::new("★ Favorites"),
+            ListItem::new(format!("All ({})", all)),
+        ];
+        items.extend(self.groups.iter().map(|(name, count)| {
+            let name = if name.is_empty() {
+                "Without group"
+            } else {
+                name
+            };
+            ListItem::new(format!("{} ({})", name, count))
+        }));
+        let list = List::new(items)
+            .block(self.block(" Groups ".to_owned(), Focus::Groups))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.group_state);
+    }
+
+    /**
+     * Only the results that fit are turned into list items, as there may be many thousands
+     */
+    fn draw_results(&mut self, frame: &mut Frame, area: Rect) {
+        let identity = self.gm.config.identity;
+        let playing = self.player.playing().map(|m3u8| m3u8.id(identity));
+        self.page = area.height.saturating_sub(2).max(1) as usize;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected >= self.offset + self.page {
+            self.offset = self.selected + 1 - self.page;
+        }
+
+        let items: Vec<ListItem> = self
+            .results
+            .iter()
+            .skip(self.offset)
+            .take(self.page)
+            .map(|m3u8| {
+                let id = m3u8.id(identity);
+                let mut spans = vec![
+                    Span::raw(if playing.as_ref() == Some(&id) {
+                        "▶ "
+                    } else if m3u8.watched {
+                        "✓ "
+                    } else {
+                        "  "
+                    }),
+                    Span::raw(if self.favorites.contains(&id) {
+                        "★ "
+                    } else {
+                        "  "
+                    }),
+                    Span::raw(m3u8.name.clone()).bold(),
+                    Span::raw(format!("  {}", m3u8.group_title)).dark_gray(),
+                ];
+                if let (Some(now), _) = m3u8.now_and_next() {
+                    spans.push(Span::raw(format!("  now: {}", now.title)).cyan());
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
+
+        let title = format!(" Results ({}) ", self.results.len());
+        let list = List::new(items)
+            .block(self.block(title, Focus::Results))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default();
+        if !self.results.is_empty() {
+            state.select(Some(self.selected - self.offset));
+        }
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    /**
+     * The latest downloads, with how far along they are
+     */
+    fn draw_downloads(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::default().borders(Borders::ALL).title(" Downloads ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let downloads = self.queue.downloads();
+        let latest = &downloads[downloads.len().saturating_sub(SHOWN_DOWNLOADS)..];
+        for (row, download) in latest.iter().enumerate() {
+            let line = Rect {
+                y: inner.y + row as u16,
+                height: 1,
+                ..inner
+            };
+            let downloaded = download.progress.downloaded.load(Ordering::Relaxed);
+            let total = download.progress.total.load(Ordering::Relaxed);
+            let (ratio, state, color) = match &download.status {
+                DownloadStatus::Queued => (0.0, "queued".to_owned(), Color::Gray),
+                DownloadStatus::Measuring => (0.0, "measuring".to_owned(), Color::Gray),
+                DownloadStatus::Downloading if total > 0 => (
+                    (downloaded as f64 / total as f64).min(1.0),
+                    format!("{} / {}", HumanBytes(downloaded), HumanBytes(total)),
+                    Color::Magenta,
+                ),
+                DownloadStatus::Downloading => {
+                    (0.0, format!("{}", HumanBytes(downloaded)), Color::Magenta)
+                }
+                DownloadStatus::Done { size } => {
+                    (1.0, format!("done, {}", HumanBytes(*size)), Color::Green)
+                }
+                DownloadStatus::Failed { error } => (0.0, error.clone(), Color::Red),
+            };
+            let gauge = LineGauge::default()
+                .label(format!("{}  {}", download.entry.name, state))
+                .ratio(ratio)
+                .filled_style(Style::default().fg(color))
+                .unfilled_style(Style::default().fg(Color::DarkGray));
+            frame.render_widget(gauge, line);
+        }
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let mode = if self.in_online {
+            Span::raw(" ONLINE ").black().on_green()
+        } else {
+            Span::raw(" OFFLINE ").black().on_yellow()
+        };
+        let mut spans = vec![
+            mode,
+            Span::raw(format!(" {} ", self.gm.config.active_profile)).bold(),
+        ];
+        if let Some(m3u8) = self.player.playing() {
+            spans.push(Span::raw(format!("▶ {} ", m3u8.name)).magenta());
+        }
+        if self.fullscreen {
+            spans.push(Span::raw("[fullscreen] ").dark_gray());
+        }
+        spans.push(Span::raw(self.message.clone()));
+
+        let help = Line::from(
+            " / search  tab lists  enter play  s stop  d download  o offline  f favorite  \
+             m mode  r refresh  q quit",
+        )
+        .dark_gray();
+        frame.render_widget(Paragraph::new(vec![Line::from(spans), help]), area);
+    }
+}
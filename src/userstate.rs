@@ -0,0 +1,212 @@
+use std::{collections::BTreeMap, path::Path, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    watchhistory::{unix_secs, HistoryEntry, WatchHistory},
+    Conf, Configuration, Favorites, OfflineEntry,
+};
+
+type Error = String;
+
+/// The version of the export format that this build writes
+pub const USER_STATE_VERSION: u32 = 1;
+
+/**
+ * Everything that the user has set up or done, bundled together to be moved
+ * to another installation
+ */
+#[derive(Serialize, Deserialize)]
+pub struct UserState {
+    pub version: u32,
+    /// Seconds since the unix epoch
+    pub exported_at: u64,
+    /// When the configfile was last written to, the newest settings win when importing
+    pub config_modified: u64,
+    /// Kept as json so that configs from older versions can be migrated
+    pub config: serde_json::Map<String, serde_json::Value>,
+    /// Ids of the watched entries
+    #[serde(default)]
+    pub watched: Vec<String>,
+    /// The offline catalog, the files themselves aren't included
+    #[serde(default)]
+    pub offline: Vec<OfflineEntry>,
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+    /// Ids of the favorites from the terminal ui
+    #[serde(default)]
+    pub favorites: Vec<String>,
+}
+
+pub struct ImportReport {
+    /// Whether the imported settings were newer and replaced the local ones
+    pub settings_replaced: bool,
+    pub sources_added: Vec<String>,
+    pub watched_added: usize,
+    pub offline_added: usize,
+    /// Imported offline entries whose files aren't on this machine
+    pub offline_missing: usize,
+    pub history_added: usize,
+    pub favorites_added: usize,
+    /// Where the previous configfile was saved
+    pub config_backup: Option<String>,
+}
+
+impl UserState {
+    /**
+     * Read an export, making sure that it's from a version of ilovetv that isn't newer
+     */
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let json: serde_json::Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+        let version = json
+            .get("version")
+            .and_then(|x| x.as_u64())
+            .unwrap_or_default();
+        if version > USER_STATE_VERSION as u64 {
+            return Err(format!(
+                "The export is of version {}, but this version of ilovetv only understands up to {}",
+                version, USER_STATE_VERSION
+            ));
+        }
+        serde_json::from_value(json).map_err(|e| e.to_string())
+    }
+
+    pub fn export(config: &Configuration, include_secrets: bool) -> Result<Self, Error> {
+        let mut conf = config.conf.clone();
+        if !include_secrets {
+            conf.api.token = None;
+            for source in conf.sources.iter_mut() {
+                if let Some(login) = source.xtream.as_mut() {
+                    login.password.clear();
+                }
+            }
+        }
+        let conf = match serde_json::to_value(&conf) {
+            Ok(serde_json::Value::Object(conf)) => conf,
+            Ok(_) => return Err("The config couldn't be exported".to_owned()),
+            Err(e) => return Err(format!("The config couldn't be exported, {}", e)),
+        };
+
+        Ok(Self {
+            version: USER_STATE_VERSION,
+            exported_at: unix_secs(SystemTime::now()),
+            config_modified: config.config_modified().map(unix_secs).unwrap_or_default(),
+            config: conf,
+            watched: config.seen_links.clone(),
+            offline: config.offlinefile_content.to_vec(),
+            history: WatchHistory::new(&config.watch_history_path)
+                .entries()
+                .to_vec(),
+            favorites: Favorites::new(&config.favorites_path)
+                .ids()
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /**
+     * Merge into the local state. Watched entries, offline entries, history, favorites
+     * and sources are combined, while the newest config wins for everything else
+     */
+    pub fn import(self, config: &Configuration) -> Result<ImportReport, Error> {
+        let imported = Conf::from_json(self.config)
+            .map_err(|e| format!("The exported config is invalid\n{}", e))?;
+
+        let local_modified = config.config_modified().map(unix_secs).unwrap_or_default();
+        let settings_replaced = self.config_modified > local_modified;
+        let (newer, older) = if settings_replaced {
+            (imported, &config.conf)
+        } else {
+            (config.conf.clone(), &imported)
+        };
+        let merged = merge_conf(newer, older);
+        let sources_added: Vec<String> = merged
+            .sources
+            .iter()
+            .filter(|source| !config.sources.iter().any(|x| x.name == source.name))
+            .map(|source| source.name.to_string())
+            .collect();
+        let config_backup = config.replace_conf_ugly(merged)?;
+
+        let mut report = ImportReport {
+            settings_replaced,
+            sources_added,
+            watched_added: 0,
+            offline_added: 0,
+            offline_missing: 0,
+            history_added: 0,
+            favorites_added: 0,
+            config_backup: Some(config_backup.display().to_string()),
+        };
+
+        let mut watched = config.seen_links.clone();
+        for id in self.watched {
+            if !watched.contains(&id) {
+                watched.push(id);
+                report.watched_added += 1;
+            }
+        }
+        config.set_seen_links_ugly(watched);
+        config
+            .write_seen_links()
+            .map_err(|e| format!("Failed to write watched links {:?}", e))?;
+
+        let identity = config.identity;
+        for entry in self.offline {
+            let id = entry.id(identity);
+            if config
+                .offlinefile_content
+                .iter()
+                .any(|x| x.id(identity) == id)
+            {
+                continue;
+            }
+            if !Path::new(&*entry.path).exists() {
+                report.offline_missing += 1;
+            }
+            config.push_offlinefile_ugly(entry);
+            report.offline_added += 1;
+        }
+        config
+            .write_datafile()
+            .map_err(|e| format!("Failed to write the offline catalog {:?}", e))?;
+
+        report.history_added = WatchHistory::new(&config.watch_history_path).merge(self.history)?;
+        report.favorites_added = Favorites::new(&config.favorites_path).merge(self.favorites)?;
+
+        Ok(report)
+    }
+}
+
+/**
+ * Settings come from the newer config, and sources and profiles that only
+ * are in the older one are added
+ */
+fn merge_conf(newer: Conf, older: &Conf) -> Conf {
+    let mut merged = newer;
+    // An export without secrets keeps the ones that are already here
+    if merged.api.token.is_none() {
+        merged.api.token = older.api.token.clone();
+    }
+    for source in merged.sources.iter_mut() {
+        let older_login = older
+            .sources
+            .iter()
+            .find(|x| x.name == source.name)
+            .and_then(|x| x.xtream.as_ref());
+        if let (Some(login), Some(older_login)) = (source.xtream.as_mut(), older_login) {
+            if login.password.is_empty() && login.username == older_login.username {
+                login.password = older_login.password.clone();
+            }
+        }
+    }
+    for source in older.sources.iter() {
+        if !merged.sources.iter().any(|x| x.name == source.name) {
+            merged.sources.push(source.clone());
+        }
+    }
+    let mut profiles: BTreeMap<String, Vec<String>> = older.profiles.clone();
+    profiles.extend(merged.profiles);
+    merged.profiles = profiles;
+    merged
+}
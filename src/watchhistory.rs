@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{m3u8::Identity, M3u8};
+
+type Error = String;
+
+/**
+ * One time that an entry was played
+ */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    m3u8: M3u8,
+    /// Seconds since the unix epoch when the player was started
+    pub started: u64,
+    /// For how long the player was open, in seconds
+    pub duration: u64,
+    /// Whether it was played from the offline store
+    pub offline: bool,
+}
+
+impl HistoryEntry {
+    pub fn new(m3u8: &M3u8, started: SystemTime, offline: bool) -> Self {
+        let mut m3u8 = m3u8.clone();
+        m3u8.watched = true;
+        Self {
+            m3u8,
+            started: unix_secs(started),
+            duration: started.elapsed().map(|x| x.as_secs()).unwrap_or_default(),
+            offline,
+        }
+    }
+
+    pub fn mode(&self) -> &'static str {
+        if self.offline {
+            "offline"
+        } else {
+            "online"
+        }
+    }
+
+    pub fn local_time(&self) -> String {
+        chrono::DateTime::from_timestamp(self.started as i64, 0)
+            .map(|x| {
+                x.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Deref for HistoryEntry {
+    type Target = M3u8;
+
+    fn deref(&self) -> &Self::Target {
+        &self.m3u8
+    }
+}
+
+/**
+ * How much something was watched, either a channel or a group
+ */
+pub struct Tally {
+    pub name: String,
+    pub plays: usize,
+    pub duration: Duration,
+}
+
+pub struct WatchStats {
+    pub plays: usize,
+    pub duration: Duration,
+    /// Most watched first
+    pub channels: Vec<Tally>,
+    /// Most watched first
+    pub groups: Vec<Tally>,
+}
+
+/**
+ * Everything that has been played, stored in the data dir
+ */
+pub struct WatchHistory {
+    path: PathBuf,
+    /// Oldest first
+    entries: Vec<HistoryEntry>,
+}
+
+impl WatchHistory {
+    pub fn new(path: &Path) -> Self {
+        let entries = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Self {
+            path: path.to_path_buf(),
+            entries,
+        }
+    }
+
+    /**
+     * Oldest first
+     */
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    /**
+     * Add plays from another history, such as an imported one, skipping those that
+     * are already in it. Returns how many were added
+     */
+    pub fn merge(&mut self, entries: Vec<HistoryEntry>) -> Result<usize, Error> {
+        let before = self.entries.len();
+        for entry in entries {
+            let exists = self
+                .entries
+                .iter()
+                .any(|x| x.started == entry.started && x.link == entry.link);
+            if !exists {
+                self.entries.push(entry);
+            }
+        }
+        let added = self.entries.len() - before;
+        if added > 0 {
+            self.entries.sort_by_key(|entry| entry.started);
+            self.save()?;
+        }
+        Ok(added)
+    }
+
+    pub fn record(&mut self, entry: HistoryEntry) -> Result<(), Error> {
+        self.entries.push(entry);
+        self.save()
+    }
+
+    /**
+     * The latest plays, newest first
+     */
+    pub fn recent(&self, limit: usize) -> Vec<&HistoryEntry> {
+        self.entries.iter().rev().take(limit).collect()
+    }
+
+    /**
+     * When each id was last watched, as when its latest play ended
+     */
+    pub fn last_watched(&self, identity: Identity) -> HashMap<String, SystemTime> {
+        let mut last_watched: HashMap<String, SystemTime> = HashMap::new();
+        for entry in self.entries.iter() {
+            let ended = UNIX_EPOCH + Duration::from_secs(entry.started + entry.duration);
+            let latest = last_watched.entry(entry.id(identity)).or_insert(ended);
+            *latest = (*latest).max(ended);
+        }
+        last_watched
+    }
+
+    /**
+     * The ids of what has been played, newest first and without duplicates
+     */
+    pub fn recent_ids(&self, identity: Identity) -> Vec<String> {
+        let mut ids: Vec<String> = Vec::new();
+        for entry in self.entries.iter().rev() {
+            let id = entry.id(identity);
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        ids
+    }
+
+    /**
+     * Forget the plays that started longer ago than `older_than`. Returns how many were removed
+     */
+    pub fn prune(&mut self, older_than: Duration) -> Result<usize, Error> {
+        let oldest = unix_secs(SystemTime::now()).saturating_sub(older_than.as_secs());
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.started >= oldest);
+        self.save()?;
+        Ok(before - self.entries.len())
+    }
+
+    pub fn clear(&mut self) -> Result<usize, Error> {
+        let removed = self.entries.len();
+        self.entries.clear();
+        self.save()?;
+        Ok(removed)
+    }
+
+    /**
+     * Totals for all plays, where channels are told apart by the identity strategy
+     */
+    pub fn stats(&self, identity: Identity) -> WatchStats {
+        let mut channels: HashMap<String, Tally> = HashMap::new();
+        let mut groups: HashMap<&str, Tally> = HashMap::new();
+        for entry in self.entries.iter() {
+            let duration = Duration::from_secs(entry.duration);
+            let channel = channels.entry(entry.id(identity)).or_insert(Tally {
+                name: String::new(),
+                plays: 0,
+                duration: Duration::ZERO,
+            });
+            // Entries are oldest first, so the latest name is kept
+            channel.name = entry.name.clone();
+            channel.plays += 1;
+            channel.duration += duration;
+
+            let group_title = match entry.group_title.as_str() {
+                "" => "(no group)",
+                group_title => group_title,
+            };
+            let group = groups.entry(group_title).or_insert(Tally {
+                name: group_title.to_owned(),
+                plays: 0,
+                duration: Duration::ZERO,
+            });
+            group.plays += 1;
+            group.duration += duration;
+        }
+
+        let most_watched_first = |tallies: Vec<Tally>| {
+            let mut tallies = tallies;
+            tallies.sort_by(|a, b| {
+                b.duration
+                    .cmp(&a.duration)
+                    .then(b.plays.cmp(&a.plays))
+                    .then(a.name.cmp(&b.name))
+            });
+            tallies
+        };
+        WatchStats {
+            plays: self.entries.len(),
+            duration: self
+                .entries
+                .iter()
+                .map(|entry| Duration::from_secs(entry.duration))
+                .sum(),
+            channels: most_watched_first(channels.into_values().collect()),
+            groups: most_watched_first(groups.into_values().collect()),
+        }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let content = serde_json::to_string(&self.entries).map_err(|e| e.to_string())?;
+        fs::write(&self.path, content)
+            .map_err(|e| format!("Failed to write '{}', {:?}", self.path.display(), e))
+    }
+}
+
+/**
+ * Such as "2h 05m" or "12m"
+ */
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    if minutes < 60 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}h {:02}m", minutes / 60, minutes % 60)
+    }
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,647 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use futures_util::{stream, StreamExt};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    downloader::{Fetched, Validators},
+    Catchup, CatchupKind, M3u8,
+};
+
+type Error = String;
+
+/// How many series to get the episodes of at the same time
+const CONCURRENT_SERIES_REQUESTS: usize = 8;
+
+/**
+ * The account of an Xtream Codes panel. A source with this set is read through
+ * player_api.php instead of being downloaded as a playlist
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct XtreamLogin {
+    pub username: String,
+    pub password: String,
+    /// Getting the episodes takes one request per series, which is slow on big panels
+    #[serde(default = "XtreamLogin::default_series")]
+    pub series: bool,
+}
+
+impl XtreamLogin {
+    fn default_series() -> bool {
+        true
+    }
+}
+
+/**
+ * The live channels, movies and episodes of a panel. This is what is cached for
+ * Xtream Codes sources, as json, instead of a playlist
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct XtreamPlaylist {
+    /// The xmltv.php of the panel
+    pub epg_url: String,
+    pub entries: Vec<XtreamEntry>,
+}
+
+impl XtreamPlaylist {
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        serde_json::from_str(content)
+            .map_err(|e| format!("The saved Xtream Codes playlist is broken, {}", e))
+    }
+
+    /**
+     * The entries as they are shown, tagged with the name of the source
+     */
+    pub fn to_m3u8s(&self, source: &Rc<String>) -> Vec<M3u8> {
+        self.entries.iter().map(|x| x.to_m3u8(source)).collect()
+    }
+}
+
+/**
+ * One live channel, movie or episode of a panel
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct XtreamEntry {
+    pub tvg_id: String,
+    pub name: String,
+    pub logo: String,
+    pub group_title: String,
+    pub link: String,
+    /// Days of archive, for channels with tv_archive
+    #[serde(default)]
+    pub catchup_days: Option<u64>,
+    /// The file type of movies and episodes, such as mkv
+    #[serde(default)]
+    pub container: Option<String>,
+    #[serde(default)]
+    pub season: Option<u64>,
+    #[serde(default)]
+    pub episode: Option<u64>,
+}
+
+impl XtreamEntry {
+    pub fn to_m3u8(&self, source: &Rc<String>) -> M3u8 {
+        M3u8 {
+            tvg_id: self.tvg_id.clone(),
+            tvg_name: self.name.clone(),
+            tvg_logo: self.logo.clone(),
+            group_title: self.group_title.clone(),
+            name: self.name.clone(),
+            link: Rc::new(self.link.clone()),
+            watched: false,
+            source: Some(source.clone()),
+            catchup: self.catchup_days.map(|days| Catchup {
+                kind: CatchupKind::Xc,
+                source: String::new(),
+                days: u32::try_from(days).ok(),
+            }),
+            programmes: None,
+        }
+    }
+}
+
+pub struct XtreamPanel {
+    client: Client,
+    /// Such as http://example.org:8080/
+    base: Url,
+    login: XtreamLogin,
+    headers: BTreeMap<String, String>,
+}
+
+impl XtreamPanel {
+    /**
+     * The url may be the address of the panel, or a link to player_api.php or get.php on it
+     */
+    pub fn new(
+        url: &str,
+        login: XtreamLogin,
+        headers: BTreeMap<String, String>,
+    ) -> Result<Self, Error> {
+        let mut base = Url::parse(url).map_err(|e| format!("'{}' is not an url, {}", url, e))?;
+        if base.path().ends_with(".php") {
+            base.path_segments_mut()
+                .map_err(|_| format!("'{}' is not an url to a panel", url))?
+                .pop();
+        }
+        base.set_query(None);
+
+        let client = Client::builder()
+            .gzip(true)
+            .deflate(true)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            client,
+            base,
+            login,
+            headers,
+        })
+    }
+
+    pub fn epg_url(&self) -> String {
+        let mut url = self.url(&["xmltv.php"]);
+        url.query_pairs_mut()
+            .append_pair("username", &self.login.username)
+            .append_pair("password", &self.login.password);
+        url.to_string()
+    }
+
+    /**
+     * Get the live channels, movies and episodes of the panel, with the categories as groups
+     */
+    pub async fn playlist(&self, show_progress: bool) -> Result<XtreamPlaylist, Error> {
+        self.authenticate().await?;
+
+        let mut entries = self.live_entries().await?;
+        entries.extend(self.vod_entries().await?);
+        if self.login.series {
+            entries.extend(self.series_entries(show_progress).await?);
+        }
+
+        Ok(XtreamPlaylist {
+            epg_url: self.epg_url(),
+            entries,
+        })
+    }
+
+    async fn authenticate(&self) -> Result<(), Error> {
+        let response = self.call(None, &[]).await?;
+        let user_info = response
+            .get("user_info")
+            .ok_or_else(|| "The panel didn't answer like an Xtream Codes panel".to_owned())?;
+        if text(user_info, "auth") != "1" {
+            return Err("The panel didn't accept the username and password".to_owned());
+        }
+        match text(user_info, "status").as_str() {
+            "" | "Active" => Ok(()),
+            status => Err(format!("The account is {}", status.to_lowercase())),
+        }
+    }
+
+    async fn live_entries(&self) -> Result<Vec<XtreamEntry>, Error> {
+        let categories = self.categories("get_live_categories").await?;
+        let streams = self.list("get_live_streams").await?;
+        Ok(streams
+            .iter()
+            .map(|stream| {
+                let stream_id = text(stream, "stream_id");
+                let archive_days = number(stream, "tv_archive_duration");
+                XtreamEntry {
+                    tvg_id: text(stream, "epg_channel_id"),
+                    name: text(stream, "name"),
+                    logo: text(stream, "stream_icon"),
+                    group_title: category(&categories, stream),
+                    link: self.stream_url("live", &stream_id, "ts"),
+                    catchup_days: (number(stream, "tv_archive") == 1 && archive_days > 0)
+                        .then_some(archive_days),
+                    container: None,
+                    season: None,
+                    episode: None,
+                }
+            })
+            .collect())
+    }
+
+    async fn vod_entries(&self) -> Result<Vec<XtreamEntry>, Error> {
+        let categories = self.categories("get_vod_categories").await?;
+        let streams = self.list("get_vod_streams").await?;
+        Ok(streams
+            .iter()
+            .map(|stream| {
+                let extension = container(stream);
+                XtreamEntry {
+                    tvg_id: String::new(),
+                    name: text(stream, "name"),
+                    logo: text(stream, "stream_icon"),
+                    group_title: category(&categories, stream),
+                    link: self.stream_url("movie", &text(stream, "stream_id"), &extension),
+                    catchup_days: None,
+                    container: Some(extension),
+                    season: None,
+                    episode: None,
+                }
+            })
+            .collect())
+    }
+
+    async fn series_entries(&self, show_progress: bool) -> Result<Vec<XtreamEntry>, Error> {
+        let categories = self.categories("get_series_categories").await?;
+        let series = self.list("get_series").await?;
+        if show_progress && !series.is_empty() {
+            println!("Getting the episodes of {} series", series.len());
+        }
+
+        let series_ids: Vec<String> = series.iter().map(|x| text(x, "series_id")).collect();
+        let infos: Vec<Result<Value, Error>> = stream::iter(series_ids)
+            .map(|series_id| async move {
+                self.call(
+                    Some("get_series_info"),
+                    &[("series_id", series_id.as_str())],
+                )
+                .await
+            })
+            .buffered(CONCURRENT_SERIES_REQUESTS)
+            .collect()
+            .await;
+
+        let mut entries = Vec::new();
+        for (show, info) in series.iter().zip(infos) {
+            let series_name = text(show, "name");
+            // One broken series shouldn't hide all the others
+            let info = match info {
+                Ok(info) => info,
+                Err(e) => {
+                    println!("Failed to get the episodes of {}, {}", series_name, e);
+                    continue;
+                }
+            };
+            let group_title = category(&categories, show);
+            let cover = text(show, "cover");
+            for episode in episodes(&info) {
+                let extension = container(episode);
+                let logo = episode
+                    .get("info")
+                    .map(|info| text(info, "movie_image"))
+                    .filter(|x| !x.is_empty())
+                    .unwrap_or_else(|| cover.clone());
+                entries.push(XtreamEntry {
+                    tvg_id: String::new(),
+                    name: episode_name(&series_name, episode),
+                    logo,
+                    group_title: group_title.clone(),
+                    link: self.stream_url("series", &text(episode, "id"), &extension),
+                    catchup_days: None,
+                    container: Some(extension),
+                    season: Some(number(episode, "season")),
+                    episode: Some(number(episode, "episode_num")),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn stream_url(&self, kind: &str, stream_id: &str, extension: &str) -> String {
+        let file = format!("{}.{}", stream_id, extension);
+        self.url(&[kind, &self.login.username, &self.login.password, &file])
+            .to_string()
+    }
+
+    /**
+     * An url on the panel, where the segments are escaped
+     */
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().extend(segments);
+        }
+        url
+    }
+
+    /**
+     * Category ids to their names
+     */
+    async fn categories(&self, action: &str) -> Result<HashMap<String, String>, Error> {
+        Ok(self
+            .list(action)
+            .await?
+            .iter()
+            .map(|category| {
+                (
+                    text(category, "category_id"),
+                    text(category, "category_name"),
+                )
+            })
+            .collect())
+    }
+
+    async fn list(&self, action: &str) -> Result<Vec<Value>, Error> {
+        match self.call(Some(action), &[]).await? {
+            Value::Array(items) => Ok(items),
+            // Panels answer with an empty object instead of an empty list at times
+            Value::Object(map) if map.is_empty() => Ok(Vec::new()),
+            Value::Null => Ok(Vec::new()),
+            _ => Err(format!(
+                "The panel answered {} with something else than a list",
+                action
+            )),
+        }
+    }
+
+    async fn call(&self, action: Option<&str>, query: &[(&str, &str)]) -> Result<Value, Error> {
+        let mut builder = self.client.get(self.url(&["player_api.php"])).query(&[
+            ("username", self.login.username.as_str()),
+            ("password", self.login.password.as_str()),
+        ]);
+        if let Some(action) = action {
+            builder = builder.query(&[("action", action)]);
+        }
+        builder = builder.query(query);
+        for (key, value) in self.headers.iter() {
+            builder = builder.header(key, value);
+        }
+
+        let resp = builder.send().await.map_err(|e| e.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("The panel answered {}", resp.status()));
+        }
+        let content = resp.text().await.map_err(|e| e.to_string())?;
+        serde_json::from_str(&content)
+            .map_err(|e| format!("The panel didn't answer with json, {}", e))
+    }
+}
+
+/**
+ * Get the playlist of a panel as json, in a way that can be sent to another thread
+ */
+pub async fn fetch_xtream(
+    url: String,
+    login: XtreamLogin,
+    headers: BTreeMap<String, String>,
+    show_progress: bool,
+) -> Result<Fetched, Error> {
+    let panel = XtreamPanel::new(&url, login, headers)?;
+    let playlist = panel.playlist(show_progress).await?;
+    Ok(Fetched::Modified {
+        content: serde_json::to_string(&playlist).map_err(|e| e.to_string())?,
+        validators: Validators::default(),
+    })
+}
+
+/**
+ * The panels are inconsistent in whether numbers are sent as numbers or strings
+ */
+fn text(item: &Value, key: &str) -> String {
+    match item.get(key) {
+        Some(Value::String(text)) => text.trim().to_owned(),
+        Some(Value::Number(number)) => number.to_string(),
+        Some(Value::Bool(boolean)) => (*boolean as u8).to_string(),
+        _ => String::new(),
+    }
+}
+
+fn number(item: &Value, key: &str) -> u64 {
+    text(item, key).parse().unwrap_or_default()
+}
+
+/**
+ * The file type of a movie or an episode, which is part of its link
+ */
+fn container(item: &Value) -> String {
+    match text(item, "container_extension") {
+        extension if extension.is_empty() => "mp4".to_owned(),
+        extension => extension,
+    }
+}
+
+fn category(categories: &HashMap<String, String>, item: &Value) -> String {
+    categories
+        .get(&text(item, "category_id"))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/**
+ * The episodes are listed by season, either in an object or in a list of lists
+ */
+fn episodes(info: &Value) -> Vec<&Value> {
+    let seasons: Vec<&Value> = match info.get("episodes") {
+        Some(Value::Object(seasons)) => seasons.values().collect(),
+        Some(Value::Array(seasons)) => seasons.iter().collect(),
+        _ => Vec::new(),
+    };
+    seasons
+        .into_iter()
+        .filter_map(Value::as_array)
+        .flatten()
+        .collect()
+}
+
+/**
+ * Such as "The Show S01E02 The Title", unless the title already has the name of the series
+ */
+fn episode_name(series_name: &str, episode: &Value) -> String {
+    let title = text(episode, "title");
+    if !series_name.is_empty() && title.contains(series_name) {
+        return title;
+    }
+    format!(
+        "{} S{:02}E{:02} {}",
+        series_name,
+        number(episode, "season"),
+        number(episode, "episode_num"),
+        title
+    )
+    .trim()
+    .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+    use crate::{config::Source, Epg, Identity, OnlineParser, Playlist};
+
+    const XMLTV: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tv>
+  <channel id="news.one"><display-name>News One</display-name></channel>
+  <programme start="20231114220000 +0000" stop="20231114230000 +0000" channel="news.one">
+    <title>Evening news</title>
+    <desc>What happened today</desc>
+  </programme>
+</tv>"#;
+
+    /**
+     * What the panel answers to a request, by its path and query
+     */
+    fn answer(path: &str, query: &HashMap<String, String>) -> (&'static str, String) {
+        let json = "application/json";
+        if path == "/xmltv.php" {
+            return ("application/xml", XMLTV.to_owned());
+        }
+        if query.get("password").map(String::as_str) != Some("p") {
+            return (json, r#"{"user_info":{"auth":0}}"#.to_owned());
+        }
+        let body = match query.get("action").map(String::as_str) {
+            None => r#"{"user_info":{"auth":1,"status":"Active"}}"#,
+            Some("get_live_categories") => r#"[{"category_id":"1","category_name":"News"}]"#,
+            Some("get_live_streams") => {
+                r#"[{"stream_id":11,"name":"News One","stream_icon":"http://img/news.png",
+                    "epg_channel_id":"news.one","category_id":"1",
+                    "tv_archive":1,"tv_archive_duration":"3"}]"#
+            }
+            Some("get_vod_categories") => r#"[{"category_id":2,"category_name":"Movies"}]"#,
+            Some("get_vod_streams") => {
+                r#"[{"stream_id":"21","name":"A Movie, Part 2","category_id":"2",
+                    "container_extension":"mkv"}]"#
+            }
+            Some("get_series_categories") => r#"[{"category_id":"3","category_name":"Shows"}]"#,
+            Some("get_series") => {
+                r#"[{"series_id":31,"name":"The Show","cover":"http://img/show.png",
+                    "category_id":"3"}]"#
+            }
+            Some("get_series_info") if query.get("series_id").unwrap() == "31" => {
+                r#"{"episodes":{"1":[{"id":"301","season":1,"episode_num":2,
+                    "title":"Pilot","container_extension":"mp4"}]}}"#
+            }
+            _ => "{}",
+        };
+        (json, body.to_owned())
+    }
+
+    fn respond(mut stream: TcpStream) {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(read) => request.extend_from_slice(&buf[..read]),
+            }
+        }
+        let request = String::from_utf8_lossy(&request);
+        let target = request.split_whitespace().nth(1).unwrap_or("/");
+        let url = Url::parse(&format!("http://panel{}", target)).unwrap();
+        let query = url.query_pairs().into_owned().collect();
+
+        let (content_type, body) = answer(url.path(), &query);
+        let _ = write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        );
+    }
+
+    /**
+     * A panel on a free port of localhost, returns its address
+     */
+    fn mock_panel() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                thread::spawn(move || respond(stream));
+            }
+        });
+        format!("http://{}/", address)
+    }
+
+    fn login(password: &str) -> XtreamLogin {
+        XtreamLogin {
+            username: "u".to_owned(),
+            password: password.to_owned(),
+            series: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_the_entries_of_a_panel() {
+        let base = mock_panel();
+        let panel = XtreamPanel::new(
+            &format!("{}player_api.php", base),
+            login("p"),
+            BTreeMap::new(),
+        )
+        .unwrap();
+        let playlist = panel.playlist(false).await.unwrap();
+
+        assert_eq!(
+            playlist.epg_url,
+            format!("{}xmltv.php?username=u&password=p", base)
+        );
+        assert_eq!(
+            playlist.entries,
+            vec![
+                XtreamEntry {
+                    tvg_id: "news.one".to_owned(),
+                    name: "News One".to_owned(),
+                    logo: "http://img/news.png".to_owned(),
+                    group_title: "News".to_owned(),
+                    link: format!("{}live/u/p/11.ts", base),
+                    catchup_days: Some(3),
+                    container: None,
+                    season: None,
+                    episode: None,
+                },
+                XtreamEntry {
+                    tvg_id: String::new(),
+                    name: "A Movie, Part 2".to_owned(),
+                    logo: String::new(),
+                    group_title: "Movies".to_owned(),
+                    link: format!("{}movie/u/p/21.mkv", base),
+                    catchup_days: None,
+                    container: Some("mkv".to_owned()),
+                    season: None,
+                    episode: None,
+                },
+                XtreamEntry {
+                    tvg_id: String::new(),
+                    name: "The Show S01E02 Pilot".to_owned(),
+                    logo: "http://img/show.png".to_owned(),
+                    group_title: "Shows".to_owned(),
+                    link: format!("{}series/u/p/301.mp4", base),
+                    catchup_days: None,
+                    container: Some("mp4".to_owned()),
+                    season: Some(1),
+                    episode: Some(2),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_password() {
+        let panel = XtreamPanel::new(&mock_panel(), login("wrong"), BTreeMap::new()).unwrap();
+        assert_eq!(
+            panel.playlist(false).await.unwrap_err(),
+            "The panel didn't accept the username and password"
+        );
+    }
+
+    #[tokio::test]
+    async fn loads_a_panel_source_with_its_epg() {
+        let cache_dir = std::env::temp_dir().join(format!("ilovetv-xtream-{}", std::process::id()));
+        fs::create_dir_all(&cache_dir).unwrap();
+        let mut source = Source::new("panel", mock_panel());
+        source.xtream = Some(login("p"));
+
+        let playlists = vec![Playlist::new(source, &cache_dir).await.unwrap()];
+        let epg = Epg::load(&playlists, &cache_dir).await;
+        let parser = OnlineParser::new(&playlists, &vec![], Identity::Url, &epg).await;
+        let _ = fs::remove_dir_all(&cache_dir);
+
+        let groups: Vec<&str> = parser.iter().map(|x| x.group_title.as_str()).collect();
+        assert_eq!(groups, ["News", "Movies", "Shows"]);
+        let names: Vec<&str> = parser.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["News One", "A Movie, Part 2", "The Show S01E02 Pilot"]
+        );
+        assert!(parser
+            .iter()
+            .all(|x| x.source.as_deref().unwrap() == "panel"));
+
+        let news = &parser[0];
+        let catchup = news.catchup.as_ref().unwrap();
+        assert_eq!((catchup.kind, catchup.days), (CatchupKind::Xc, Some(3)));
+        let programmes = news.programmes.as_ref().unwrap();
+        assert_eq!(programmes.len(), 1);
+        assert_eq!(programmes[0].title, "Evening news");
+        assert_eq!(programmes[0].start, 1_699_999_200);
+        assert_eq!(programmes[0].stop, 1_700_002_800);
+        assert!(parser[1].programmes.is_none());
+    }
+}